@@ -0,0 +1,71 @@
+use chrono::Utc;
+use sea_orm::entity::prelude::*;
+use sea_orm::ActiveValue::Set;
+use serde::{Deserialize, Serialize};
+
+/// A personal access token for scripts. Only a SHA-256 hash of the token is stored.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "access_token")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    #[serde(skip_deserializing)]
+    pub id: Uuid,
+    pub user_id: Uuid,
+    /// Chosen by the user to tell their tokens apart.
+    pub name: String,
+    /// The granted scopes, separated by spaces.
+    pub scopes: String,
+    #[sea_orm(unique)]
+    pub token_hash: String,
+    /// `None` for tokens that don't expire.
+    pub expires_at: Option<DateTimeWithTimeZone>,
+    pub last_used_at: Option<DateTimeWithTimeZone>,
+    pub created_at: DateTimeWithTimeZone,
+    pub updated_at: DateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {
+    User,
+}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        match self {
+            Self::User => Entity::belongs_to(super::user::Entity)
+                .from(Column::UserId)
+                .to(super::user::Column::UserId)
+                .on_delete(ForeignKeyAction::Cascade)
+                .into(),
+        }
+    }
+}
+
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        let timestamp = Utc::now();
+        Self {
+            id: Set(Uuid::new_v4()),
+            created_at: Set(timestamp.into()),
+            updated_at: Set(timestamp.into()),
+            ..ActiveModelTrait::default()
+        }
+    }
+
+    fn before_save(mut self, _insert: bool) -> Result<Self, DbErr> {
+        self.updated_at = Set(Utc::now().into());
+        Ok(self)
+    }
+}
+
+impl Entity {
+    pub fn find_by_token_hash(hash: &str) -> Select<Entity> {
+        Self::find().filter(Column::TokenHash.eq(hash))
+    }
+}
@@ -2,5 +2,13 @@ pub mod project;
 pub mod task;
 pub mod category;
 pub mod user;
+pub mod refresh_token;
+pub mod one_time_token;
+pub mod recovery_code;
+pub mod access_token;
+pub mod login_attempt;
+pub mod workspace;
+pub mod workspace_member;
+pub mod workspace_invitation;
 
 pub use sea_orm;
\ No newline at end of file
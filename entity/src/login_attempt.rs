@@ -0,0 +1,40 @@
+use chrono::Utc;
+use sea_orm::entity::prelude::*;
+use sea_orm::ActiveValue::Set;
+use serde::{Deserialize, Serialize};
+
+/// The failed logins counted against one account or client address, when the counters are kept
+/// in the database so that every instance sees them.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "login_attempt")]
+pub struct Model {
+    /// What is counted, e.g. `account:alice` or `ip:192.0.2.1`.
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub attempt_key: String,
+    pub failures: i32,
+    /// No attempts are let through before this.
+    pub locked_until: Option<DateTimeWithTimeZone>,
+    /// When the failures are forgotten, unless there are more before then.
+    pub reset_at: DateTimeWithTimeZone,
+    pub created_at: DateTimeWithTimeZone,
+    pub updated_at: DateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        let timestamp = Utc::now();
+        Self {
+            created_at: Set(timestamp.into()),
+            updated_at: Set(timestamp.into()),
+            ..ActiveModelTrait::default()
+        }
+    }
+
+    fn before_save(mut self, _insert: bool) -> Result<Self, DbErr> {
+        self.updated_at = Set(Utc::now().into());
+        Ok(self)
+    }
+}
@@ -0,0 +1,73 @@
+use chrono::Utc;
+use sea_orm::entity::prelude::*;
+use sea_orm::ActiveValue::Set;
+use serde::{Deserialize, Serialize};
+
+/// A single-use token sent by email, e.g. to reset a password. Only a SHA-256 hash of the token is
+/// stored.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "one_time_token")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    #[serde(skip_deserializing)]
+    pub id: Uuid,
+    pub user_id: Uuid,
+    /// What the token may be used for, one of the `PURPOSE_*` constants.
+    pub purpose: String,
+    /// The address the token was sent to. Tokens only count while the account still has this
+    /// address, except email change tokens, which are sent to the address it is changing to.
+    pub email: String,
+    #[sea_orm(unique)]
+    pub token_hash: String,
+    pub expires_at: DateTimeWithTimeZone,
+    pub used_at: Option<DateTimeWithTimeZone>,
+    pub created_at: DateTimeWithTimeZone,
+    pub updated_at: DateTimeWithTimeZone,
+}
+
+pub const PURPOSE_PASSWORD_RESET: &str = "password_reset";
+pub const PURPOSE_EMAIL_VERIFICATION: &str = "email_verification";
+/// Sent to a new address, which replaces the account's current one once the link is opened.
+pub const PURPOSE_EMAIL_CHANGE: &str = "email_change";
+/// Issued after the password step of a login, when the account has two-factor authentication.
+pub const PURPOSE_TWO_FACTOR_LOGIN: &str = "two_factor_login";
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {
+    User,
+}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        match self {
+            Self::User => Entity::belongs_to(super::user::Entity)
+                .from(Column::UserId)
+                .to(super::user::Column::UserId)
+                .on_delete(ForeignKeyAction::Cascade)
+                .into(),
+        }
+    }
+}
+
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        let timestamp = Utc::now();
+        Self {
+            id: Set(Uuid::new_v4()),
+            created_at: Set(timestamp.into()),
+            updated_at: Set(timestamp.into()),
+            ..ActiveModelTrait::default()
+        }
+    }
+
+    fn before_save(mut self, _insert: bool) -> Result<Self, DbErr> {
+        self.updated_at = Set(Utc::now().into());
+        Ok(self)
+    }
+}
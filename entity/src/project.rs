@@ -1,5 +1,5 @@
 use chrono::Utc;
-use sea_orm::ActiveValue::Set;
+use sea_orm::ActiveValue::{self, Set};
 use sea_orm::{entity::prelude::*};
 use serde::{Deserialize, Serialize};
 
@@ -14,13 +14,17 @@ pub struct Model {
     pub text: String,
     pub created_at: DateTimeWithTimeZone,
     pub updated_at: DateTimeWithTimeZone,
-    pub user_id: Option<String>
+    /// Who created the project.
+    pub user_id: Uuid,
+    /// The workspace the project is shared in. Defaults to the personal workspace of `user_id`.
+    pub workspace_id: Uuid,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter)]
 pub enum Relation {
     Task,
-    User
+    User,
+    Workspace,
 }
 
 impl RelationTrait for Relation {
@@ -28,9 +32,15 @@ impl RelationTrait for Relation {
         match self {
             Self::Task => Entity::has_many(super::task::Entity).into(),
             Self::User => Entity::belongs_to(super::user::Entity)
-            .from(Column::UserId)
-            .to(super::user::Column::UserId)
-            .into(),
+                .from(Column::UserId)
+                .to(super::user::Column::UserId)
+                .on_delete(ForeignKeyAction::Cascade)
+                .into(),
+            Self::Workspace => Entity::belongs_to(super::workspace::Entity)
+                .from(Column::WorkspaceId)
+                .to(super::workspace::Column::Id)
+                .on_delete(ForeignKeyAction::Cascade)
+                .into(),
         }
     }
 }
@@ -47,6 +57,12 @@ impl Related<super::user::Entity> for Entity {
     }
 }
 
+impl Related<super::workspace::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Workspace.def()
+    }
+}
+
 
 
 impl ActiveModelBehavior for ActiveModel {
@@ -60,7 +76,12 @@ impl ActiveModelBehavior for ActiveModel {
         }
     }
 
-    fn before_save(mut self, _insert: bool) -> Result<Self, DbErr> {
+    fn before_save(mut self, insert: bool) -> Result<Self, DbErr> {
+        if insert && self.workspace_id.is_not_set() {
+            if let ActiveValue::Set(user_id) = self.user_id {
+                self.workspace_id = Set(user_id);
+            }
+        }
         self.updated_at = Set(Utc::now().into());
         Ok(self)
     }
@@ -70,4 +91,8 @@ impl Entity {
     pub fn find_by_title(name: &str) -> Select<Entity> {
         Self::find().filter(Column::Title.eq(name))
     }
+
+    pub fn find_by_owner(user_id: Uuid) -> Select<Entity> {
+        Self::find().filter(Column::UserId.eq(user_id))
+    }
 }
@@ -13,6 +13,8 @@ pub struct Model {
     #[sea_orm(column_type = "Text")]
     pub text: String,
     pub project_id: Option<Uuid>,
+    #[sea_orm(default_value = false)]
+    pub done: bool,
     pub created_at: DateTimeWithTimeZone,
     pub updated_at: DateTimeWithTimeZone,
 }
@@ -28,6 +30,7 @@ impl RelationTrait for Relation {
             Self::Project => Entity::belongs_to(super::project::Entity)
                 .from(Column::ProjectId)
                 .to(super::project::Column::Id)
+                .on_delete(ForeignKeyAction::Cascade)
                 .into(),
         }
     }
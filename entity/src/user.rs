@@ -3,25 +3,54 @@ use sea_orm::entity::prelude::*;
 use sea_orm::ActiveValue::Set;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+/// Deliberately not `Serialize`: the row holds the password hash, so responses have to go through
+/// a type that picks the fields to show.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Deserialize)]
 #[sea_orm(table_name = "user")]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
     #[serde(skip_deserializing)]
     pub user_id: Uuid,
     #[sea_orm(unique, case_insensitive)]
-    username: String,
+    pub username: String,
     #[sea_orm(unique, case_insensitive)]
-    email: String,
+    pub email: String,
     #[sea_orm(default = "")]
-    bio: String,
-    image: Option<String>,
+    pub bio: String,
+    pub image: Option<String>,
+    /// When the current `email` was confirmed, or `None` while it is unverified.
+    pub email_verified_at: Option<DateTimeWithTimeZone>,
     #[sea_orm(column_type = "Text")]
     pub password_hash: String,
+    /// The base32 TOTP secret. Set once enrollment starts, but only in use once
+    /// `totp_enabled_at` is set too.
+    pub totp_secret: Option<String>,
+    pub totp_enabled_at: Option<DateTimeWithTimeZone>,
+    /// The time step of the last accepted code, so that no code is accepted twice.
+    pub totp_last_used_step: Option<i64>,
+    pub role: Role,
+    /// Set while an admin has disabled the account, which can't log in then.
+    pub disabled_at: Option<DateTimeWithTimeZone>,
     pub created_at: DateTimeWithTimeZone,
     pub updated_at: DateTimeWithTimeZone,
 }
 
+/// What a user may do. See `auth::permission` for what each role grants.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(16))")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Manages other users, besides everything a member can do.
+    #[sea_orm(string_value = "admin")]
+    Admin,
+    /// What every new account starts as.
+    #[sea_orm(string_value = "member")]
+    Member,
+    /// Can look, but not change anything.
+    #[sea_orm(string_value = "guest")]
+    Guest,
+}
+
 #[derive(Copy, Clone, Debug, EnumIter)]
 pub enum Relation {
     Project,
@@ -46,6 +75,7 @@ impl ActiveModelBehavior for ActiveModel {
         let timestamp = Utc::now();
         Self {
             user_id: Set(Uuid::new_v4()),
+            role: Set(Role::Member),
             created_at: Set(timestamp.into()),
             updated_at: Set(timestamp.into()),
             ..ActiveModelTrait::default()
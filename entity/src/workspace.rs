@@ -0,0 +1,95 @@
+use chrono::Utc;
+use sea_orm::entity::prelude::*;
+use sea_orm::ActiveValue::Set;
+use sea_orm::ConnectionTrait;
+use serde::{Deserialize, Serialize};
+
+/// A group of people that share projects, such as a household.
+///
+/// Every user has a personal workspace with the same id as the user, which is created along with
+/// the account and where their projects go unless they pick another one.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "workspace")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    #[serde(skip_deserializing)]
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTimeWithTimeZone,
+    pub updated_at: DateTimeWithTimeZone,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {
+    Member,
+    Invitation,
+    Project,
+}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        match self {
+            Self::Member => Entity::has_many(super::workspace_member::Entity).into(),
+            Self::Invitation => Entity::has_many(super::workspace_invitation::Entity).into(),
+            Self::Project => Entity::has_many(super::project::Entity).into(),
+        }
+    }
+}
+
+impl Related<super::workspace_member::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Member.def()
+    }
+}
+
+impl Related<super::workspace_invitation::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Invitation.def()
+    }
+}
+
+impl Related<super::project::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Project.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        let timestamp = Utc::now();
+        Self {
+            id: Set(Uuid::new_v4()),
+            created_at: Set(timestamp.into()),
+            updated_at: Set(timestamp.into()),
+            ..ActiveModelTrait::default()
+        }
+    }
+
+    fn before_save(mut self, _insert: bool) -> Result<Self, DbErr> {
+        self.updated_at = Set(Utc::now().into());
+        Ok(self)
+    }
+}
+
+/// Creates the personal workspace of `owner`, with `owner` as its only member.
+pub async fn create_personal<C: ConnectionTrait>(
+    db: &C,
+    owner: &super::user::Model,
+) -> Result<Model, DbErr> {
+    let workspace = ActiveModel {
+        id: Set(owner.user_id),
+        name: Set(owner.username.clone()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+    super::workspace_member::ActiveModel {
+        workspace_id: Set(workspace.id),
+        user_id: Set(owner.user_id),
+        role: Set(super::workspace_member::WorkspaceRole::Owner),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+    Ok(workspace)
+}
@@ -0,0 +1,102 @@
+use chrono::Utc;
+use sea_orm::entity::prelude::*;
+use sea_orm::ActiveValue::Set;
+use serde::{Deserialize, Serialize};
+
+/// That a user belongs to a workspace, and with which role. A user is a member of a workspace at
+/// most once.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "workspace_member")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    #[serde(skip_deserializing)]
+    pub id: Uuid,
+    pub workspace_id: Uuid,
+    pub user_id: Uuid,
+    pub role: WorkspaceRole,
+    pub created_at: DateTimeWithTimeZone,
+    pub updated_at: DateTimeWithTimeZone,
+}
+
+/// What a member may do in a workspace. This comes on top of the account's own role, so a guest
+/// account can't edit projects even where it is an editor.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, Serialize, Deserialize)]
+#[sea_orm(rs_type = "String", db_type = "String(Some(16))")]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceRole {
+    /// Manages members and invitations, besides everything an editor can do.
+    #[sea_orm(string_value = "owner")]
+    Owner,
+    /// Creates, changes and deletes projects and their tasks.
+    #[sea_orm(string_value = "editor")]
+    Editor,
+    /// Only sees the projects.
+    #[sea_orm(string_value = "viewer")]
+    Viewer,
+}
+
+impl WorkspaceRole {
+    pub fn can_edit(self) -> bool {
+        self != WorkspaceRole::Viewer
+    }
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {
+    Workspace,
+    User,
+}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        match self {
+            Self::Workspace => Entity::belongs_to(super::workspace::Entity)
+                .from(Column::WorkspaceId)
+                .to(super::workspace::Column::Id)
+                .on_delete(ForeignKeyAction::Cascade)
+                .into(),
+            Self::User => Entity::belongs_to(super::user::Entity)
+                .from(Column::UserId)
+                .to(super::user::Column::UserId)
+                .on_delete(ForeignKeyAction::Cascade)
+                .into(),
+        }
+    }
+}
+
+impl Related<super::workspace::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Workspace.def()
+    }
+}
+
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        let timestamp = Utc::now();
+        Self {
+            id: Set(Uuid::new_v4()),
+            created_at: Set(timestamp.into()),
+            updated_at: Set(timestamp.into()),
+            ..ActiveModelTrait::default()
+        }
+    }
+
+    fn before_save(mut self, _insert: bool) -> Result<Self, DbErr> {
+        self.updated_at = Set(Utc::now().into());
+        Ok(self)
+    }
+}
+
+impl Entity {
+    pub fn find_membership(workspace_id: Uuid, user_id: Uuid) -> Select<Entity> {
+        Self::find()
+            .filter(Column::WorkspaceId.eq(workspace_id))
+            .filter(Column::UserId.eq(user_id))
+    }
+}
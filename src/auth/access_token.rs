@@ -0,0 +1,168 @@
+//! Personal access tokens: long-lived bearer tokens for scripts, limited to a set of scopes.
+use super::{
+    permission::ensure_enabled,
+    refresh::{generate_token, hash_token},
+};
+use crate::{error::HttpError, Result};
+use chrono::{Duration, Utc};
+use entity::{access_token, user};
+use sea_orm::{
+    prelude::{DateTimeWithTimeZone, Uuid},
+    sea_query::{Condition, Expr},
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Every personal access token starts with this, which is how they are told apart from access
+/// tokens, and what secret scanners can look for.
+pub const PREFIX: &str = "hpat_";
+
+/// How often `last_used_at` is written at most, so that busy scripts don't write on every call.
+const LAST_USED_RESOLUTION_SECS: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    /// Read projects and their tasks.
+    #[serde(rename = "projects:read")]
+    ReadProjects,
+    /// Create, change and delete tasks.
+    #[serde(rename = "tasks:write")]
+    WriteTasks,
+    /// Everything the owner can do.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    const ALL: [Scope; 3] = [Scope::ReadProjects, Scope::WriteTasks, Scope::Admin];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::ReadProjects => "projects:read",
+            Scope::WriteTasks => "tasks:write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A set of scopes. `admin` covers every other scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Scopes(u8);
+
+impl Scopes {
+    pub fn contains(self, scope: Scope) -> bool {
+        self.0 & (scope.bit() | Scope::Admin.bit()) != 0
+    }
+
+    pub fn to_vec(self) -> Vec<Scope> {
+        Scope::ALL
+            .into_iter()
+            .filter(|scope| self.0 & scope.bit() != 0)
+            .collect()
+    }
+
+    /// The scopes as stored, separated by spaces. Unknown names are dropped when reading them back.
+    pub fn parse(stored: &str) -> Self {
+        stored
+            .split_whitespace()
+            .filter_map(|name| Scope::ALL.into_iter().find(|scope| scope.as_str() == name))
+            .collect()
+    }
+}
+
+impl FromIterator<Scope> for Scopes {
+    fn from_iter<I: IntoIterator<Item = Scope>>(iter: I) -> Self {
+        Scopes(iter.into_iter().fold(0, |bits, scope| bits | scope.bit()))
+    }
+}
+
+impl fmt::Display for Scopes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.to_vec().into_iter().map(Scope::as_str).collect();
+        f.write_str(&names.join(" "))
+    }
+}
+
+/// Creates a token for `user_id`, returning the row and the token itself, which is not stored and
+/// can't be shown again.
+pub async fn issue<C: ConnectionTrait>(
+    db: &C,
+    user_id: Uuid,
+    name: String,
+    scopes: Scopes,
+    expires_in_days: Option<i64>,
+) -> Result<(access_token::Model, String)> {
+    let token = format!("{}{}", PREFIX, generate_token());
+    let model = access_token::ActiveModel {
+        user_id: ActiveValue::Set(user_id),
+        name: ActiveValue::Set(name),
+        scopes: ActiveValue::Set(scopes.to_string()),
+        token_hash: ActiveValue::Set(hash_token(&token)),
+        expires_at: ActiveValue::Set(
+            expires_in_days.map(|days| (Utc::now() + Duration::days(days)).into()),
+        ),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+
+    Ok((model, token))
+}
+
+fn invalid_token() -> HttpError {
+    HttpError::unauthorized(
+        Some("invalid_token".to_owned()),
+        Some("The access token is invalid, has expired or has been revoked.".to_owned()),
+    )
+}
+
+/// Looks up the owner and scopes of `token`, and notes that it was used. Tokens of disabled
+/// accounts are refused.
+pub async fn authenticate<C: ConnectionTrait>(db: &C, token: &str) -> Result<(Uuid, Scopes)> {
+    let now = Utc::now();
+    let (found, owner) = access_token::Entity::find_by_token_hash(&hash_token(token))
+        .find_also_related(user::Entity)
+        .one(db)
+        .await?
+        .filter(|(found, _)| found.expires_at.is_none_or(|expires_at| expires_at > now))
+        .ok_or_else(invalid_token)?;
+    ensure_enabled(&owner.ok_or_else(invalid_token)?)?;
+
+    access_token::Entity::update_many()
+        .col_expr(
+            access_token::Column::LastUsedAt,
+            Expr::value(Some(DateTimeWithTimeZone::from(now))),
+        )
+        .filter(access_token::Column::Id.eq(found.id))
+        .filter(
+            Condition::any()
+                .add(access_token::Column::LastUsedAt.is_null())
+                .add(access_token::Column::LastUsedAt.lt(DateTimeWithTimeZone::from(
+                    now - Duration::seconds(LAST_USED_RESOLUTION_SECS),
+                ))),
+        )
+        .exec(db)
+        .await?;
+
+    Ok((found.user_id, Scopes::parse(&found.scopes)))
+}
+
+/// Revokes every personal access token of `user_id`.
+pub async fn revoke_all_for_user<C: ConnectionTrait>(db: &C, user_id: Uuid) -> Result<()> {
+    access_token::Entity::delete_many()
+        .filter(access_token::Column::UserId.eq(user_id))
+        .exec(db)
+        .await?;
+    Ok(())
+}
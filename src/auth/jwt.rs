@@ -0,0 +1,44 @@
+use crate::{error::HttpError, settings::AuthSettings, Result};
+use chrono::Utc;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use sea_orm::prelude::Uuid;
+use serde::{Deserialize, Serialize};
+
+/// The claims carried by an access token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    /// The id of the user the token was issued to.
+    pub sub: Uuid,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+pub fn issue_access_token(settings: &AuthSettings, user_id: Uuid) -> Result<String> {
+    let now = Utc::now().timestamp();
+    let claims = Claims {
+        sub: user_id,
+        iat: now,
+        exp: now + settings.access_token_ttl_secs,
+    };
+
+    encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(settings.jwt_secret()),
+    )
+    .map_err(|e| anyhow::anyhow!("failed to sign access token: {}", e).into())
+}
+
+pub fn verify_access_token(settings: &AuthSettings, token: &str) -> Result<Claims> {
+    let data = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(settings.jwt_secret()),
+        &Validation::new(Algorithm::HS256),
+    )
+    .map_err(|e| {
+        tracing::debug!("rejected access token: {}", e);
+        HttpError::unauthorized(Some("invalid_token".to_owned()), None)
+    })?;
+
+    Ok(data.claims)
+}
@@ -0,0 +1,474 @@
+//! Slowing down password guessing.
+//!
+//! Failed logins are counted per account and per client address. After a few free failures every
+//! further one doubles the wait before the next attempt, and at the maximum the account or address
+//! is locked for a while. Attempts that come too early are refused with a 429 before the password
+//! is hashed, which is the expensive part.
+//!
+//! Every attempt is counted as a failure up front, in the same step that checks whether it has to
+//! wait, and taken back if the password turns out to be right. Checking first and counting later
+//! would let a burst of parallel guesses all through before the first of them is counted.
+//!
+//! The counters live in an [`AttemptStore`], chosen by `auth.lockout.backend`: in memory when
+//! there is a single instance, or in the database so that several instances agree.
+use crate::{
+    database::constraint::{self, ConstraintKind},
+    error::HttpError,
+    settings::{AttemptBackend, LockoutSettings},
+    Result,
+};
+use axum::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use entity::login_attempt;
+use sea_orm::{
+    prelude::DateTimeWithTimeZone,
+    sea_query::{Condition, Expr},
+    ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Something failed logins are counted against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttemptKey<'a> {
+    /// The username as given, whether or not such an account exists, so that lockouts don't tell
+    /// which accounts do.
+    Account(&'a str),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for AttemptKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttemptKey::Account(username) => write!(f, "account:{}", username),
+            AttemptKey::Ip(ip) => write!(f, "ip:{}", ip),
+        }
+    }
+}
+
+/// The failed attempts counted against one key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attempts {
+    pub failures: u32,
+    /// No attempts are let through before this.
+    pub locked_until: Option<DateTime<Utc>>,
+    /// When the failures are forgotten, unless there are more before then.
+    pub reset_at: DateTime<Utc>,
+}
+
+/// How failures against one kind of key are punished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub free_failures: u32,
+    pub max_failures: u32,
+    pub base_delay: Duration,
+    pub lockout: Duration,
+    pub window: Duration,
+}
+
+impl Policy {
+    pub fn for_key(settings: &LockoutSettings, key: &AttemptKey) -> Self {
+        let (free_failures, max_failures) = match key {
+            AttemptKey::Account(_) => (settings.account_free_failures, settings.account_max_failures),
+            AttemptKey::Ip(_) => (settings.ip_free_failures, settings.ip_max_failures),
+        };
+        Self {
+            free_failures,
+            max_failures,
+            base_delay: Duration::seconds(settings.base_delay_secs as i64),
+            lockout: Duration::seconds(settings.lockout_secs as i64),
+            window: Duration::seconds(settings.window_secs as i64),
+        }
+    }
+
+    /// How long to wait after the given number of failures.
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures >= self.max_failures {
+            return self.lockout;
+        }
+        match failures.checked_sub(self.free_failures) {
+            None | Some(0) => Duration::zero(),
+            Some(over) => {
+                // The delay is capped at the lockout anyway, so the exponent can be capped against overflow.
+                let factor = 2i32.pow((over - 1).min(20));
+                (self.base_delay * factor).min(self.lockout)
+            }
+        }
+    }
+}
+
+impl Attempts {
+    /// The attempts after one more failure at `now`. Failures from an earlier window are
+    /// forgotten first.
+    pub fn after_failure(previous: Option<Attempts>, policy: &Policy, now: DateTime<Utc>) -> Self {
+        let failures = match previous {
+            Some(previous) if previous.reset_at > now => previous.failures.saturating_add(1),
+            _ => 1,
+        };
+        let delay = policy.delay(failures);
+        let locked_until = (delay > Duration::zero()).then(|| now + delay);
+        Self {
+            failures,
+            locked_until,
+            reset_at: locked_until.unwrap_or(now) + policy.window,
+        }
+    }
+
+    /// How long until the next attempt is let through, if it has to wait.
+    pub fn retry_after(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.locked_until
+            .map(|until| until - now)
+            .filter(|wait| *wait > Duration::zero())
+    }
+}
+
+/// What [AttemptStore::reserve] made of an attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reserved {
+    /// Counted as a failure. `before` is what to go back to if it doesn't fail after all.
+    Counted {
+        before: Option<Attempts>,
+        after: Attempts,
+    },
+    /// Refused without counting, as the key has to wait this long.
+    Locked(Duration),
+}
+
+#[async_trait]
+pub trait AttemptStore: Send + Sync {
+    /// The attempts counted against `key`, if any.
+    async fn get(&self, key: &str) -> Result<Option<Attempts>>;
+
+    /// Counts an attempt against `key` as a failure, unless it has to wait. Checking and counting
+    /// are one step, without losing any attempts that other callers count at the same time.
+    async fn reserve(&self, key: &str, policy: &Policy, now: DateTime<Utc>) -> Result<Reserved>;
+
+    /// Takes back an attempt that [AttemptStore::reserve] counted as `after`. If another one has
+    /// been counted since, it stays counted, erring on the side of caution.
+    async fn release(&self, key: &str, before: Option<Attempts>, after: Attempts) -> Result<()>;
+
+    /// Forgets the failures counted against `key`.
+    async fn clear(&self, key: &str) -> Result<()>;
+}
+
+/// The attempt store the settings ask for.
+pub fn store_from_settings(
+    settings: &LockoutSettings,
+    db: &DatabaseConnection,
+) -> Arc<dyn AttemptStore> {
+    match settings.backend {
+        AttemptBackend::Memory => Arc::new(MemoryAttemptStore::default()),
+        AttemptBackend::Database => Arc::new(DatabaseAttemptStore::new(db.clone())),
+    }
+}
+
+/// Never fewer entries than this before expired ones are swept out.
+const MEMORY_SWEEP_MIN: usize = 1024;
+
+/// Keeps the counters of this instance in memory.
+#[derive(Debug)]
+pub struct MemoryAttemptStore {
+    inner: Mutex<MemoryAttempts>,
+}
+
+#[derive(Debug)]
+struct MemoryAttempts {
+    attempts: HashMap<String, Attempts>,
+    /// Expired entries are swept once there are this many, so that a flood of usernames can't
+    /// grow the map forever, nor make every failure walk all of it.
+    sweep_at: usize,
+}
+
+impl Default for MemoryAttemptStore {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(MemoryAttempts {
+                attempts: HashMap::new(),
+                sweep_at: MEMORY_SWEEP_MIN,
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl AttemptStore for MemoryAttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<Attempts>> {
+        Ok(self.inner.lock().unwrap().attempts.get(key).copied())
+    }
+
+    async fn reserve(&self, key: &str, policy: &Policy, now: DateTime<Utc>) -> Result<Reserved> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.attempts.len() >= inner.sweep_at {
+            inner.attempts.retain(|_, attempts| attempts.reset_at > now);
+            inner.sweep_at = (inner.attempts.len() * 2).max(MEMORY_SWEEP_MIN);
+        }
+        let before = inner.attempts.get(key).copied();
+        if let Some(wait) = before.and_then(|attempts| attempts.retry_after(now)) {
+            return Ok(Reserved::Locked(wait));
+        }
+        let after = Attempts::after_failure(before, policy, now);
+        inner.attempts.insert(key.to_owned(), after);
+        Ok(Reserved::Counted { before, after })
+    }
+
+    async fn release(&self, key: &str, before: Option<Attempts>, after: Attempts) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.attempts.get(key) == Some(&after) {
+            match before {
+                Some(before) => inner.attempts.insert(key.to_owned(), before),
+                None => inner.attempts.remove(key),
+            };
+        }
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<()> {
+        self.inner.lock().unwrap().attempts.remove(key);
+        Ok(())
+    }
+}
+
+/// How often a failure is retried when other instances keep changing the same counter.
+const DATABASE_RETRIES: usize = 5;
+
+/// Keeps the counters in the `login_attempt` table, shared by every instance.
+#[derive(Debug, Clone)]
+pub struct DatabaseAttemptStore {
+    db: DatabaseConnection,
+}
+
+impl DatabaseAttemptStore {
+    pub fn new(db: DatabaseConnection) -> Self {
+        Self { db }
+    }
+}
+
+impl DatabaseAttemptStore {
+    /// Writes `next` over the row of `key`, if it still holds `current`.
+    async fn replace(&self, key: &str, current: &Attempts, next: &Attempts) -> Result<bool> {
+        let updated = login_attempt::Entity::update_many()
+            .col_expr(login_attempt::Column::Failures, Expr::value(next.failures as i32))
+            .col_expr(
+                login_attempt::Column::LockedUntil,
+                Expr::value(next.locked_until.map(DateTimeWithTimeZone::from)),
+            )
+            .col_expr(
+                login_attempt::Column::ResetAt,
+                Expr::value(DateTimeWithTimeZone::from(next.reset_at)),
+            )
+            .col_expr(
+                login_attempt::Column::UpdatedAt,
+                Expr::value(DateTimeWithTimeZone::from(Utc::now())),
+            )
+            .filter(unchanged(key, current))
+            .exec(&self.db)
+            .await?;
+        Ok(updated.rows_affected == 1)
+    }
+}
+
+/// The row of `key`, as long as it still holds `attempts`.
+fn unchanged(key: &str, attempts: &Attempts) -> Condition {
+    Condition::all()
+        .add(login_attempt::Column::AttemptKey.eq(key))
+        .add(login_attempt::Column::Failures.eq(attempts.failures as i32))
+        .add(login_attempt::Column::ResetAt.eq(DateTimeWithTimeZone::from(attempts.reset_at)))
+}
+
+impl From<login_attempt::Model> for Attempts {
+    fn from(row: login_attempt::Model) -> Self {
+        Self {
+            failures: row.failures.max(0) as u32,
+            locked_until: row.locked_until.map(|until| until.with_timezone(&Utc)),
+            reset_at: row.reset_at.with_timezone(&Utc),
+        }
+    }
+}
+
+#[async_trait]
+impl AttemptStore for DatabaseAttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<Attempts>> {
+        Ok(login_attempt::Entity::find_by_id(key.to_owned())
+            .one(&self.db)
+            .await?
+            .map(Into::into))
+    }
+
+    async fn reserve(&self, key: &str, policy: &Policy, now: DateTime<Utc>) -> Result<Reserved> {
+        login_attempt::Entity::delete_many()
+            .filter(login_attempt::Column::ResetAt.lt(DateTimeWithTimeZone::from(now)))
+            .exec(&self.db)
+            .await?;
+
+        // Read, work out the next value, and write it only if nobody else wrote in between.
+        for _ in 0..DATABASE_RETRIES {
+            let row = login_attempt::Entity::find_by_id(key.to_owned())
+                .one(&self.db)
+                .await?;
+            let before: Option<Attempts> = row.clone().map(Into::into);
+            if let Some(wait) = before.and_then(|attempts| attempts.retry_after(now)) {
+                return Ok(Reserved::Locked(wait));
+            }
+            let after = Attempts::after_failure(before, policy, now);
+            let counted = Reserved::Counted { before, after };
+
+            match row {
+                None => {
+                    let inserted = login_attempt::ActiveModel {
+                        attempt_key: ActiveValue::Set(key.to_owned()),
+                        failures: ActiveValue::Set(after.failures as i32),
+                        locked_until: ActiveValue::Set(after.locked_until.map(Into::into)),
+                        reset_at: ActiveValue::Set(after.reset_at.into()),
+                        ..Default::default()
+                    }
+                    .insert(&self.db)
+                    .await;
+                    match inserted {
+                        Ok(_) => return Ok(counted),
+                        Err(e) if is_unique_violation(&e) => continue,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Some(row) => {
+                    if self.replace(key, &row.clone().into(), &after).await? {
+                        return Ok(counted);
+                    }
+                }
+            }
+        }
+        Err(anyhow::anyhow!("the login attempts for `{}` kept changing", key).into())
+    }
+
+    async fn release(&self, key: &str, before: Option<Attempts>, after: Attempts) -> Result<()> {
+        match before {
+            Some(before) => {
+                self.replace(key, &after, &before).await?;
+            }
+            None => {
+                login_attempt::Entity::delete_many()
+                    .filter(unchanged(key, &after))
+                    .exec(&self.db)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<()> {
+        login_attempt::Entity::delete_by_id(key.to_owned())
+            .exec(&self.db)
+            .await?;
+        Ok(())
+    }
+}
+
+fn is_unique_violation(err: &sea_orm::DbErr) -> bool {
+    matches!(
+        constraint::classify(err),
+        Some(violation) if violation.kind == ConstraintKind::Unique
+    )
+}
+
+/// An attempt that [Lockout::reserve] let through and counted as a failure, until it is known how
+/// it went.
+#[must_use = "an attempt stays counted as a failure unless it is released"]
+#[derive(Debug)]
+pub struct Attempt {
+    counted: Vec<CountedAttempt>,
+}
+
+#[derive(Debug)]
+struct CountedAttempt {
+    key: String,
+    max_failures: u32,
+    before: Option<Attempts>,
+    after: Attempts,
+}
+
+/// Failed login tracking, as configured under `auth.lockout`.
+pub struct Lockout {
+    store: Arc<dyn AttemptStore>,
+    settings: LockoutSettings,
+}
+
+impl Lockout {
+    pub fn new(store: Arc<dyn AttemptStore>, settings: LockoutSettings) -> Self {
+        Self { store, settings }
+    }
+
+    /// Counts an attempt against each of `keys` as a failure, or refuses it with a 429 if any of
+    /// them has to wait. Settle the result with one of the methods taking an [Attempt].
+    pub async fn reserve(&self, keys: &[AttemptKey<'_>], now: DateTime<Utc>) -> Result<Attempt> {
+        let mut attempt = Attempt {
+            counted: Vec::with_capacity(keys.len()),
+        };
+        let mut wait = Duration::zero();
+        for key in keys {
+            let policy = Policy::for_key(&self.settings, key);
+            match self.store.reserve(&key.to_string(), &policy, now).await? {
+                Reserved::Counted { before, after } => attempt.counted.push(CountedAttempt {
+                    key: key.to_string(),
+                    max_failures: policy.max_failures,
+                    before,
+                    after,
+                }),
+                Reserved::Locked(locked) => wait = wait.max(locked),
+            }
+        }
+        if wait <= Duration::zero() {
+            return Ok(attempt);
+        }
+        // Refused attempts don't count, so those already counted against the other keys are
+        // taken back.
+        self.release(attempt).await?;
+
+        // Rounded up, so that a client waiting exactly this long isn't turned away again.
+        let secs = (wait + Duration::milliseconds(999)).num_seconds().max(1) as u64;
+        Err(HttpError::too_many_requests(
+            Some("too_many_attempts".to_owned()),
+            Some(format!(
+                "Too many failed attempts. Try again in {} seconds.",
+                secs
+            )),
+            secs,
+        )
+        .into())
+    }
+
+    /// Leaves the attempt counted as the failure it was.
+    pub fn record_failure(&self, attempt: Attempt) {
+        for counted in attempt.counted {
+            if counted.after.failures == counted.max_failures {
+                tracing::warn!(
+                    key = %counted.key,
+                    "locked out after {} failed logins",
+                    counted.after.failures
+                );
+            }
+        }
+    }
+
+    /// Takes the attempt back, as it didn't fail after all.
+    pub async fn release(&self, attempt: Attempt) -> Result<()> {
+        for counted in attempt.counted {
+            self.store
+                .release(&counted.key, counted.before, counted.after)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Takes the attempt back and forgets the failures of the account that logged in. Those of
+    /// the address are kept, so that an attacker can't reset them by logging into an account of
+    /// their own in between.
+    pub async fn record_success(&self, attempt: Attempt, username: &str) -> Result<()> {
+        self.release(attempt).await?;
+        self.store
+            .clear(&AttemptKey::Account(username).to_string())
+            .await
+    }
+}
@@ -0,0 +1,160 @@
+use crate::{
+    error::{Error, HttpError},
+    server::Server,
+    Result,
+};
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, RequestParts},
+    http::header::AUTHORIZATION,
+};
+use access_token::{Scope, Scopes};
+use entity::user;
+use permission::ensure_enabled;
+use sea_orm::{prelude::Uuid, EntityTrait};
+
+pub mod access_token;
+pub mod jwt;
+pub mod lockout;
+pub mod one_time;
+pub mod totp;
+pub mod password;
+pub mod permission;
+pub mod refresh;
+
+/// The authenticated caller of a request, taken from a bearer access token or a personal access
+/// token.
+///
+/// Rejects the request with a 401 if the `Authorization` header is missing or the token is invalid,
+/// and with a 403 if the account has been disabled or a personal access token lacks the scope the
+/// route needs. Routes declare that scope with [`permission::require`]; everywhere else only tokens
+/// with the `admin` scope get through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AuthUser {
+    pub user_id: Uuid,
+    /// The scopes of the personal access token used, or `None` for a login session, which can do
+    /// everything the user can.
+    pub scopes: Option<Scopes>,
+}
+
+impl AuthUser {
+    /// For things a token should never do on its own, like creating more tokens.
+    pub fn require_session(&self) -> Result<()> {
+        match self.scopes {
+            None => Ok(()),
+            Some(_) => Err(HttpError::forbidden(
+                Some("session_required".to_owned()),
+                Some("This needs a login session rather than an access token.".to_owned()),
+            )
+            .into()),
+        }
+    }
+
+    /// Refuses personal access tokens without `scope`. Login sessions have every scope.
+    pub fn require_scope(&self, scope: Scope) -> Result<()> {
+        match self.scopes {
+            Some(scopes) if !scopes.contains(scope) => Err(HttpError::forbidden(
+                Some("insufficient_scope".to_owned()),
+                Some(format!("The access token needs the `{}` scope for this.", scope)),
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Like [`AuthUser`], but for routes that can also be called anonymously.
+///
+/// A missing `Authorization` header gives `None`, while a malformed or expired token is still
+/// rejected so that clients notice when their session has lapsed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaybeAuthUser(pub Option<AuthUser>);
+
+/// Pulls the token out of an `Authorization: Bearer <token>` header, if there is one.
+fn bearer_token<B>(req: &RequestParts<B>) -> Result<Option<&str>> {
+    let header = match req.headers().get(AUTHORIZATION) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    header
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| Some(token.trim()))
+        .ok_or_else(|| {
+            HttpError::unauthorized(
+                Some("invalid_token".to_owned()),
+                Some("Expected a bearer token in the Authorization header.".to_owned()),
+            )
+            .into()
+        })
+}
+
+async fn server<B: Send>(req: &mut RequestParts<B>) -> Result<Server> {
+    let Extension(ctx) = Extension::<Server>::from_request(req).await.map_err(|e| {
+        tracing::error!("Server extension missing: {}", e);
+        HttpError::internal_server_errer(None, None)
+    })?;
+    Ok(ctx)
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for AuthUser {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self> {
+        MaybeAuthUser::from_request(req)
+            .await?
+            .0
+            .ok_or_else(|| HttpError::unauthorized(None, None).into())
+    }
+}
+
+/// Who sent the request, if anyone, without regard to what their token may do.
+async fn authenticate<B: Send>(req: &mut RequestParts<B>) -> Result<Option<AuthUser>> {
+    let ctx = server(req).await?;
+    let token = match bearer_token(req)? {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+
+    if token.starts_with(access_token::PREFIX) {
+        let (user_id, scopes) = access_token::authenticate(&ctx.db, token).await?;
+        return Ok(Some(AuthUser {
+            user_id,
+            scopes: Some(scopes),
+        }));
+    }
+
+    let claims = jwt::verify_access_token(&ctx.settings.auth, token)?;
+    // An access token outlives the account being disabled, so check the account every time.
+    let account = user::Entity::find_by_id(claims.sub)
+        .one(&ctx.db)
+        .await?
+        .ok_or_else(|| HttpError::unauthorized(None, None))?;
+    ensure_enabled(&account)?;
+    Ok(Some(AuthUser {
+        user_id: claims.sub,
+        scopes: None,
+    }))
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for MaybeAuthUser {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self> {
+        // Already worked out by a permission layer, along with the scope the route needs.
+        if let Some(user) = req.extensions().get::<AuthUser>() {
+            return Ok(MaybeAuthUser(Some(*user)));
+        }
+
+        let user = authenticate(req).await?;
+        if let Some(user) = &user {
+            // The route didn't declare a scope, so only a token that may do everything will do.
+            user.require_scope(Scope::Admin)?;
+        }
+        Ok(MaybeAuthUser(user))
+    }
+}
@@ -0,0 +1,113 @@
+//! Single-use tokens that are sent by email, for password resets and confirming email addresses.
+use super::refresh::{generate_token, hash_token};
+use crate::{error::HttpError, Result};
+use chrono::{Duration, Utc};
+use entity::{one_time_token, user};
+use sea_orm::{
+    prelude::{DateTimeWithTimeZone, Uuid},
+    sea_query::Expr,
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter,
+};
+
+/// Issues a token for `purpose`, sent to the current email address of `user`. Earlier tokens for
+/// the same purpose stop working, so only the most recent email counts.
+pub async fn issue<C: ConnectionTrait>(
+    db: &C,
+    user: &user::Model,
+    purpose: &str,
+    ttl_secs: i64,
+) -> Result<String> {
+    issue_to(db, user, purpose, &user.email, ttl_secs).await
+}
+
+/// Like [`issue`], but for a token sent to `email` rather than the account's current address.
+pub async fn issue_to<C: ConnectionTrait>(
+    db: &C,
+    user: &user::Model,
+    purpose: &str,
+    email: &str,
+    ttl_secs: i64,
+) -> Result<String> {
+    invalidate(db, user.user_id, purpose).await?;
+
+    let token = generate_token();
+    one_time_token::ActiveModel {
+        user_id: ActiveValue::Set(user.user_id),
+        purpose: ActiveValue::Set(purpose.to_owned()),
+        email: ActiveValue::Set(email.to_owned()),
+        token_hash: ActiveValue::Set(hash_token(&token)),
+        expires_at: ActiveValue::Set((Utc::now() + Duration::seconds(ttl_secs)).into()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+
+    Ok(token)
+}
+
+/// Marks every unused token of `user_id` for `purpose` as used.
+pub async fn invalidate<C: ConnectionTrait>(db: &C, user_id: Uuid, purpose: &str) -> Result<()> {
+    one_time_token::Entity::update_many()
+        .col_expr(
+            one_time_token::Column::UsedAt,
+            Expr::value(Some(DateTimeWithTimeZone::from(Utc::now()))),
+        )
+        .filter(one_time_token::Column::UserId.eq(user_id))
+        .filter(one_time_token::Column::Purpose.eq(purpose))
+        .filter(one_time_token::Column::UsedAt.is_null())
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+fn invalid_token() -> HttpError {
+    HttpError::bad_request(
+        Some("invalid_token".to_owned()),
+        Some("The link is invalid, has expired or has already been used.".to_owned()),
+    )
+}
+
+/// Uses up `token` and returns the user it was issued to.
+///
+/// A token stops working once the account's email address is no longer the one it was sent to.
+pub async fn consume<C: ConnectionTrait>(db: &C, token: &str, purpose: &str) -> Result<user::Model> {
+    let (user, email) = consume_for_address(db, token, purpose).await?;
+    if user.email == email {
+        Ok(user)
+    } else {
+        Err(invalid_token().into())
+    }
+}
+
+/// Uses up `token` and returns the user it was issued to, along with the address it was sent to,
+/// which need not be the account's.
+///
+/// Marking the token as used is a single conditional update, so two requests racing with the same
+/// token can't both succeed.
+pub async fn consume_for_address<C: ConnectionTrait>(
+    db: &C,
+    token: &str,
+    purpose: &str,
+) -> Result<(user::Model, String)> {
+    let now = DateTimeWithTimeZone::from(Utc::now());
+    let token_hash = hash_token(token);
+    let used = one_time_token::Entity::update_many()
+        .col_expr(one_time_token::Column::UsedAt, Expr::value(Some(now)))
+        .filter(one_time_token::Column::TokenHash.eq(token_hash.as_str()))
+        .filter(one_time_token::Column::Purpose.eq(purpose))
+        .filter(one_time_token::Column::UsedAt.is_null())
+        .filter(one_time_token::Column::ExpiresAt.gt(now))
+        .exec(db)
+        .await?;
+    if used.rows_affected != 1 {
+        return Err(invalid_token().into());
+    }
+
+    let (token, user) = one_time_token::Entity::find()
+        .filter(one_time_token::Column::TokenHash.eq(token_hash.as_str()))
+        .find_also_related(user::Entity)
+        .one(db)
+        .await?
+        .ok_or_else(invalid_token)?;
+    Ok((user.ok_or_else(invalid_token)?, token.email))
+}
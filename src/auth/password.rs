@@ -0,0 +1,101 @@
+use crate::{
+    error::{HttpError, ValidationErrorItem},
+    settings::PasswordSettings,
+    Result,
+};
+use anyhow::Context;
+use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordVerifier};
+use std::collections::HashSet;
+
+/// The rules a new password has to meet, with the breached password list loaded.
+#[derive(Debug)]
+pub struct PasswordPolicy {
+    min_length: usize,
+    max_length: usize,
+    breached: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    pub fn from_settings(settings: &PasswordSettings) -> anyhow::Result<Self> {
+        let breached = match &settings.breached_passwords_file {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| {
+                    format!("could not read the breached passwords in {}", path.display())
+                })?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        Ok(Self {
+            min_length: settings.min_length,
+            max_length: settings.max_length,
+            breached,
+        })
+    }
+
+    /// What is wrong with `password`, if anything.
+    pub fn check(&self, password: &str) -> std::result::Result<(), String> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            Err(format!("Must be at least {} characters long", self.min_length))
+        } else if length > self.max_length {
+            Err(format!("Must be at most {} characters long", self.max_length))
+        } else if self.breached.contains(password) {
+            Err("Appears in a list of breached passwords, choose another one".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Like [`PasswordPolicy::check`], but as a 422 for the request body `field`.
+    pub fn validate(&self, field: &str, password: &str) -> Result<()> {
+        self.check(password).map_err(|msg| {
+            HttpError::unprocessable_entity(vec![ValidationErrorItem {
+                loc: vec!["body".to_owned(), field.to_owned()],
+                msg,
+                ty: "value_error.password".to_owned(),
+            }])
+            .into()
+        })
+    }
+}
+
+pub async fn hash_password(password: String) -> Result<String> {
+    // Argon2 hashing is designed to be computationally intensive,
+    // so we need to do this on a blocking thread.
+    tokio::task::spawn_blocking(move || -> Result<String> {
+        let salt = SaltString::generate(rand::thread_rng());
+        Ok(
+            PasswordHash::generate(Argon2::default(), password, salt.as_str())
+                .map_err(|e| anyhow::anyhow!("failed to generate password hash: {}", e))?
+                .to_string(),
+        )
+    })
+    .await
+    .context("panic in generating password hash")?
+}
+
+/// A hash with the same parameters as real ones, of a password no account has. Checking against
+/// it when there is no account makes a failed login take as long either way.
+pub const DUMMY_PASSWORD_HASH: &str =
+    "$argon2id$v=19$m=4096,t=3,p=1$1p+q0WZQbIh0Kajq2IFdYA$Ay9AhY71a6MyrVhQ9rHUVoWaeQU43qhJZDBLfKnxjJg";
+
+/// Checks `password` against a stored Argon2 `password_hash`, returning `Ok(false)` on mismatch.
+pub async fn verify_password(password: String, password_hash: String) -> Result<bool> {
+    tokio::task::spawn_blocking(move || -> Result<bool> {
+        let hash = PasswordHash::new(&password_hash)
+            .map_err(|e| anyhow::anyhow!("invalid password hash: {}", e))?;
+
+        match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(anyhow::anyhow!("failed to verify password hash: {}", e).into()),
+        }
+    })
+    .await
+    .context("panic in verifying password hash")?
+}
@@ -0,0 +1,114 @@
+//! Roles and what they allow.
+//!
+//! Routes declare the permission they need with [`require`], as a `route_layer` on their method
+//! router, so that a handler only runs for callers whose role grants it. The same layer declares
+//! the scope a personal access token needs for the route. Disabled accounts never get this far, as
+//! [`AuthUser`] already turns them away.
+use super::{access_token::Scope, authenticate, server, AuthUser};
+use crate::{error::HttpError, Result};
+use axum::{
+    body::Body,
+    extract::RequestParts,
+    http::Request,
+    middleware::{self, FromFnLayer, Next},
+    response::{IntoResponse, Response},
+};
+use entity::user::{self, Role};
+use sea_orm::EntityTrait;
+use std::{future::Future, pin::Pin};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// See projects and their tasks.
+    ViewProjects,
+    /// Create, change and delete projects and tasks.
+    EditProjects,
+    /// List, disable, re-enable and promote other users.
+    ManageUsers,
+}
+
+impl Permission {
+    pub fn granted_to(self, role: Role) -> bool {
+        match role {
+            Role::Admin => true,
+            Role::Member => self != Permission::ManageUsers,
+            Role::Guest => self == Permission::ViewProjects,
+        }
+    }
+}
+
+/// Refuses accounts that an admin has disabled.
+pub fn ensure_enabled(account: &user::Model) -> Result<()> {
+    match account.disabled_at {
+        None => Ok(()),
+        Some(_) => Err(HttpError::forbidden(
+            Some("account_disabled".to_owned()),
+            Some("This account has been disabled.".to_owned()),
+        )
+        .into()),
+    }
+}
+
+/// The caller, provided their role grants `permission` and their token, if it is a personal
+/// access token, has `scope`.
+pub async fn authorize<B: Send>(
+    req: &mut RequestParts<B>,
+    permission: Permission,
+    scope: Scope,
+) -> Result<AuthUser> {
+    let user = authenticate(req)
+        .await?
+        .ok_or_else(|| HttpError::unauthorized(None, None))?;
+    user.require_scope(scope)?;
+    let ctx = server(req).await?;
+    let account = user::Entity::find_by_id(user.user_id)
+        .one(&ctx.db)
+        .await?
+        .ok_or_else(|| HttpError::unauthorized(None, None))?;
+    if !permission.granted_to(account.role) {
+        return Err(HttpError::forbidden(
+            Some("insufficient_role".to_owned()),
+            Some("Your role does not allow this.".to_owned()),
+        )
+        .into());
+    }
+    Ok(user)
+}
+
+type Checked = Pin<Box<dyn Future<Output = Response> + Send>>;
+
+/// A layer that only lets callers through whose role grants `permission`, and personal access
+/// tokens only with `scope`, e.g.
+/// `post(create_task).route_layer(require(Permission::EditProjects, Scope::WriteTasks))`.
+///
+/// The caller it finds is handed on, so that the handler's [`AuthUser`] doesn't authenticate the
+/// request a second time.
+pub fn require(
+    permission: Permission,
+    scope: Scope,
+) -> FromFnLayer<impl Fn(Request<Body>, Next<Body>) -> Checked + Clone + Send + Sync + 'static> {
+    middleware::from_fn(move |req, next| Box::pin(check(permission, scope, req, next)) as Checked)
+}
+
+async fn check(
+    permission: Permission,
+    scope: Scope,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let mut parts = RequestParts::new(req);
+    let user = match authorize(&mut parts, permission, scope).await {
+        Ok(user) => user,
+        Err(e) => return e.into_response(),
+    };
+    // Only the headers were looked at, so the body is still there.
+    let mut req = match parts.try_into_request() {
+        Ok(req) => req,
+        Err(e) => {
+            tracing::error!("request body gone after the permission check: {}", e);
+            return HttpError::internal_server_errer(None, None).into_response();
+        }
+    };
+    req.extensions_mut().insert(user);
+    next.run(req).await
+}
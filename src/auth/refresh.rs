@@ -0,0 +1,150 @@
+use super::permission::ensure_enabled;
+use crate::{error::HttpError, settings::AuthSettings, Result};
+use chrono::{Duration, Utc};
+use entity::{refresh_token, user};
+use rand::RngCore;
+use sea_orm::{
+    prelude::{DateTimeWithTimeZone, Uuid},
+    sea_query::Expr,
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter,
+    TransactionTrait,
+};
+use sha2::{Digest, Sha256};
+
+/// Generates a new random opaque token, suitable for handing to a client.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Hashes an opaque token for storage. The tokens are random and long, so a fast hash is enough.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Issues a new refresh token for `user_id`, starting a new token family unless one is given.
+pub async fn issue<C: ConnectionTrait>(
+    db: &C,
+    settings: &AuthSettings,
+    user_id: Uuid,
+    family_id: Option<Uuid>,
+) -> Result<String> {
+    let token = generate_token();
+    refresh_token::ActiveModel {
+        user_id: ActiveValue::Set(user_id),
+        family_id: ActiveValue::Set(family_id.unwrap_or_else(Uuid::new_v4)),
+        token_hash: ActiveValue::Set(hash_token(&token)),
+        expires_at: ActiveValue::Set(
+            (Utc::now() + Duration::seconds(settings.refresh_token_ttl_secs)).into(),
+        ),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+
+    Ok(token)
+}
+
+fn invalid_refresh_token() -> HttpError {
+    HttpError::unauthorized(
+        Some("invalid_refresh_token".to_owned()),
+        Some("The refresh token is invalid or has expired.".to_owned()),
+    )
+}
+
+/// Exchanges a refresh token for a new one in the same family, returning the owning user's id.
+///
+/// A token can only be used once. Presenting a token that has already been rotated or revoked
+/// means it has probably leaked, so every token in its family is revoked as well. Tokens of
+/// disabled accounts are refused.
+pub async fn rotate<C: TransactionTrait>(
+    db: &C,
+    settings: &AuthSettings,
+    token: &str,
+) -> Result<(Uuid, String)> {
+    let txn = db.begin().await?;
+
+    let (existing, owner) = refresh_token::Entity::find_by_token_hash(&hash_token(token))
+        .find_also_related(user::Entity)
+        .one(&txn)
+        .await?
+        .ok_or_else(invalid_refresh_token)?;
+
+    let now = DateTimeWithTimeZone::from(Utc::now());
+    if existing.revoked_at.is_none() && existing.expires_at < now {
+        return Err(invalid_refresh_token().into());
+    }
+
+    // Marking the token as used is a single conditional update, so of two requests racing with
+    // the same token only one gets through, and the other counts as reuse.
+    let used = match existing.revoked_at {
+        Some(_) => 0,
+        None => {
+            refresh_token::Entity::update_many()
+                .col_expr(refresh_token::Column::RevokedAt, Expr::value(Some(now)))
+                .filter(refresh_token::Column::Id.eq(existing.id))
+                .filter(refresh_token::Column::RevokedAt.is_null())
+                .exec(&txn)
+                .await?
+                .rows_affected
+        }
+    };
+    if used != 1 {
+        tracing::warn!(
+            user_id = %existing.user_id,
+            family_id = %existing.family_id,
+            "refresh token reused, revoking token family"
+        );
+        revoke_family(&txn, existing.family_id).await?;
+        txn.commit().await?;
+        return Err(invalid_refresh_token().into());
+    }
+
+    // Dropping the transaction on the way out leaves the token unused.
+    ensure_enabled(&owner.ok_or_else(invalid_refresh_token)?)?;
+
+    let (user_id, family_id) = (existing.user_id, existing.family_id);
+    let new_token = issue(&txn, settings, user_id, Some(family_id)).await?;
+    txn.commit().await?;
+
+    Ok((user_id, new_token))
+}
+
+/// Revokes the family of the given token. Unknown tokens are ignored so logging out is idempotent.
+pub async fn revoke<C: ConnectionTrait>(db: &C, token: &str) -> Result<()> {
+    if let Some(existing) = refresh_token::Entity::find_by_token_hash(&hash_token(token))
+        .one(db)
+        .await?
+    {
+        revoke_family(db, existing.family_id).await?;
+    }
+    Ok(())
+}
+
+pub async fn revoke_family<C: ConnectionTrait>(db: &C, family_id: Uuid) -> Result<()> {
+    refresh_token::Entity::update_many()
+        .col_expr(
+            refresh_token::Column::RevokedAt,
+            Expr::value(Some(DateTimeWithTimeZone::from(Utc::now()))),
+        )
+        .filter(refresh_token::Column::FamilyId.eq(family_id))
+        .filter(refresh_token::Column::RevokedAt.is_null())
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+/// Revokes every refresh token belonging to `user_id`, signing them out on all devices.
+pub async fn revoke_all_for_user<C: ConnectionTrait>(db: &C, user_id: Uuid) -> Result<()> {
+    refresh_token::Entity::update_many()
+        .col_expr(
+            refresh_token::Column::RevokedAt,
+            Expr::value(Some(DateTimeWithTimeZone::from(Utc::now()))),
+        )
+        .filter(refresh_token::Column::UserId.eq(user_id))
+        .filter(refresh_token::Column::RevokedAt.is_null())
+        .exec(db)
+        .await?;
+    Ok(())
+}
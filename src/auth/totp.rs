@@ -0,0 +1,111 @@
+//! Time-based one-time passwords (RFC 6238), as shown by authenticator apps, and the recovery
+//! codes that stand in for them when the device is lost.
+use chrono::{DateTime, Utc};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{Rng, RngCore};
+use sha1::Sha1;
+
+/// How long each code is valid for, in seconds. Authenticator apps assume 30.
+pub const STEP_SECS: i64 = 30;
+pub const DIGITS: u32 = 6;
+/// How many steps either side of the current one are accepted, to allow for clock drift.
+const SKEW: i64 = 1;
+/// How many recovery codes are handed out at a time.
+pub const RECOVERY_CODES: usize = 10;
+
+/// A new random secret, base32 encoded as authenticator apps expect.
+pub fn generate_secret() -> String {
+    // 160 bits, the size of the SHA-1 output, as RFC 4226 recommends.
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE32_NOPAD.encode(&bytes)
+}
+
+/// The time step that `now` falls in.
+pub fn step_at(now: DateTime<Utc>) -> i64 {
+    now.timestamp().div_euclid(STEP_SECS)
+}
+
+/// The code for `step`, or `None` if `secret` isn't valid base32.
+pub fn code_at(secret: &str, step: i64) -> Option<String> {
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).ok()?;
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation, RFC 4226 section 5.3.
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    Some(format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    ))
+}
+
+/// Checks `code` against the steps around `now`, returning the step it belongs to.
+///
+/// Steps up to and including `last_used_step` are refused, so that a code can't be used twice.
+pub fn verify(secret: &str, code: &str, now: DateTime<Utc>, last_used_step: Option<i64>) -> Option<i64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+
+    let current = step_at(now);
+    (current - SKEW..=current + SKEW)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| code_at(secret, *step).is_some_and(|expected| expected == code))
+}
+
+/// The `otpauth://` URI that authenticator apps read, usually from a QR code.
+pub fn provisioning_uri(secret: &str, issuer: &str, account: &str) -> String {
+    // Percent encoded throughout: some apps show a `+` for a space literally.
+    let encode = |value: &str| {
+        url::form_urlencoded::byte_serialize(value.as_bytes())
+            .collect::<String>()
+            .replace('+', "%20")
+    };
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        encode(issuer),
+        encode(account),
+        secret,
+        encode(issuer),
+        DIGITS,
+        STEP_SECS
+    )
+}
+
+/// New recovery codes, like `k3fq-8mzt-w2xa`. Each can be used once instead of a code.
+pub fn generate_recovery_codes() -> Vec<String> {
+    // Without characters that are easily confused, like `0` and `o` or `1` and `l`.
+    const ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            (0..3)
+                .map(|_| {
+                    (0..4)
+                        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                        .collect::<String>()
+                })
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect()
+}
+
+/// Recovery codes as they are hashed, so that case and spacing don't matter when typing them.
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
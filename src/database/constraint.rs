@@ -0,0 +1,134 @@
+//! Recognising constraint violations in database errors.
+//!
+//! sea-orm only hands us the driver's error message, so the violation is picked out of the text.
+//! SQLite and Postgres word these differently, and only SQLite names the column in every case, so
+//! the column is best effort.
+use sea_orm::DbErr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConstraintKind {
+    Unique,
+    ForeignKey,
+    NotNull,
+    Check,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstraintViolation {
+    pub kind: ConstraintKind,
+    /// The offending column, when the message names it. Postgres unique violations only name the
+    /// constraint, see [ConstraintViolation::column_among].
+    pub column: Option<String>,
+    /// The name of the violated constraint, when the message gives it away.
+    pub constraint: Option<String>,
+}
+
+impl ConstraintViolation {
+    fn new(kind: ConstraintKind, column: Option<String>, constraint: Option<String>) -> Self {
+        Self {
+            kind,
+            column,
+            constraint,
+        }
+    }
+
+    /// Which of `columns` the violation is about, if any. Failing a named column, the constraint
+    /// name is matched against the naming conventions: Postgres calls the constraint of a `UNIQUE`
+    /// column `{table}_{column}_key`, and our indexes are named `idx_{table}_{columns}`.
+    pub fn column_among<'a>(&self, columns: &[&'a str]) -> Option<&'a str> {
+        if let Some(column) = &self.column {
+            return columns.iter().copied().find(|candidate| candidate == column);
+        }
+        let constraint = self.constraint.as_deref()?;
+        let name = match constraint.strip_suffix("_key") {
+            Some(name) => name,
+            None if constraint.starts_with("idx_") => constraint,
+            None => return None,
+        };
+        // Longest first, so that a `hash` column doesn't claim `..._token_hash_key`.
+        columns
+            .iter()
+            .copied()
+            .filter(|column| name.strip_suffix(column).is_some_and(|rest| rest.ends_with('_')))
+            .max_by_key(|column| column.len())
+    }
+}
+
+/// Works out whether `err` is a constraint violation, and which.
+pub fn classify(err: &DbErr) -> Option<ConstraintViolation> {
+    match err {
+        DbErr::Exec(message) | DbErr::Query(message) => {
+            classify_sqlite(message).or_else(|| classify_postgres(message))
+        }
+        _ => None,
+    }
+}
+
+/// The column out of SQLite's `table.column` list, e.g. `UNIQUE constraint failed: user.email`.
+/// Composite constraints list several columns and only the first is kept.
+fn sqlite_column(columns: &str) -> Option<String> {
+    let first = columns.split(',').next()?.trim();
+    let column = first.rsplit('.').next()?.trim();
+    (!column.is_empty()).then(|| column.to_owned())
+}
+
+fn classify_sqlite(message: &str) -> Option<ConstraintViolation> {
+    let after = |marker: &str| message.find(marker).map(|i| &message[i + marker.len()..]);
+
+    if let Some(columns) = after("UNIQUE constraint failed:") {
+        Some(ConstraintViolation::new(
+            ConstraintKind::Unique,
+            sqlite_column(columns),
+            None,
+        ))
+    } else if let Some(columns) = after("NOT NULL constraint failed:") {
+        Some(ConstraintViolation::new(
+            ConstraintKind::NotNull,
+            sqlite_column(columns),
+            None,
+        ))
+    } else if let Some(name) = after("CHECK constraint failed:") {
+        Some(ConstraintViolation::new(
+            ConstraintKind::Check,
+            None,
+            Some(name.trim().to_owned()),
+        ))
+    } else if message.contains("FOREIGN KEY constraint failed") {
+        Some(ConstraintViolation::new(ConstraintKind::ForeignKey, None, None))
+    } else {
+        None
+    }
+}
+
+/// The first double quoted name in `text`, e.g. the constraint in
+/// `violates unique constraint "user_email_key"`.
+fn quoted(text: &str) -> Option<String> {
+    let start = text.find('"')? + 1;
+    let len = text[start..].find('"')?;
+    Some(text[start..start + len].to_owned())
+}
+
+fn classify_postgres(message: &str) -> Option<ConstraintViolation> {
+    let after = |marker: &str| message.find(marker).map(|i| &message[i + marker.len()..]);
+
+    if let Some(rest) = after("violates unique constraint") {
+        Some(ConstraintViolation::new(
+            ConstraintKind::Unique,
+            None,
+            quoted(rest),
+        ))
+    } else if let Some(rest) = after("violates foreign key constraint") {
+        Some(ConstraintViolation::new(
+            ConstraintKind::ForeignKey,
+            None,
+            quoted(rest),
+        ))
+    } else if message.contains("violates not-null constraint") {
+        // `null value in column "email" of relation "user" violates not-null constraint`
+        let column = after("null value in column").and_then(quoted);
+        Some(ConstraintViolation::new(ConstraintKind::NotNull, column, None))
+    } else {
+        after("violates check constraint")
+            .map(|rest| ConstraintViolation::new(ConstraintKind::Check, None, quoted(rest)))
+    }
+}
@@ -0,0 +1,86 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DatabaseStatus {
+    /// Still trying to reach the database for the first time.
+    Connecting,
+    Connected,
+    /// The database was reachable before but the last check failed.
+    Unavailable,
+}
+
+/// What we last saw of the database, as reported by the readiness probe.
+#[derive(Debug, Clone, Serialize)]
+pub struct DatabaseHealthSnapshot {
+    pub status: DatabaseStatus,
+    /// Since when the database has had this status.
+    pub since: DateTime<Utc>,
+    /// A fixed description of what failed. The probes are unauthenticated, so driver errors only
+    /// go to the logs.
+    pub last_error: Option<&'static str>,
+}
+
+/// Connection health of the database, shared between the connection retry loop and the health
+/// checks through [`crate::server::Server`].
+#[derive(Debug, Clone)]
+pub struct DatabaseHealth {
+    state: Arc<RwLock<DatabaseHealthSnapshot>>,
+}
+
+impl Default for DatabaseHealth {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(RwLock::new(DatabaseHealthSnapshot {
+                status: DatabaseStatus::Connecting,
+                since: Utc::now(),
+                last_error: None,
+            })),
+        }
+    }
+}
+
+impl DatabaseHealth {
+    /// What is reported when the database can't be reached, whatever the driver said.
+    pub const UNREACHABLE: &'static str = "database unreachable";
+
+    pub fn snapshot(&self) -> DatabaseHealthSnapshot {
+        self.state
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    pub fn status(&self) -> DatabaseStatus {
+        self.snapshot().status
+    }
+
+    fn set(&self, status: DatabaseStatus, last_error: Option<&'static str>) {
+        let mut state = self
+            .state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if state.status != status {
+            tracing::info!(from = ?state.status, to = ?status, "database status changed");
+            state.status = status;
+            state.since = Utc::now();
+        }
+        state.last_error = last_error;
+    }
+
+    pub fn set_connected(&self) {
+        self.set(DatabaseStatus::Connected, None);
+    }
+
+    /// Records a failed connection attempt or health check.
+    pub fn set_failed(&self, error: &'static str) {
+        // A database we have never reached is still starting up, not broken.
+        let status = match self.status() {
+            DatabaseStatus::Connecting => DatabaseStatus::Connecting,
+            _ => DatabaseStatus::Unavailable,
+        };
+        self.set(status, Some(error));
+    }
+}
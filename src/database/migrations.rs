@@ -0,0 +1,625 @@
+//! Versioned schema migrations.
+//!
+//! Migrations are written with `sea_query` builders rather than raw SQL, so the same list can be
+//! applied to both SQLite and Postgres. Applied versions are recorded in the `schema_migrations`
+//! table, and a migration that has been released must never be edited: add a new one instead.
+use chrono::Utc;
+use sea_orm::{
+    sea_query::{
+        Alias, ColumnDef, Expr, ForeignKey, ForeignKeyAction, Index, Query, Table,
+        TableCreateStatement,
+    },
+    ConnectionTrait, DbBackend, DbErr, Statement, TransactionTrait,
+};
+
+/// The table that records which migrations have been applied.
+pub const HISTORY_TABLE: &str = "schema_migrations";
+
+pub struct Migration {
+    /// Migrations are applied in ascending order of version.
+    pub version: i64,
+    pub name: &'static str,
+    up: fn(DbBackend) -> Vec<Statement>,
+}
+
+impl Migration {
+    /// The statements this migration runs on `backend`.
+    pub fn statements(&self, backend: DbBackend) -> Vec<Statement> {
+        (self.up)(backend)
+    }
+}
+
+/// Every migration, in the order they are applied.
+pub fn all() -> Vec<Migration> {
+    vec![
+        Migration {
+            version: 1,
+            name: "initial",
+            up: initial,
+        },
+        Migration {
+            version: 2,
+            name: "email_verification_and_one_time_tokens",
+            up: one_time_tokens,
+        },
+        Migration {
+            version: 3,
+            name: "two_factor_authentication",
+            up: two_factor,
+        },
+        Migration {
+            version: 4,
+            name: "personal_access_tokens",
+            up: access_tokens,
+        },
+        Migration {
+            version: 5,
+            name: "login_attempts",
+            up: login_attempts,
+        },
+        Migration {
+            version: 6,
+            name: "user_roles",
+            up: user_roles,
+        },
+        Migration {
+            version: 7,
+            name: "workspaces",
+            up: workspaces,
+        },
+    ]
+}
+
+fn col(name: &str) -> ColumnDef {
+    ColumnDef::new(Alias::new(name))
+}
+
+/// Adds the `created_at` and `updated_at` columns that every table carries.
+fn with_timestamps(table: &mut TableCreateStatement) -> &mut TableCreateStatement {
+    table
+        .col(col("created_at").timestamp_with_time_zone().not_null())
+        .col(col("updated_at").timestamp_with_time_zone().not_null())
+}
+
+fn initial(backend: DbBackend) -> Vec<Statement> {
+    let user = with_timestamps(
+        Table::create()
+            .table(Alias::new("user"))
+            .col(col("user_id").uuid().not_null().primary_key())
+            .col(col("username").string().not_null().unique_key())
+            .col(col("email").string().not_null().unique_key())
+            .col(col("bio").string().not_null().default(""))
+            .col(col("image").string())
+            .col(col("password_hash").text().not_null()),
+    )
+    .to_owned();
+
+    let project = with_timestamps(
+        Table::create()
+            .table(Alias::new("project"))
+            .col(col("id").uuid().not_null().primary_key())
+            .col(col("title").string().not_null())
+            .col(col("text").text().not_null())
+            .col(col("user_id").uuid().not_null()),
+    )
+    .foreign_key(
+        ForeignKey::create()
+            .name("fk_project_user")
+            .from(Alias::new("project"), Alias::new("user_id"))
+            .to(Alias::new("user"), Alias::new("user_id"))
+            .on_delete(ForeignKeyAction::Cascade),
+    )
+    .to_owned();
+
+    let task = with_timestamps(
+        Table::create()
+            .table(Alias::new("task"))
+            .col(col("id").uuid().not_null().primary_key())
+            .col(col("title").string().not_null())
+            .col(col("text").text().not_null())
+            .col(col("project_id").uuid())
+            .col(col("done").boolean().not_null().default(false)),
+    )
+    .foreign_key(
+        ForeignKey::create()
+            .name("fk_task_project")
+            .from(Alias::new("task"), Alias::new("project_id"))
+            .to(Alias::new("project"), Alias::new("id"))
+            .on_delete(ForeignKeyAction::Cascade),
+    )
+    .to_owned();
+
+    let refresh_token = with_timestamps(
+        Table::create()
+            .table(Alias::new("refresh_token"))
+            .col(col("id").uuid().not_null().primary_key())
+            .col(col("user_id").uuid().not_null())
+            .col(col("family_id").uuid().not_null())
+            .col(col("token_hash").string().not_null().unique_key())
+            .col(col("expires_at").timestamp_with_time_zone().not_null())
+            .col(col("revoked_at").timestamp_with_time_zone()),
+    )
+    .foreign_key(
+        ForeignKey::create()
+            .name("fk_refresh_token_user")
+            .from(Alias::new("refresh_token"), Alias::new("user_id"))
+            .to(Alias::new("user"), Alias::new("user_id"))
+            .on_delete(ForeignKeyAction::Cascade),
+    )
+    .to_owned();
+
+    vec![
+        backend.build(&user),
+        backend.build(&project),
+        backend.build(
+            Index::create()
+                .name("idx_project_user_id")
+                .table(Alias::new("project"))
+                .col(Alias::new("user_id")),
+        ),
+        backend.build(&task),
+        backend.build(
+            Index::create()
+                .name("idx_task_project_id")
+                .table(Alias::new("task"))
+                .col(Alias::new("project_id")),
+        ),
+        backend.build(&refresh_token),
+        backend.build(
+            Index::create()
+                .name("idx_refresh_token_family_id")
+                .table(Alias::new("refresh_token"))
+                .col(Alias::new("family_id")),
+        ),
+    ]
+}
+
+fn one_time_tokens(backend: DbBackend) -> Vec<Statement> {
+    let verified_at = Table::alter()
+        .table(Alias::new("user"))
+        .add_column(col("email_verified_at").timestamp_with_time_zone())
+        .to_owned();
+
+    let one_time_token = with_timestamps(
+        Table::create()
+            .table(Alias::new("one_time_token"))
+            .col(col("id").uuid().not_null().primary_key())
+            .col(col("user_id").uuid().not_null())
+            .col(col("purpose").string().not_null())
+            .col(col("email").string().not_null())
+            .col(col("token_hash").string().not_null().unique_key())
+            .col(col("expires_at").timestamp_with_time_zone().not_null())
+            .col(col("used_at").timestamp_with_time_zone()),
+    )
+    .foreign_key(
+        ForeignKey::create()
+            .name("fk_one_time_token_user")
+            .from(Alias::new("one_time_token"), Alias::new("user_id"))
+            .to(Alias::new("user"), Alias::new("user_id"))
+            .on_delete(ForeignKeyAction::Cascade),
+    )
+    .to_owned();
+
+    vec![
+        backend.build(&verified_at),
+        backend.build(&one_time_token),
+        backend.build(
+            Index::create()
+                .name("idx_one_time_token_user_id")
+                .table(Alias::new("one_time_token"))
+                .col(Alias::new("user_id")),
+        ),
+    ]
+}
+
+fn two_factor(backend: DbBackend) -> Vec<Statement> {
+    // SQLite can only add one column per `ALTER TABLE`.
+    let add_user_column = |column: &mut ColumnDef| {
+        backend.build(
+            Table::alter()
+                .table(Alias::new("user"))
+                .add_column(column),
+        )
+    };
+
+    let recovery_code = with_timestamps(
+        Table::create()
+            .table(Alias::new("recovery_code"))
+            .col(col("id").uuid().not_null().primary_key())
+            .col(col("user_id").uuid().not_null())
+            .col(col("code_hash").text().not_null())
+            .col(col("used_at").timestamp_with_time_zone()),
+    )
+    .foreign_key(
+        ForeignKey::create()
+            .name("fk_recovery_code_user")
+            .from(Alias::new("recovery_code"), Alias::new("user_id"))
+            .to(Alias::new("user"), Alias::new("user_id"))
+            .on_delete(ForeignKeyAction::Cascade),
+    )
+    .to_owned();
+
+    vec![
+        add_user_column(col("totp_secret").string()),
+        add_user_column(col("totp_enabled_at").timestamp_with_time_zone()),
+        add_user_column(col("totp_last_used_step").big_integer()),
+        backend.build(&recovery_code),
+        backend.build(
+            Index::create()
+                .name("idx_recovery_code_user_id")
+                .table(Alias::new("recovery_code"))
+                .col(Alias::new("user_id")),
+        ),
+    ]
+}
+
+fn access_tokens(backend: DbBackend) -> Vec<Statement> {
+    let access_token = with_timestamps(
+        Table::create()
+            .table(Alias::new("access_token"))
+            .col(col("id").uuid().not_null().primary_key())
+            .col(col("user_id").uuid().not_null())
+            .col(col("name").string().not_null())
+            .col(col("scopes").string().not_null())
+            .col(col("token_hash").string().not_null().unique_key())
+            .col(col("expires_at").timestamp_with_time_zone())
+            .col(col("last_used_at").timestamp_with_time_zone()),
+    )
+    .foreign_key(
+        ForeignKey::create()
+            .name("fk_access_token_user")
+            .from(Alias::new("access_token"), Alias::new("user_id"))
+            .to(Alias::new("user"), Alias::new("user_id"))
+            .on_delete(ForeignKeyAction::Cascade),
+    )
+    .to_owned();
+
+    vec![
+        backend.build(&access_token),
+        backend.build(
+            Index::create()
+                .name("idx_access_token_user_id")
+                .table(Alias::new("access_token"))
+                .col(Alias::new("user_id")),
+        ),
+    ]
+}
+
+fn login_attempts(backend: DbBackend) -> Vec<Statement> {
+    let login_attempt = with_timestamps(
+        Table::create()
+            .table(Alias::new("login_attempt"))
+            .col(col("attempt_key").string().not_null().primary_key())
+            .col(col("failures").integer().not_null())
+            .col(col("locked_until").timestamp_with_time_zone())
+            .col(col("reset_at").timestamp_with_time_zone().not_null()),
+    )
+    .to_owned();
+
+    vec![
+        backend.build(&login_attempt),
+        backend.build(
+            Index::create()
+                .name("idx_login_attempt_reset_at")
+                .table(Alias::new("login_attempt"))
+                .col(Alias::new("reset_at")),
+        ),
+    ]
+}
+
+fn user_roles(backend: DbBackend) -> Vec<Statement> {
+    // SQLite can only add one column per `ALTER TABLE`.
+    let add_user_column = |column: &mut ColumnDef| {
+        backend.build(
+            Table::alter()
+                .table(Alias::new("user"))
+                .add_column(column),
+        )
+    };
+
+    vec![
+        add_user_column(col("role").string_len(16).not_null().default("member")),
+        add_user_column(col("disabled_at").timestamp_with_time_zone()),
+    ]
+}
+
+fn workspaces(backend: DbBackend) -> Vec<Statement> {
+    let workspace = with_timestamps(
+        Table::create()
+            .table(Alias::new("workspace"))
+            .col(col("id").uuid().not_null().primary_key())
+            .col(col("name").string().not_null()),
+    )
+    .to_owned();
+
+    let workspace_member = with_timestamps(
+        Table::create()
+            .table(Alias::new("workspace_member"))
+            .col(col("id").uuid().not_null().primary_key())
+            .col(col("workspace_id").uuid().not_null())
+            .col(col("user_id").uuid().not_null())
+            .col(col("role").string_len(16).not_null()),
+    )
+    .foreign_key(
+        ForeignKey::create()
+            .name("fk_workspace_member_workspace")
+            .from(Alias::new("workspace_member"), Alias::new("workspace_id"))
+            .to(Alias::new("workspace"), Alias::new("id"))
+            .on_delete(ForeignKeyAction::Cascade),
+    )
+    .foreign_key(
+        ForeignKey::create()
+            .name("fk_workspace_member_user")
+            .from(Alias::new("workspace_member"), Alias::new("user_id"))
+            .to(Alias::new("user"), Alias::new("user_id"))
+            .on_delete(ForeignKeyAction::Cascade),
+    )
+    .to_owned();
+
+    let workspace_invitation = with_timestamps(
+        Table::create()
+            .table(Alias::new("workspace_invitation"))
+            .col(col("id").uuid().not_null().primary_key())
+            .col(col("workspace_id").uuid().not_null())
+            .col(col("email").string())
+            .col(col("role").string_len(16).not_null())
+            .col(col("token_hash").string().not_null().unique_key())
+            .col(col("invited_by").uuid().not_null())
+            .col(col("expires_at").timestamp_with_time_zone().not_null())
+            .col(col("used_at").timestamp_with_time_zone()),
+    )
+    .foreign_key(
+        ForeignKey::create()
+            .name("fk_workspace_invitation_workspace")
+            .from(Alias::new("workspace_invitation"), Alias::new("workspace_id"))
+            .to(Alias::new("workspace"), Alias::new("id"))
+            .on_delete(ForeignKeyAction::Cascade),
+    )
+    .to_owned();
+
+    // Every existing user gets a personal workspace with the same id, which they own and where
+    // their projects end up.
+    let user_columns = || {
+        Query::select()
+            .column(Alias::new("user_id"))
+            .from(Alias::new("user"))
+            .to_owned()
+    };
+    let personal_workspaces = Query::insert()
+        .into_table(Alias::new("workspace"))
+        .columns(vec![
+            Alias::new("id"),
+            Alias::new("name"),
+            Alias::new("created_at"),
+            Alias::new("updated_at"),
+        ])
+        .select_from(
+            user_columns()
+                .column(Alias::new("username"))
+                .column(Alias::new("created_at"))
+                .column(Alias::new("updated_at"))
+                .to_owned(),
+        )
+        .expect("as many columns as values")
+        .to_owned();
+    let owners = Query::insert()
+        .into_table(Alias::new("workspace_member"))
+        .columns(vec![
+            Alias::new("id"),
+            Alias::new("workspace_id"),
+            Alias::new("user_id"),
+            Alias::new("role"),
+            Alias::new("created_at"),
+            Alias::new("updated_at"),
+        ])
+        .select_from(
+            user_columns()
+                .column(Alias::new("user_id"))
+                .column(Alias::new("user_id"))
+                .expr(Expr::val("owner"))
+                .column(Alias::new("created_at"))
+                .column(Alias::new("updated_at"))
+                .to_owned(),
+        )
+        .expect("as many columns as values")
+        .to_owned();
+    let project_workspaces = Query::update()
+        .table(Alias::new("project"))
+        .col_expr(Alias::new("workspace_id"), Expr::col(Alias::new("user_id")).into())
+        .to_owned();
+
+    let mut statements = vec![
+        backend.build(&workspace),
+        backend.build(&workspace_member),
+        backend.build(
+            Index::create()
+                .name("idx_workspace_member_workspace_user")
+                .table(Alias::new("workspace_member"))
+                .col(Alias::new("workspace_id"))
+                .col(Alias::new("user_id"))
+                .unique(),
+        ),
+        backend.build(
+            Index::create()
+                .name("idx_workspace_member_user_id")
+                .table(Alias::new("workspace_member"))
+                .col(Alias::new("user_id")),
+        ),
+        backend.build(&workspace_invitation),
+        backend.build(
+            Index::create()
+                .name("idx_workspace_invitation_workspace_id")
+                .table(Alias::new("workspace_invitation"))
+                .col(Alias::new("workspace_id")),
+        ),
+        backend.build(
+            Table::alter()
+                .table(Alias::new("project"))
+                .add_column(col("workspace_id").uuid()),
+        ),
+        backend.build(&personal_workspaces),
+        backend.build(&owners),
+        backend.build(&project_workspaces),
+        backend.build(
+            Index::create()
+                .name("idx_project_workspace_id")
+                .table(Alias::new("project"))
+                .col(Alias::new("workspace_id")),
+        ),
+    ];
+    // SQLite can neither make an added column `NOT NULL` without a default nor add a foreign key
+    // to an existing table, so there the column stays nullable and unchecked.
+    if backend == DbBackend::Postgres {
+        statements.push(
+            backend.build(
+                Table::alter()
+                    .table(Alias::new("project"))
+                    .modify_column(col("workspace_id").uuid().not_null()),
+            ),
+        );
+        statements.push(
+            backend.build(
+                ForeignKey::create()
+                    .name("fk_project_workspace")
+                    .from(Alias::new("project"), Alias::new("workspace_id"))
+                    .to(Alias::new("workspace"), Alias::new("id"))
+                    .on_delete(ForeignKeyAction::Cascade),
+            ),
+        );
+    }
+    statements
+}
+
+async fn ensure_history_table<C: ConnectionTrait>(db: &C) -> Result<(), DbErr> {
+    let backend = db.get_database_backend();
+    db.execute(
+        backend.build(
+            Table::create()
+                .table(Alias::new(HISTORY_TABLE))
+                .if_not_exists()
+                .col(col("version").big_integer().not_null().primary_key())
+                .col(col("name").string().not_null())
+                .col(col("applied_at").timestamp_with_time_zone().not_null()),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Whether the history table exists yet. It is only created by [run].
+async fn history_table_exists<C: ConnectionTrait>(db: &C) -> Result<bool, DbErr> {
+    let backend = db.get_database_backend();
+    let statement = match backend {
+        DbBackend::Sqlite => Statement::from_sql_and_values(
+            backend,
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?",
+            vec![HISTORY_TABLE.into()],
+        ),
+        DbBackend::Postgres => Statement::from_sql_and_values(
+            backend,
+            "SELECT table_name FROM information_schema.tables \
+             WHERE table_schema = current_schema() AND table_name = $1",
+            vec![HISTORY_TABLE.into()],
+        ),
+        DbBackend::MySql => {
+            return Err(DbErr::Custom("MySQL is not supported".to_owned()));
+        }
+    };
+    Ok(db.query_one(statement).await?.is_some())
+}
+
+/// The versions that have already been applied, in ascending order. Only reads, so that the
+/// readiness probe can call it often and with a read-only role: without a history table nothing
+/// has been applied.
+pub async fn applied_versions<C: ConnectionTrait>(db: &C) -> Result<Vec<i64>, DbErr> {
+    if !history_table_exists(db).await? {
+        return Ok(Vec::new());
+    }
+    let backend = db.get_database_backend();
+    let rows = db
+        .query_all(
+            backend.build(
+                Query::select()
+                    .column(Alias::new("version"))
+                    .from(Alias::new(HISTORY_TABLE))
+                    .order_by(Alias::new("version"), sea_orm::sea_query::Order::Asc),
+            ),
+        )
+        .await?;
+
+    rows.iter().map(|row| row.try_get("", "version")).collect()
+}
+
+/// The migrations that have not been applied yet.
+pub async fn pending<C: ConnectionTrait>(db: &C) -> Result<Vec<Migration>, DbErr> {
+    let applied = applied_versions(db).await?;
+    Ok(all()
+        .into_iter()
+        .filter(|m| !applied.contains(&m.version))
+        .collect())
+}
+
+/// Applies every pending migration, each in its own transaction, and returns the versions that
+/// were applied.
+pub async fn run<C>(db: &C) -> Result<Vec<i64>, DbErr>
+where
+    C: ConnectionTrait + TransactionTrait,
+{
+    let backend = db.get_database_backend();
+    let mut applied = Vec::new();
+
+    ensure_history_table(db).await?;
+    for migration in pending(db).await? {
+        tracing::info!(
+            version = migration.version,
+            name = migration.name,
+            "applying migration"
+        );
+
+        let txn = db.begin().await?;
+        for statement in migration.statements(backend) {
+            txn.execute(statement).await.map_err(|e| {
+                DbErr::Exec(format!(
+                    "migration {} ({}) failed: {}",
+                    migration.version, migration.name, e
+                ))
+            })?;
+        }
+        txn.execute(
+            backend.build(
+                Query::insert()
+                    .into_table(Alias::new(HISTORY_TABLE))
+                    .columns(vec![
+                        Alias::new("version"),
+                        Alias::new("name"),
+                        Alias::new("applied_at"),
+                    ])
+                    .values_panic(vec![
+                        migration.version.into(),
+                        migration.name.into(),
+                        sea_orm::prelude::DateTimeWithTimeZone::from(Utc::now()).into(),
+                    ]),
+            ),
+        )
+        .await?;
+        txn.commit().await?;
+
+        applied.push(migration.version);
+    }
+
+    Ok(applied)
+}
+
+/// All the migrations as SQL for `backend`, for reviewing or applying by hand.
+pub fn sql(backend: DbBackend) -> Vec<String> {
+    all()
+        .iter()
+        .flat_map(|m| {
+            std::iter::once(format!("-- {}_{}", m.version, m.name)).chain(
+                m.statements(backend)
+                    .into_iter()
+                    .map(|s| format!("{};", s.sql)),
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,152 @@
+use entity::{
+    access_token, login_attempt, one_time_token, project, recovery_code, refresh_token, task, user,
+    workspace, workspace_invitation, workspace_member,
+};
+use sea_orm::{
+    ConnectionTrait, DatabaseConnection, DbBackend, DbErr, EntityTrait, IdenStatic, Iterable,
+    Statement,
+};
+use crate::settings::{DatabaseSettings, Settings};
+
+pub mod constraint;
+pub mod health;
+pub mod migrations;
+pub mod pool;
+
+pub use health::{DatabaseHealth, DatabaseHealthSnapshot, DatabaseStatus};
+pub use pool::DatabasePool;
+
+/// Connects to the database, retrying with capped exponential backoff while it is unreachable, so
+/// that the server can start before the database does.
+pub async fn get_db_pool(
+    settings: &Settings,
+    health: &DatabaseHealth,
+) -> Result<DatabasePool, DbErr> {
+    let retry = &settings.database.connect_retry;
+
+    let mut attempt = 1;
+    loop {
+        let db_opts = settings
+            .database
+            .database_connect()
+            .map_err(|e| DbErr::Conn(format!("invalid database settings: {}", e)))?;
+
+        match DatabasePool::connect(db_opts).await {
+            Ok(pool) => {
+                health.set_connected();
+                return Ok(pool);
+            }
+            Err(e) => {
+                health.set_failed(DatabaseHealth::UNREACHABLE);
+                if retry.max_attempts != 0 && attempt >= retry.max_attempts {
+                    tracing::error!(attempt, "giving up connecting to the database: {}", e);
+                    return Err(e);
+                }
+
+                let backoff = retry.backoff(attempt);
+                tracing::warn!(
+                    attempt,
+                    backoff_ms = backoff.as_millis() as u64,
+                    "could not connect to the database: {}",
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Brings the schema up to date at startup, if `run_migrations` allows it, and then makes sure
+/// that the entities match the migrated tables.
+pub async fn prepare_schema(
+    db: &DatabaseConnection,
+    settings: &DatabaseSettings,
+) -> Result<(), DbErr> {
+    if settings.run_migrations {
+        let applied = migrations::run(db).await?;
+        if !applied.is_empty() {
+            tracing::info!(?applied, "applied database migrations");
+        }
+    }
+
+    let pending = migrations::pending(db).await?;
+    if !pending.is_empty() {
+        let versions: Vec<_> = pending.iter().map(|m| m.version).collect();
+        return Err(DbErr::Custom(format!(
+            "database has pending migrations {:?}; set database.run_migrations to apply them",
+            versions
+        )));
+    }
+
+    verify_schema(db).await
+}
+
+/// The columns `table` actually has in the database.
+async fn table_columns<C: ConnectionTrait>(db: &C, table: &str) -> Result<Vec<String>, DbErr> {
+    let backend = db.get_database_backend();
+    let (statement, column) = match backend {
+        DbBackend::Sqlite => (
+            Statement::from_string(backend, format!("PRAGMA table_info(\"{}\")", table)),
+            "name",
+        ),
+        DbBackend::Postgres => (
+            Statement::from_sql_and_values(
+                backend,
+                "SELECT column_name FROM information_schema.columns \
+                 WHERE table_schema = current_schema() AND table_name = $1",
+                vec![table.into()],
+            ),
+            "column_name",
+        ),
+        DbBackend::MySql => {
+            return Err(DbErr::Custom("MySQL is not supported".to_owned()));
+        }
+    };
+
+    db.query_all(statement)
+        .await?
+        .iter()
+        .map(|row| row.try_get("", column))
+        .collect()
+}
+
+/// Finds the columns of `E` that are missing from its table. Extra columns in the table are fine,
+/// as a newer instance may already have migrated the schema during a rolling deploy.
+async fn missing_columns<E, C>(db: &C, entity: E) -> Result<Vec<String>, DbErr>
+where
+    E: EntityTrait,
+    C: ConnectionTrait,
+{
+    let actual = table_columns(db, entity.table_name()).await?;
+    Ok(E::Column::iter()
+        .map(|c| c.as_str().to_owned())
+        .filter(|c| !actual.contains(c))
+        .map(|c| format!("{}.{}", entity.table_name(), c))
+        .collect())
+}
+
+/// Checks that every entity's columns exist in the migrated schema.
+pub async fn verify_schema<C: ConnectionTrait>(db: &C) -> Result<(), DbErr> {
+    let mut missing = Vec::new();
+    missing.extend(missing_columns(db, user::Entity).await?);
+    missing.extend(missing_columns(db, project::Entity).await?);
+    missing.extend(missing_columns(db, task::Entity).await?);
+    missing.extend(missing_columns(db, refresh_token::Entity).await?);
+    missing.extend(missing_columns(db, one_time_token::Entity).await?);
+    missing.extend(missing_columns(db, recovery_code::Entity).await?);
+    missing.extend(missing_columns(db, access_token::Entity).await?);
+    missing.extend(missing_columns(db, login_attempt::Entity).await?);
+    missing.extend(missing_columns(db, workspace::Entity).await?);
+    missing.extend(missing_columns(db, workspace_member::Entity).await?);
+    missing.extend(missing_columns(db, workspace_invitation::Entity).await?);
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(DbErr::Custom(format!(
+            "entities do not match the migrated schema, missing columns: {}",
+            missing.join(", ")
+        )))
+    }
+}
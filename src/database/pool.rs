@@ -0,0 +1,70 @@
+use sea_orm::{
+    ConnectOptions, DatabaseConnection, DbBackend, DbErr, SqlxPostgresConnector,
+    SqlxSqliteConnector,
+};
+use sqlx::{postgres::PgConnectOptions, sqlite::SqliteConnectOptions, ConnectOptions as _};
+
+/// The connection pool behind a [`DatabaseConnection`].
+///
+/// sea-orm doesn't expose its pool, so it can only be dropped, which leaves Postgres to notice the
+/// abandoned connections on its own. Building the pool here keeps a handle that can close it.
+#[derive(Clone, Debug)]
+pub enum DatabasePool {
+    Sqlite(sqlx::SqlitePool),
+    Postgres(sqlx::PgPool),
+}
+
+impl DatabasePool {
+    /// Opens a pool the way `sea_orm::Database::connect` would.
+    pub async fn connect(options: ConnectOptions) -> Result<Self, DbErr> {
+        let url = options.get_url().to_owned();
+        let log_statements = options.get_sqlx_logging();
+
+        if DbBackend::Postgres.is_prefix_of(&url) {
+            let mut connect = url
+                .parse::<PgConnectOptions>()
+                .map_err(|e| DbErr::Conn(e.to_string()))?;
+            if !log_statements {
+                connect.disable_statement_logging();
+            }
+            let pool = options
+                .pool_options()
+                .connect_with(connect)
+                .await
+                .map_err(|e| DbErr::Conn(e.to_string()))?;
+            Ok(Self::Postgres(pool))
+        } else if DbBackend::Sqlite.is_prefix_of(&url) {
+            let mut connect = url
+                .parse::<SqliteConnectOptions>()
+                .map_err(|e| DbErr::Conn(e.to_string()))?;
+            if !log_statements {
+                connect.disable_statement_logging();
+            }
+            let pool = options
+                .pool_options()
+                .connect_with(connect)
+                .await
+                .map_err(|e| DbErr::Conn(e.to_string()))?;
+            Ok(Self::Sqlite(pool))
+        } else {
+            Err(DbErr::Conn(format!("the URL {} is not a supported database", url)))
+        }
+    }
+
+    /// A sea-orm connection that shares this pool.
+    pub fn connection(&self) -> DatabaseConnection {
+        match self {
+            Self::Sqlite(pool) => SqlxSqliteConnector::from_sqlx_sqlite_pool(pool.clone()),
+            Self::Postgres(pool) => SqlxPostgresConnector::from_sqlx_postgres_pool(pool.clone()),
+        }
+    }
+
+    /// Closes every connection in the pool, waiting for the ones that are checked out to be
+    /// returned. Queries made after this fail.
+    pub async fn close(&self) {
+        match self {
+            Self::Sqlite(pool) => pool.close().await,
+            Self::Postgres(pool) => pool.close().await,
+        }
+    }
+}
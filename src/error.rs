@@ -3,10 +3,11 @@
 use std::fmt;
 use axum::response::IntoResponse;
 use axum::response::Response;
-use axum::{Json, http::StatusCode};
+use axum::{Json, http::{header::RETRY_AFTER, HeaderValue, StatusCode}};
 use sea_orm::DbErr;
+use crate::database::constraint::{self, ConstraintKind, ConstraintViolation};
+use crate::telemetry::current_request_id;
 use serde::Serialize;
-use serde_json::json;
 
 /// A short-hand version of a [std::result::Result] that always returns an Svix [Error].
 pub type Result<T> = std::result::Result<T, Error>;
@@ -66,25 +67,110 @@ impl From<String> for Error {
 
 impl From<DbErr> for Error {
     fn from(err: DbErr) -> Error {
-        if let DbErr::Query(ref err_str) = err {
-            // Surely there's a better way
-            if err_str.contains("duplicate key value violates unique constraint") {
-                Error::Http(HttpError::conflict(None, None))
-            } else {
-                Error::Database(err.to_string())
+        if let DbErr::RecordNotFound(_) = err {
+            return Error::Http(HttpError::not_found(None, None));
+        }
+
+        match constraint::classify(&err) {
+            Some(violation) => {
+                // The raw message can include SQL and values, so it only goes to the logs.
+                let http = HttpError::from(violation);
+                if http.status.is_server_error() {
+                    tracing::error!("constraint violation: {}", err);
+                } else {
+                    tracing::debug!("constraint violation: {}", err);
+                }
+                Error::Http(http)
+            }
+            None => Error::Database(err.to_string()),
+        }
+    }
+}
+
+/// Columns that request bodies accept under the same name, and so may be pointed at in a `loc`.
+/// Anything else is internal (token hashes, foreign keys, ...) and is not named to the client.
+const BODY_COLUMNS: &[&str] = &[
+    "username", "email", "bio", "image", "title", "text", "done", "name", "role",
+];
+
+impl From<ConstraintViolation> for HttpError {
+    fn from(violation: ConstraintViolation) -> HttpError {
+        let column = match violation.column_among(BODY_COLUMNS) {
+            Some(column) => column,
+            // Not something the client sent: a clash with existing data is still a conflict, but
+            // a missing or invalid internal value is our own bug.
+            None => {
+                return match violation.kind {
+                    ConstraintKind::Unique | ConstraintKind::ForeignKey => {
+                        HttpError::conflict(None, None)
+                    }
+                    ConstraintKind::NotNull | ConstraintKind::Check => {
+                        HttpError::internal_server_errer(None, None)
+                    }
+                };
             }
-        } else {
-            Error::Database(err.to_string())
+        };
+
+        let (msg, ty) = match violation.kind {
+            ConstraintKind::Unique => ("is already in use", "value_error.unique"),
+            ConstraintKind::ForeignKey => (
+                "refers to a record that does not exist",
+                "value_error.foreign_key",
+            ),
+            ConstraintKind::NotNull => ("field required", "value_error.missing"),
+            ConstraintKind::Check => ("is not an allowed value", "value_error.check"),
+        };
+        let detail = vec![ValidationErrorItem {
+            loc: vec!["body".to_owned(), column.to_owned()],
+            msg: msg.to_owned(),
+            ty: ty.to_owned(),
+        }];
+
+        match violation.kind {
+            ConstraintKind::Unique => HttpError::conflict_fields(detail),
+            _ => HttpError::unprocessable_entity(detail),
         }
     }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        tracing::debug!("{:?}", &self);
+        let code = match &self {
+            Error::Http(s) => {
+                tracing::debug!("{}", s);
+                return self.into_http_error().into_response();
+            }
+            Error::Validation(_) => "validation_error",
+            Error::Database(_) => "database_error",
+            Error::Queue(_) => "queue_error",
+            Error::Generic(_) | Error::Anyhow(_) => "internal_error",
+        };
+        // The details stay in the logs, under the request id that the client gets back.
+        tracing::error!(code, "request failed: {}", self);
+        self.into_http_error().into_response()
+    }
+}
+
+impl Error {
+    /// What the client is told about the error. Only [`Error::Http`] and [`Error::Validation`]
+    /// carry details meant for clients; the rest get a stable code and nothing else.
+    fn into_http_error(self) -> HttpError {
         match self {
-            Error::Http(s) => s.into_response(),
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({}))).into_response(),
+            Error::Http(s) => s,
+            Error::Validation(detail) => {
+                HttpError::bad_request(Some("validation_error".to_owned()), Some(detail))
+            }
+            Error::Database(_) => HttpError::internal_server_errer(
+                Some("database_error".to_owned()),
+                Some("The database failed to handle the request.".to_owned()),
+            ),
+            Error::Queue(_) => HttpError::internal_server_errer(
+                Some("queue_error".to_owned()),
+                Some("A background job could not be queued.".to_owned()),
+            ),
+            Error::Generic(_) | Error::Anyhow(_) => {
+                HttpError::internal_server_errer(Some("internal_error".to_owned()), None)
+            }
         }
     }
 }
@@ -118,6 +204,8 @@ pub struct ValidationErrorItem {
 pub struct HttpError {
     status: StatusCode,
     body: HttpErrorBody,
+    /// Sent as `Retry-After`, in seconds.
+    retry_after: Option<u64>,
 }
 
 impl HttpError {
@@ -125,6 +213,7 @@ impl HttpError {
         Self {
             status,
             body: HttpErrorBody::Standard { code, detail },
+            retry_after: None,
         }
     }
 
@@ -152,6 +241,14 @@ impl HttpError {
         )
     }
 
+    pub fn forbidden(code: Option<String>, detail: Option<String>) -> Self {
+        Self::new_standard(
+            StatusCode::FORBIDDEN,
+            code.unwrap_or_else(|| "forbidden".to_owned()),
+            detail.unwrap_or_else(|| "You are not allowed to do this.".to_owned()),
+        )
+    }
+
     pub fn conflict(code: Option<String>, detail: Option<String>) -> Self {
         Self::new_standard(
             StatusCode::CONFLICT,
@@ -160,10 +257,52 @@ impl HttpError {
         )
     }
 
+    /// A 409 that points at the fields that clash with an existing record.
+    pub fn conflict_fields(detail: Vec<ValidationErrorItem>) -> Self {
+        Self {
+            status: StatusCode::CONFLICT,
+            body: HttpErrorBody::Validation { detail },
+            retry_after: None,
+        }
+    }
+
+    pub fn payload_too_large(code: Option<String>, detail: Option<String>) -> Self {
+        Self::new_standard(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            code.unwrap_or_else(|| "payload_too_large".to_owned()),
+            detail.unwrap_or_else(|| "The request body is too large.".to_owned()),
+        )
+    }
+
+    pub fn unsupported_media_type(code: Option<String>, detail: Option<String>) -> Self {
+        Self::new_standard(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            code.unwrap_or_else(|| "unsupported_media_type".to_owned()),
+            detail.unwrap_or_else(|| "The request body has an unsupported type.".to_owned()),
+        )
+    }
+
+    /// A 429 telling the client to wait `retry_after_secs` before trying again.
+    pub fn too_many_requests(
+        code: Option<String>,
+        detail: Option<String>,
+        retry_after_secs: u64,
+    ) -> Self {
+        Self {
+            retry_after: Some(retry_after_secs),
+            ..Self::new_standard(
+                StatusCode::TOO_MANY_REQUESTS,
+                code.unwrap_or_else(|| "too_many_requests".to_owned()),
+                detail.unwrap_or_else(|| "Too many requests, slow down.".to_owned()),
+            )
+        }
+    }
+
     pub fn unprocessable_entity(detail: Vec<ValidationErrorItem>) -> Self {
         Self {
             status: StatusCode::UNPROCESSABLE_ENTITY,
             body: HttpErrorBody::Validation { detail },
+            retry_after: None,
         }
     }
 
@@ -175,6 +314,14 @@ impl HttpError {
         )
     }
 
+    pub fn service_unavailable(code: Option<String>, detail: Option<String>) -> Self {
+        Self::new_standard(
+            StatusCode::SERVICE_UNAVAILABLE,
+            code.unwrap_or_else(|| "service_unavailable".to_owned()),
+            detail.unwrap_or_else(|| "The service is not available right now.".to_owned()),
+        )
+    }
+
     pub fn not_implemented(code: Option<String>, detail: Option<String>) -> Self {
         Self::new_standard(
             StatusCode::NOT_IMPLEMENTED,
@@ -212,8 +359,25 @@ impl fmt::Display for HttpError {
     }
 }
 
+/// The body sent to the client: the error plus the id of the request that caused it.
+#[derive(Serialize)]
+struct HttpErrorResponse {
+    #[serde(flatten)]
+    body: HttpErrorBody,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
 impl IntoResponse for HttpError {
     fn into_response(self) -> Response {
-        (self.status, Json(self.body)).into_response()
+        let response = HttpErrorResponse {
+            body: self.body,
+            request_id: current_request_id(),
+        };
+        let mut response = (self.status, Json(response)).into_response();
+        if let Some(secs) = self.retry_after {
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
     }
 }
\ No newline at end of file
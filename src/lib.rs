@@ -1,5 +1,9 @@
+pub mod auth;
+pub mod clock;
 pub mod database;
+pub mod mail;
 pub mod settings;
+pub mod storage;
 pub mod server;
 pub mod router;
 pub mod error;
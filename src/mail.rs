@@ -0,0 +1,143 @@
+//! Sending email.
+//!
+//! Handlers only see the [`Mailer`] trait. Which implementation is used comes from
+//! `mail.backend`: SMTP for real deliveries, or writing to files or the log during development.
+use crate::{
+    settings::{MailBackend, MailSettings, SmtpSecurity},
+    Result,
+};
+use anyhow::Context;
+use axum::async_trait;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::{path::PathBuf, sync::Arc};
+use uuid::Uuid;
+
+/// A plain text email.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<()>;
+}
+
+/// The mailer the settings ask for.
+pub fn from_settings(settings: &MailSettings) -> anyhow::Result<Arc<dyn Mailer>> {
+    let from: Mailbox = settings
+        .from
+        .parse()
+        .with_context(|| format!("invalid mail.from `{}`", settings.from))?;
+
+    Ok(match settings.backend {
+        MailBackend::Smtp => {
+            let smtp = settings
+                .smtp
+                .as_ref()
+                .context("mail.smtp must be set when mail.backend is `smtp`")?;
+            let builder = match smtp.security {
+                SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?,
+                SmtpSecurity::StartTls => {
+                    AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?
+                }
+                SmtpSecurity::None => {
+                    AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host)
+                }
+            };
+            let mut builder = builder.port(smtp.port);
+            if !smtp.username.is_empty() {
+                builder = builder.credentials(Credentials::new(
+                    smtp.username.clone(),
+                    smtp.password().to_owned(),
+                ));
+            }
+            Arc::new(SmtpMailer {
+                transport: builder.build(),
+                from,
+            })
+        }
+        MailBackend::File => Arc::new(FileMailer {
+            dir: settings.file_dir.clone(),
+            from,
+        }),
+        MailBackend::Log => Arc::new(LogMailer),
+    })
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email
+                .to
+                .parse()
+                .with_context(|| format!("invalid recipient `{}`", email.to))?)
+            .subject(email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body)
+            .context("could not build the email")?;
+        self.transport
+            .send(message)
+            .await
+            .context("could not send the email")?;
+        Ok(())
+    }
+}
+
+/// Writes every email to its own file, headers first, so that they can be read back in tests.
+pub struct FileMailer {
+    dir: PathBuf,
+    from: Mailbox,
+}
+
+impl FileMailer {
+    pub fn new(dir: PathBuf, from: Mailbox) -> Self {
+        Self { dir, from }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<()> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("could not create {}", self.dir.display()))?;
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+            Uuid::new_v4()
+        ));
+        let contents = format!(
+            "From: {}\nTo: {}\nSubject: {}\n\n{}\n",
+            self.from, email.to, email.subject, email.body
+        );
+        tokio::fs::write(&path, contents)
+            .await
+            .with_context(|| format!("could not write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Only logs emails. They carry working links, so never use this in production.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<()> {
+        tracing::info!(to = %email.to, subject = %email.subject, "email:\n{}", email.body);
+        Ok(())
+    }
+}
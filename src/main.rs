@@ -1,39 +1,77 @@
 use home_projects::{
-    database::{get_db_connection},
+    database::{get_db_pool, prepare_schema, DatabaseHealth},
     settings::Settings,
-    server::serve,
+    server::{serve, serve_while_starting, Server},
     telemetry::{get_subscriber, init_subscriber},
 };
+use anyhow::Context;
+use entity::user::{self, Role};
+use sea_orm::{ActiveModelTrait, ActiveValue, DatabaseConnection};
 use std::env;
-use home_projects::database::create_tables;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().ok();
+    if env::args().any(|arg| arg == "--print-config") {
+        return print_config();
+    }
+
     let subscriber = get_subscriber("home_project".into(), "info".into(), std::io::stdout);
     init_subscriber(subscriber);
-    dotenv::dotenv().ok();
     env::set_var("RUST_LOG", "debug");
     let settings = Settings::new()?;
-    let db = get_db_connection(&settings).await?;
-    //create_tables(&db).await?;
+    let db_health = DatabaseHealth::default();
+    let make_admin_of = flag_value("--make-admin");
+    let starting = async {
+        let pool = get_db_pool(&settings, &db_health).await?;
+        prepare_schema(&pool.connection(), &settings.database).await?;
+        Ok(pool)
+    };
+    // The health checks answer from the start, so that a database that is slow to come up doesn't
+    // look like a dead process.
+    let pool = match make_admin_of {
+        Some(_) => starting.await?,
+        None => serve_while_starting(&settings, db_health.clone(), starting).await?,
+    };
+    let db = pool.connection();
+    let result = match make_admin_of {
+        Some(username) => make_admin(&db, &username).await,
+        None => serve(Server::with_health(settings, db, db_health)?).await,
+    };
+    // Only close once the server is done with its connections.
+    pool.close().await;
+    tracing::info!("database pool closed");
+    result
+}
 
+/// The value after `flag` on the command line, e.g. `--make-admin alice`.
+fn flag_value(flag: &str) -> Option<String> {
+    let mut args = env::args().skip_while(|arg| arg != flag);
+    args.next()?;
+    args.next()
+}
+
+/// Gives `username` the admin role, which is how the first admin comes about.
+async fn make_admin(db: &DatabaseConnection, username: &str) -> anyhow::Result<()> {
+    let account = user::Entity::find_by_name(username)
+        .one(db)
+        .await?
+        .with_context(|| format!("there is no user called `{}`", username))?;
+    user::ActiveModel {
+        user_id: ActiveValue::Unchanged(account.user_id),
+        role: ActiveValue::Set(Role::Admin),
+        ..ActiveModelTrait::default()
+    }
+    .update(db)
+    .await?;
+    tracing::info!(%username, "made admin");
+    Ok(())
+}
 
-    //let project = project::ActiveModel {
-    //    title: Set("Project Title".to_owned()),
-    //    text: Set("Project Description".to_owned()),
-    //    ..Default::default()
-    //}
-    //.insert(&db)
-    //.await?;
-//
-    //task::ActiveModel {
-    //    title: Set("Task Title".to_owned()),
-    //    text: Set("Task Description".to_owned()),
-    //    project_id: Set(Some(project.id)),
-    //    ..Default::default()
-    //}
-    //.insert(&db)
-    //.await?;
-    serve(settings, db).await?;
+/// Prints the merged configuration with its secrets masked, then any problems with it.
+fn print_config() -> anyhow::Result<()> {
+    let settings = Settings::load()?;
+    println!("{}", serde_json::to_string_pretty(&settings)?);
+    settings.validate()?;
     Ok(())
 }
@@ -0,0 +1,247 @@
+//! Password resets, email verification and email changes, all of which work through links sent
+//! by email.
+use super::users::current_account;
+use crate::{
+    auth::{access_token, one_time, password::hash_password, refresh, AuthUser},
+    error::HttpError,
+    mail::Email,
+    server::Server,
+    utils::ValidatedJson,
+    Result,
+};
+use axum::{extract::Extension, http::StatusCode, routing::post, Json, Router};
+use chrono::Utc;
+use entity::{
+    one_time_token::{PURPOSE_EMAIL_CHANGE, PURPOSE_EMAIL_VERIFICATION, PURPOSE_PASSWORD_RESET},
+    user,
+};
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait,
+    QueryFilter, TransactionTrait,
+};
+use serde::Deserialize;
+use tracing::Instrument;
+use url::Url;
+use validator::Validate;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/user/password-reset", post(request_password_reset))
+        .route("/user/password-reset/confirm", post(confirm_password_reset))
+        .route("/user/me/verify-email", post(resend_verification))
+        .route("/user/verify-email/confirm", post(confirm_verification))
+        .route("/user/email-change/confirm", post(confirm_email_change))
+}
+
+/// A link to `path` on the frontend, carrying `token`.
+pub(super) fn link(ctx: &Server, path: &str, token: &str) -> Result<String> {
+    // With a trailing slash, so that joining keeps any path the frontend is served under.
+    let base = format!("{}/", ctx.settings.mail.public_url.trim_end_matches('/'));
+    let mut url = Url::parse(&base)
+        .and_then(|base| base.join(path))
+        .map_err(anyhow::Error::new)?;
+    url.query_pairs_mut().append_pair("token", token);
+    Ok(url.into())
+}
+
+/// Sends `user` a link to confirm their current email address.
+pub(super) async fn send_verification_email(ctx: &Server, user: &user::Model) -> Result<()> {
+    let ttl = ctx.settings.auth.email_verification_ttl_secs;
+    let token = one_time::issue(&ctx.db, user, PURPOSE_EMAIL_VERIFICATION, ttl).await?;
+    let link = link(ctx, "verify-email", &token)?;
+    ctx.mailer
+        .send(Email {
+            to: user.email.clone(),
+            subject: "Confirm your email address".to_owned(),
+            body: format!(
+                "Hi {},\n\nPlease confirm your email address by opening this link:\n\n{}\n\n\
+                 The link is valid for {} hours.",
+                user.username,
+                link,
+                ttl / 3600
+            ),
+        })
+        .await
+}
+
+/// Sends a link to `email` that makes it the address of `user`. Until it is opened, the account
+/// keeps its current address.
+pub(super) async fn send_email_change(ctx: &Server, user: &user::Model, email: &str) -> Result<()> {
+    let ttl = ctx.settings.auth.email_verification_ttl_secs;
+    let token = one_time::issue_to(&ctx.db, user, PURPOSE_EMAIL_CHANGE, email, ttl).await?;
+    let link = link(ctx, "confirm-email-change", &token)?;
+    ctx.mailer
+        .send(Email {
+            to: email.to_owned(),
+            subject: "Confirm your new email address".to_owned(),
+            body: format!(
+                "Hi {},\n\nPlease confirm that this is your new email address by opening this \
+                 link:\n\n{}\n\nThe link is valid for {} hours. Until then, your account keeps \
+                 its current address.",
+                user.username,
+                link,
+                ttl / 3600
+            ),
+        })
+        .await
+}
+
+async fn send_password_reset(ctx: &Server, email: &str) -> Result<()> {
+    let user = match user::Entity::find()
+        .filter(user::Column::Email.eq(email))
+        .one(&ctx.db)
+        .await?
+    {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+
+    let ttl = ctx.settings.auth.password_reset_ttl_secs;
+    let token = one_time::issue(&ctx.db, &user, PURPOSE_PASSWORD_RESET, ttl).await?;
+    let link = link(ctx, "reset-password", &token)?;
+    ctx.mailer
+        .send(Email {
+            to: user.email.clone(),
+            subject: "Reset your password".to_owned(),
+            body: format!(
+                "Hi {},\n\nSomeone asked to reset the password of your account. If that was you, \
+                 choose a new password here:\n\n{}\n\nThe link is valid for {} minutes. If you \
+                 didn't ask for this, you can ignore this email.",
+                user.username,
+                link,
+                ttl / 60
+            ),
+        })
+        .await
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct PasswordResetRequest {
+    #[validate(email(message = "Must be a valid email address"))]
+    email: String,
+}
+
+/// Emails a reset link if an account has the given address.
+///
+/// The answer is the same either way, and the work happens after responding, so that neither the
+/// answer nor its timing tells whether an account exists. That work is dropped when the server
+/// shuts down, so that it doesn't outlive the database connection.
+async fn request_password_reset(
+    Extension(ctx): Extension<Server>,
+    ValidatedJson(req): ValidatedJson<PasswordResetRequest>,
+) -> Result<StatusCode> {
+    let shutdown = ctx.shutdown.clone();
+    tokio::spawn(
+        async move {
+            tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => {
+                    tracing::warn!("shutting down, a password reset email was not sent");
+                }
+                sent = send_password_reset(&ctx, &req.email) => {
+                    if let Err(e) = sent {
+                        tracing::error!("could not send a password reset email: {}", e);
+                    }
+                }
+            }
+        }
+        .in_current_span(),
+    );
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConfirmPasswordResetRequest {
+    token: String,
+    new_password: String,
+}
+
+/// Sets a new password with a token from a reset email, and ends every session of the account and
+/// revokes its personal access tokens.
+async fn confirm_password_reset(
+    Extension(ctx): Extension<Server>,
+    Json(req): Json<ConfirmPasswordResetRequest>,
+) -> Result<StatusCode> {
+    // Before using up the token, so that a rejected password can be fixed and sent again.
+    ctx.password_policy.validate("new_password", &req.new_password)?;
+    let password_hash = hash_password(req.new_password).await?;
+
+    let txn = ctx.db.begin().await?;
+    let user = one_time::consume(&txn, &req.token, PURPOSE_PASSWORD_RESET).await?;
+    let mut model = user::ActiveModel {
+        user_id: ActiveValue::Unchanged(user.user_id),
+        password_hash: ActiveValue::Set(password_hash),
+        ..ActiveModelTrait::default()
+    };
+    // Getting the email through proves the address as well.
+    if user.email_verified_at.is_none() {
+        model.email_verified_at = ActiveValue::Set(Some(DateTimeWithTimeZone::from(Utc::now())));
+    }
+    model.update(&txn).await?;
+    refresh::revoke_all_for_user(&txn, user.user_id).await?;
+    access_token::revoke_all_for_user(&txn, user.user_id).await?;
+    txn.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Sends the caller a new verification link.
+async fn resend_verification(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+) -> Result<StatusCode> {
+    let account = current_account(&ctx, &user).await?;
+    if account.email_verified_at.is_some() {
+        return Err(HttpError::conflict(
+            Some("already_verified".to_owned()),
+            Some("The email address is already verified.".to_owned()),
+        )
+        .into());
+    }
+    send_verification_email(&ctx, &account).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConfirmVerificationRequest {
+    token: String,
+}
+
+async fn confirm_verification(
+    Extension(ctx): Extension<Server>,
+    Json(req): Json<ConfirmVerificationRequest>,
+) -> Result<StatusCode> {
+    let txn = ctx.db.begin().await?;
+    let user = one_time::consume(&txn, &req.token, PURPOSE_EMAIL_VERIFICATION).await?;
+    user::ActiveModel {
+        user_id: ActiveValue::Unchanged(user.user_id),
+        email_verified_at: ActiveValue::Set(Some(DateTimeWithTimeZone::from(Utc::now()))),
+        ..ActiveModelTrait::default()
+    }
+    .update(&txn)
+    .await?;
+    txn.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Switches the account over to the address a change link was sent to, which opening it proves.
+async fn confirm_email_change(
+    Extension(ctx): Extension<Server>,
+    Json(req): Json<ConfirmVerificationRequest>,
+) -> Result<StatusCode> {
+    let txn = ctx.db.begin().await?;
+    let (user, email) =
+        one_time::consume_for_address(&txn, &req.token, PURPOSE_EMAIL_CHANGE).await?;
+    user::ActiveModel {
+        user_id: ActiveValue::Unchanged(user.user_id),
+        email: ActiveValue::Set(email),
+        email_verified_at: ActiveValue::Set(Some(DateTimeWithTimeZone::from(Utc::now()))),
+        ..ActiveModelTrait::default()
+    }
+    .update(&txn)
+    .await?;
+    txn.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
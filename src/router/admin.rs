@@ -0,0 +1,188 @@
+//! Managing other users, for admins only.
+use super::users::USER_SORTING;
+use crate::{
+    auth::{
+        access_token::Scope,
+        permission::{require, Permission},
+        refresh, AuthUser,
+    },
+    error::HttpError,
+    server::Server,
+    utils::{paginate, Page, Pagination},
+    Result,
+};
+use axum::{
+    extract::{Extension, Path, Query},
+    routing::{get, post, put},
+    Json, Router,
+};
+use chrono::Utc;
+use entity::user::{self, Role};
+use sea_orm::{
+    prelude::{DateTimeWithTimeZone, Uuid},
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/admin/users", get(list_users))
+        .route("/admin/users/:id/disable", post(disable_user))
+        .route("/admin/users/:id/enable", post(enable_user))
+        .route("/admin/users/:id/role", put(set_role))
+        .route_layer(require(Permission::ManageUsers, Scope::Admin))
+}
+
+/// What admins see of a user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AdminUserResponse {
+    pub user_id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub two_factor_enabled: bool,
+    pub role: Role,
+    pub disabled_at: Option<DateTimeWithTimeZone>,
+    pub created_at: DateTimeWithTimeZone,
+    pub updated_at: DateTimeWithTimeZone,
+}
+
+impl From<user::Model> for AdminUserResponse {
+    fn from(user: user::Model) -> Self {
+        Self {
+            user_id: user.user_id,
+            username: user.username,
+            email: user.email,
+            email_verified: user.email_verified_at.is_some(),
+            two_factor_enabled: user.totp_enabled_at.is_some(),
+            role: user.role,
+            disabled_at: user.disabled_at,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct AdminUsersQuery {
+    pub username_contains: Option<String>,
+    pub role: Option<Role>,
+    pub disabled: Option<bool>,
+}
+
+async fn list_users(
+    Extension(ctx): Extension<Server>,
+    pagination: Pagination,
+    Query(query): Query<AdminUsersQuery>,
+) -> Result<Json<Page<AdminUserResponse>>> {
+    let mut select = user::Entity::find();
+    if let Some(username) = &query.username_contains {
+        select = select.filter(user::Column::Username.contains(username));
+    }
+    if let Some(role) = query.role {
+        select = select.filter(user::Column::Role.eq(role));
+    }
+    match query.disabled {
+        Some(true) => select = select.filter(user::Column::DisabledAt.is_not_null()),
+        Some(false) => select = select.filter(user::Column::DisabledAt.is_null()),
+        None => {}
+    }
+
+    Ok(Json(
+        paginate(select, &pagination, &USER_SORTING, &ctx.db)
+            .await?
+            .map(AdminUserResponse::from),
+    ))
+}
+
+/// Admins can't disable or demote themselves, which could leave nobody to undo it.
+fn not_yourself(admin: &AuthUser, id: Uuid) -> Result<()> {
+    if admin.user_id == id {
+        return Err(HttpError::conflict(
+            Some("own_account".to_owned()),
+            Some("Admins can't disable or change the role of their own account.".to_owned()),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+async fn find_user(ctx: &Server, id: Uuid) -> Result<user::Model> {
+    Ok(user::Entity::find_by_id(id)
+        .one(&ctx.db)
+        .await?
+        .ok_or_else(|| HttpError::not_found(None, None))?)
+}
+
+/// Stops the account from logging in and ends its sessions. Access tokens already handed out are
+/// refused from then on, until the account is enabled again.
+#[tracing::instrument(name = "Disabling a user", skip(ctx, admin), fields(admin_id = %admin.user_id))]
+async fn disable_user(
+    Extension(ctx): Extension<Server>,
+    admin: AuthUser,
+    Path(id): Path<Uuid>,
+) -> Result<Json<AdminUserResponse>> {
+    not_yourself(&admin, id)?;
+    let account = find_user(&ctx, id).await?;
+    if account.disabled_at.is_some() {
+        return Ok(Json(account.into()));
+    }
+
+    let txn = ctx.db.begin().await?;
+    let account = user::ActiveModel {
+        user_id: ActiveValue::Unchanged(id),
+        disabled_at: ActiveValue::Set(Some(Utc::now().into())),
+        ..ActiveModelTrait::default()
+    }
+    .update(&txn)
+    .await?;
+    refresh::revoke_all_for_user(&txn, id).await?;
+    txn.commit().await?;
+
+    Ok(Json(account.into()))
+}
+
+#[tracing::instrument(name = "Enabling a user", skip(ctx, admin), fields(admin_id = %admin.user_id))]
+async fn enable_user(
+    Extension(ctx): Extension<Server>,
+    admin: AuthUser,
+    Path(id): Path<Uuid>,
+) -> Result<Json<AdminUserResponse>> {
+    find_user(&ctx, id).await?;
+    let account = user::ActiveModel {
+        user_id: ActiveValue::Unchanged(id),
+        disabled_at: ActiveValue::Set(None),
+        ..ActiveModelTrait::default()
+    }
+    .update(&ctx.db)
+    .await?;
+
+    Ok(Json(account.into()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetRoleRequest {
+    role: Role,
+}
+
+/// Promotes or demotes a user.
+#[tracing::instrument(name = "Changing the role of a user", skip(ctx, admin), fields(admin_id = %admin.user_id))]
+async fn set_role(
+    Extension(ctx): Extension<Server>,
+    admin: AuthUser,
+    Path(id): Path<Uuid>,
+    Json(req): Json<SetRoleRequest>,
+) -> Result<Json<AdminUserResponse>> {
+    not_yourself(&admin, id)?;
+    find_user(&ctx, id).await?;
+    let account = user::ActiveModel {
+        user_id: ActiveValue::Unchanged(id),
+        role: ActiveValue::Set(req.role),
+        ..ActiveModelTrait::default()
+    }
+    .update(&ctx.db)
+    .await?;
+
+    Ok(Json(account.into()))
+}
@@ -0,0 +1,209 @@
+use super::users::{current_account, PrivateUserResponse};
+use crate::{auth::AuthUser, error::HttpError, server::Server, Result};
+use axum::{
+    extract::{BodyStream, Extension, Path},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    routing::{get, put},
+    Json, Router,
+};
+use bytes::{Bytes, BytesMut};
+use entity::user;
+use rand::Rng;
+use sea_orm::{ActiveModelTrait, ActiveValue};
+use tokio_stream::StreamExt;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/user/me/avatar", put(upload_avatar).delete(delete_avatar))
+        .route("/avatars/:name", get(get_avatar))
+}
+
+/// Avatars are stored under `avatars/` in the blob store, and served from `/avatars/`.
+const AVATAR_PREFIX: &str = "avatars/";
+
+/// The image formats accepted as avatars. The type is taken from the leading bytes of the upload
+/// rather than from `Content-Type`, which is up to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ImageKind {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+}
+
+impl ImageKind {
+    fn sniff(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(Self::Jpeg)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(Self::Gif)
+        } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(Self::Webp)
+        } else {
+            None
+        }
+    }
+
+    fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "png" => Some(Self::Png),
+            "jpg" => Some(Self::Jpeg),
+            "gif" => Some(Self::Gif),
+            "webp" => Some(Self::Webp),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::Gif => "gif",
+            Self::Webp => "webp",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Gif => "image/gif",
+            Self::Webp => "image/webp",
+        }
+    }
+}
+
+/// The blob key behind a user's `image`, if it is an uploaded avatar rather than an outside URL.
+fn avatar_key(image: &str) -> Option<String> {
+    image
+        .strip_prefix('/')
+        .filter(|key| key.starts_with(AVATAR_PREFIX))
+        .map(str::to_owned)
+}
+
+/// Removes the uploaded avatar behind `image`, if there is one. The account no longer points to
+/// it, so a failure only leaves a stray file behind and is logged rather than returned.
+pub(super) async fn remove_avatar(ctx: &Server, image: Option<&str>) {
+    if let Some(key) = image.and_then(avatar_key) {
+        if let Err(e) = ctx.blobs.delete(&key).await {
+            tracing::warn!("could not remove avatar {}: {}", key, e);
+        }
+    }
+}
+
+/// Reads the whole body, giving up as soon as it grows past `limit` bytes.
+async fn read_limited(mut body: BodyStream, limit: usize) -> Result<Bytes> {
+    let too_large = || {
+        HttpError::payload_too_large(
+            None,
+            Some(format!("Avatars can be at most {} bytes.", limit)),
+        )
+    };
+
+    let mut data = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| {
+            HttpError::bad_request(Some("invalid_body".to_owned()), Some(e.to_string()))
+        })?;
+        if data.len() + chunk.len() > limit {
+            return Err(too_large().into());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data.freeze())
+}
+
+/// Replaces the caller's avatar with the image in the request body.
+#[tracing::instrument(name = "Uploading an avatar", skip(ctx, user, body), fields(user_id = %user.user_id))]
+async fn upload_avatar(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    body: BodyStream,
+) -> Result<Json<PrivateUserResponse>> {
+    let data = read_limited(body, ctx.settings.storage.max_avatar_bytes).await?;
+    let kind = ImageKind::sniff(&data).ok_or_else(|| {
+        HttpError::unsupported_media_type(
+            None,
+            Some("Avatars have to be PNG, JPEG, GIF or WebP images.".to_owned()),
+        )
+    })?;
+    let account = current_account(&ctx, &user).await?;
+
+    // A new name for every upload, so that caches never serve the previous image.
+    let key = format!(
+        "{}{}-{:016x}.{}",
+        AVATAR_PREFIX,
+        user.user_id,
+        rand::thread_rng().gen::<u64>(),
+        kind.extension()
+    );
+    ctx.blobs.put(&key, data).await?;
+
+    let updated = user::ActiveModel {
+        user_id: ActiveValue::Unchanged(user.user_id),
+        image: ActiveValue::Set(Some(format!("/{}", key))),
+        ..ActiveModelTrait::default()
+    }
+    .update(&ctx.db)
+    .await;
+    let updated = match updated {
+        Ok(updated) => updated,
+        Err(e) => {
+            remove_avatar(&ctx, Some(&format!("/{}", key))).await;
+            return Err(e.into());
+        }
+    };
+    remove_avatar(&ctx, account.image.as_deref()).await;
+
+    Ok(Json(updated.into()))
+}
+
+/// Clears the caller's avatar.
+async fn delete_avatar(Extension(ctx): Extension<Server>, user: AuthUser) -> Result<StatusCode> {
+    let account = current_account(&ctx, &user).await?;
+    user::ActiveModel {
+        user_id: ActiveValue::Unchanged(user.user_id),
+        image: ActiveValue::Set(None),
+        ..ActiveModelTrait::default()
+    }
+    .update(&ctx.db)
+    .await?;
+    remove_avatar(&ctx, account.image.as_deref()).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Serves an uploaded avatar. Avatars are public, like the rest of a user's public profile.
+async fn get_avatar(
+    Extension(ctx): Extension<Server>,
+    Path(name): Path<String>,
+) -> Result<(HeaderMap, Bytes)> {
+    let not_found = || HttpError::not_found(None, None);
+    let kind = name
+        .rsplit_once('.')
+        .and_then(|(_, extension)| ImageKind::from_extension(extension))
+        .ok_or_else(not_found)?;
+    let data = ctx
+        .blobs
+        .get(&format!("{}{}", AVATAR_PREFIX, name))
+        .await?
+        .ok_or_else(not_found)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(kind.content_type()),
+    );
+    // Every upload gets a new name, so a name always refers to the same image.
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=31536000, immutable"),
+    );
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    Ok((headers, data))
+}
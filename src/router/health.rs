@@ -0,0 +1,186 @@
+use crate::{
+    database::{migrations, DatabaseHealth, DatabaseHealthSnapshot, DatabaseStatus},
+    error::HttpError,
+    server::Server,
+};
+use axum::{
+    extract::Extension,
+    handler::Handler,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use sea_orm::{ConnectionTrait, Statement};
+use serde::Serialize;
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+/// Checks that take longer than this count as failed, so a hung database can't hang the probe.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+}
+
+/// What is served while the database is still being connected to and migrated, so that probes can
+/// tell a slow start from a dead process. Needs the [`DatabaseHealth`] as an extension, and answers
+/// everything but the health checks with a 503.
+pub fn starting_router() -> Router {
+    Router::new()
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready_while_starting))
+        .fallback(starting.into_service())
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Failed,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HealthResponse {
+    pub status: CheckStatus,
+    pub checks: Vec<CheckResult>,
+    /// What the server has seen of the database over time, beyond the check just made.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<DatabaseHealthSnapshot>,
+}
+
+impl IntoResponse for HealthResponse {
+    fn into_response(self) -> Response {
+        let status = match self.status {
+            CheckStatus::Ok => StatusCode::OK,
+            CheckStatus::Failed => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+/// Runs `check` with a timeout and times it.
+async fn run_check<F>(name: &'static str, check: F) -> CheckResult
+where
+    F: Future<Output = Result<(), String>>,
+{
+    let start = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err(format!("timed out after {:?}", CHECK_TIMEOUT)));
+
+    CheckResult {
+        name,
+        status: if result.is_ok() {
+            CheckStatus::Ok
+        } else {
+            CheckStatus::Failed
+        },
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+        error: result.err(),
+    }
+}
+
+/// The process is up and able to answer requests. Deliberately checks nothing else, so that a
+/// database outage doesn't get the container restarted.
+async fn live() -> HealthResponse {
+    HealthResponse {
+        status: CheckStatus::Ok,
+        checks: Vec::new(),
+        database: None,
+    }
+}
+
+/// The service can do useful work: the database answers and its schema is up to date.
+async fn ready(Extension(ctx): Extension<Server>) -> HealthResponse {
+    let database = run_check("database", async {
+        let backend = ctx.db.get_database_backend();
+        ctx.db
+            .execute(Statement::from_string(backend, "SELECT 1".to_owned()))
+            .await
+            .map(|_| ())
+            .map_err(|e| {
+                tracing::warn!("readiness check could not reach the database: {}", e);
+                DatabaseHealth::UNREACHABLE.to_owned()
+            })
+    })
+    .await;
+
+    match &database.status {
+        CheckStatus::Ok => ctx.db_health.set_connected(),
+        CheckStatus::Failed => ctx.db_health.set_failed(DatabaseHealth::UNREACHABLE),
+    }
+
+    let migrations = run_check("migrations", async {
+        let pending = migrations::pending(&ctx.db).await.map_err(|e| {
+            tracing::warn!("readiness check could not read the applied migrations: {}", e);
+            "could not read the applied migrations".to_owned()
+        })?;
+        match pending.iter().map(|m| m.version).collect::<Vec<_>>() {
+            versions if versions.is_empty() => Ok(()),
+            versions => Err(format!("pending migrations: {:?}", versions)),
+        }
+    })
+    .await;
+
+    // Fail readiness while draining so that load balancers stop sending new requests.
+    let accepting = run_check("accepting_requests", async {
+        if ctx.shutdown.is_cancelled() {
+            Err("the server is shutting down".to_owned())
+        } else {
+            Ok(())
+        }
+    })
+    .await;
+
+    let checks = vec![database, migrations, accepting];
+    HealthResponse {
+        status: if checks.iter().all(|c| c.status == CheckStatus::Ok) {
+            CheckStatus::Ok
+        } else {
+            CheckStatus::Failed
+        },
+        checks,
+        database: Some(ctx.db_health.snapshot()),
+    }
+}
+
+/// Never ready, as there is no database to use yet.
+async fn ready_while_starting(Extension(health): Extension<DatabaseHealth>) -> HealthResponse {
+    let snapshot = health.snapshot();
+    let error = match (&snapshot.status, &snapshot.last_error) {
+        (_, Some(error)) => (*error).to_owned(),
+        (DatabaseStatus::Connected, None) => "still preparing the database".to_owned(),
+        (_, None) => "still connecting to the database".to_owned(),
+    };
+    HealthResponse {
+        status: CheckStatus::Failed,
+        checks: vec![CheckResult {
+            name: "database",
+            status: CheckStatus::Failed,
+            latency_ms: 0.0,
+            error: Some(error),
+        }],
+        database: Some(snapshot),
+    }
+}
+
+async fn starting() -> HttpError {
+    HttpError::service_unavailable(
+        Some("starting".to_owned()),
+        Some("The server is still starting up.".to_owned()),
+    )
+}
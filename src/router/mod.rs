@@ -1,12 +1,63 @@
+use crate::Result;
 use axum::Router;
+use sea_orm::{
+    ActiveModelBehavior, ActiveModelTrait, ConnectionTrait, EntityTrait, IntoActiveModel, Iterable,
+    ModelTrait, PrimaryKeyToColumn,
+};
+mod account;
+mod admin;
+mod avatars;
+mod health;
 mod projects;
+mod tasks;
+mod tokens;
+mod two_factor;
 mod users;
+mod workspaces;
 
-
+pub use health::starting_router;
 
 pub fn api_router() -> Router {
     // This is the order that the modules were authored in.
     projects::router()
        .merge(users::router())
+       .merge(tasks::router())
+       .merge(health::router())
+       .merge(avatars::router())
+       .merge(account::router())
+       .merge(two_factor::router())
+       .merge(tokens::router())
+       .merge(admin::router())
+       .merge(workspaces::router())
 }
 
+/// A request body that can be written onto an existing row, with [`apply_update`].
+pub trait ModelIn {
+    type ActiveModel;
+
+    fn update_model(self, model: &mut Self::ActiveModel);
+}
+
+/// Writes `data` onto `row`, which the caller has already loaded and checked access to, and
+/// returns the updated row. Only the columns that `data` sets are written.
+pub(crate) async fn apply_update<A, T, C>(
+    db: &C,
+    row: &<A::Entity as EntityTrait>::Model,
+    data: T,
+) -> Result<<A::Entity as EntityTrait>::Model>
+where
+    A: ActiveModelTrait + ActiveModelBehavior + Send,
+    <A::Entity as EntityTrait>::Model: IntoActiveModel<A>,
+    T: ModelIn<ActiveModel = A>,
+    C: ConnectionTrait,
+{
+    // Deliberately not `Default::default()`, which would stamp a fresh id and `created_at`, but a
+    // model with nothing set apart from the key.
+    let mut model = <A as ActiveModelTrait>::default();
+    for key in <A::Entity as EntityTrait>::PrimaryKey::iter() {
+        let column = key.into_column();
+        model.set(column, row.get(column));
+    }
+    data.update_model(&mut model);
+    Ok(model.update(db).await?)
+}
@@ -1,81 +1,237 @@
-use crate::{error::HttpError, server::Server, Result};
+use super::{
+    workspaces::{editable_membership, read_only},
+    apply_update, ModelIn,
+};
+use crate::{
+    auth::{
+        access_token::Scope,
+        permission::{require, Permission},
+        AuthUser,
+    },
+    error::HttpError,
+    server::Server,
+    utils::{paginate, set_if_some, to_utc, Page, Pagination, Sorting, ValidatedJson},
+    Result,
+};
 use axum::{
     extract::Extension,
-    extract::Path,
+    extract::{Path, Query},
     http::StatusCode,
-    routing::{get, post},
+    routing::{get, post, put},
     Json, Router,
 };
-use entity::{project, task};
-use sea_orm::{prelude::Uuid, ActiveModelTrait, ActiveValue, EntityTrait};
+use entity::{
+    project, task,
+    workspace_member::{self, WorkspaceRole},
+};
+use sea_orm::{
+    prelude::{DateTimeWithTimeZone, Uuid},
+    sea_query::{Query as SubQuery, SelectStatement},
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, EntityTrait,
+    ModelTrait, QueryFilter, QueryOrder, Select, TransactionTrait,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use validator::Validate;
 
 pub fn router() -> Router {
     Router::new()
-        .route("/project/:id", get(get_project))
-        .route("/projects/", get(get_projects))
-        .route("/projects/", post(create_project))
+        .route(
+            "/project/:id",
+            get(get_project).route_layer(require(Permission::ViewProjects, Scope::ReadProjects)),
+        )
+        .route(
+            "/project/:id",
+            put(update_project)
+                .patch(patch_project)
+                .delete(delete_project)
+                .route_layer(require(Permission::EditProjects, Scope::Admin)),
+        )
+        .route(
+            "/projects/",
+            get(get_projects).route_layer(require(Permission::ViewProjects, Scope::ReadProjects)),
+        )
+        .route(
+            "/projects/",
+            post(create_project).route_layer(require(Permission::EditProjects, Scope::Admin)),
+        )
+}
+
+/// The workspaces `user` is a member of, or only those they may edit in with `editing`.
+fn member_workspaces(user: &AuthUser, editing: bool) -> SelectStatement {
+    let mut query = SubQuery::select()
+        .column(workspace_member::Column::WorkspaceId)
+        .from(workspace_member::Entity)
+        .and_where(workspace_member::Column::UserId.eq(user.user_id))
+        .to_owned();
+    if editing {
+        query.and_where(workspace_member::Column::Role.ne(WorkspaceRole::Viewer));
+    }
+    query
+}
+
+/// The projects `user` is allowed to see, which are those of the workspaces they are a member of.
+/// Every project query should start from here, so that projects of other workspaces are
+/// indistinguishable from ones that don't exist.
+pub(super) fn visible_projects(user: &AuthUser) -> Select<project::Entity> {
+    project::Entity::find()
+        .filter(project::Column::WorkspaceId.in_subquery(member_workspaces(user, false)))
+}
+
+/// Looks up a single project the caller can see, or 404s.
+pub(super) async fn find_visible<C: ConnectionTrait>(
+    db: &C,
+    user: &AuthUser,
+    id: Uuid,
+) -> Result<project::Model> {
+    Ok(visible_projects(user)
+        .filter(project::Column::Id.eq(id))
+        .one(db)
+        .await?
+        .ok_or_else(|| HttpError::not_found(None, None))?)
+}
+
+/// Looks up a single project the caller may change. Projects they can only view are refused with
+/// a 403, the ones they can't see at all 404.
+pub(super) async fn find_editable<C: ConnectionTrait>(
+    db: &C,
+    user: &AuthUser,
+    id: Uuid,
+) -> Result<project::Model> {
+    let editable = project::Entity::find()
+        .filter(project::Column::WorkspaceId.in_subquery(member_workspaces(user, true)))
+        .filter(project::Column::Id.eq(id))
+        .one(db)
+        .await?;
+    match editable {
+        Some(project) => Ok(project),
+        None => {
+            find_visible(db, user, id).await?;
+            Err(read_only().into())
+        }
+    }
 }
 
 #[derive(Serialize, Debug)]
 #[serde(default)]
 pub struct GetProjectResponse {
     pub project: project::Model,
-    pub tasks: Vec<task::Model>,
+    /// Left out of list responses unless they were asked for with `?include=tasks`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tasks: Option<Vec<task::Model>>,
 }
 
 async fn get_project(
-    ref ctx: Extension<Server>,
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
     Path(id): Path<Uuid>,
 ) -> Result<Json<GetProjectResponse>> {
-    let (project, task) = project::Entity::find_by_id(id)
-        .find_also_related(task::Entity)
-        .one(&ctx.db)
-        .await?
-        .ok_or_else(|| HttpError::not_found(None, None))?;
+    let project = find_visible(&ctx.db, &user, id).await?;
+    let tasks = project
+        .find_related(task::Entity)
+        .order_by_asc(task::Column::CreatedAt)
+        .all(&ctx.db)
+        .await?;
 
-    Ok(Json(if task.is_some() {
-        GetProjectResponse {
-            project: project,
-            tasks: vec![task.unwrap()],
-        }
-    } else {
-        GetProjectResponse {
-            project: project,
-            tasks: Vec::new(),
-        }
+    Ok(Json(GetProjectResponse {
+        project,
+        tasks: Some(tasks),
     }))
 }
 
-async fn get_projects(ref ctx: Extension<Server>) -> Result<Json<Vec<GetProjectResponse>>> {
-    let res = project::Entity::find()
-        .find_also_related(task::Entity)
+const PROJECT_SORTING: Sorting<project::Entity> = Sorting {
+    columns: &[
+        ("created_at", project::Column::CreatedAt),
+        ("updated_at", project::Column::UpdatedAt),
+        ("title", project::Column::Title),
+    ],
+    id: project::Column::Id,
+};
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct GetProjectsQuery {
+    /// A comma separated list of relations to embed in each project. Only `tasks` is supported.
+    pub include: String,
+    pub title_contains: Option<String>,
+    pub created_after: Option<DateTimeWithTimeZone>,
+    pub created_before: Option<DateTimeWithTimeZone>,
+    /// Only the projects created by this user, e.g. one of the other members of a workspace.
+    pub owner: Option<Uuid>,
+    /// Only the projects of this workspace, instead of those of every workspace of the caller.
+    pub workspace: Option<Uuid>,
+}
+
+impl GetProjectsQuery {
+    fn includes(&self, relation: &str) -> bool {
+        self.include.split(',').any(|r| r.trim() == relation)
+    }
+}
+
+/// Loads the tasks of all `projects` in one query, keyed by project id.
+async fn tasks_by_project(
+    ctx: &Server,
+    projects: &[project::Model],
+) -> Result<HashMap<Uuid, Vec<task::Model>>> {
+    let mut by_project: HashMap<Uuid, Vec<task::Model>> =
+        projects.iter().map(|p| (p.id, Vec::new())).collect();
+    if projects.is_empty() {
+        return Ok(by_project);
+    }
+
+    let tasks = task::Entity::find()
+        .filter(task::Column::ProjectId.is_in(projects.iter().map(|p| p.id)))
+        .order_by_asc(task::Column::CreatedAt)
         .all(&ctx.db)
-        .await?
-        .into_iter()
-        .map(|(project, tasks)| {
-            if tasks.is_some() {
-                GetProjectResponse {
-                    project: project,
-                    tasks: vec![tasks.unwrap()],
-                }
-            } else {
-                GetProjectResponse {
-                    project: project,
-                    tasks: Vec::new(),
-                }
-            }
-        })
-        .collect();
+        .await?;
+    for task in tasks {
+        if let Some(list) = task.project_id.and_then(|id| by_project.get_mut(&id)) {
+            list.push(task);
+        }
+    }
 
-    Ok(Json(res))
+    Ok(by_project)
 }
 
-pub trait ModelIn {
-    type ActiveModel;
+async fn get_projects(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    pagination: Pagination,
+    Query(query): Query<GetProjectsQuery>,
+) -> Result<Json<Page<GetProjectResponse>>> {
+    let mut select = visible_projects(&user);
+    if let Some(title) = &query.title_contains {
+        select = select.filter(project::Column::Title.contains(title));
+    }
+    if let Some(after) = query.created_after {
+        select = select.filter(project::Column::CreatedAt.gt(to_utc(after)));
+    }
+    if let Some(before) = query.created_before {
+        select = select.filter(project::Column::CreatedAt.lt(to_utc(before)));
+    }
+    if let Some(owner) = query.owner {
+        select = select.filter(project::Column::UserId.eq(owner));
+    }
+    if let Some(workspace) = query.workspace {
+        select = select.filter(project::Column::WorkspaceId.eq(workspace));
+    }
+
+    let projects = paginate(select, &pagination, &PROJECT_SORTING, &ctx.db).await?;
+
+    let mut tasks = if query.includes("tasks") {
+        Some(tasks_by_project(&ctx, &projects.items).await?)
+    } else {
+        None
+    };
+
+    let res = projects.map(|project| GetProjectResponse {
+        tasks: tasks
+            .as_mut()
+            .map(|tasks| tasks.remove(&project.id).unwrap_or_default()),
+        project,
+    });
 
-    fn update_model(self, model: &mut Self::ActiveModel);
+    Ok(Json(res))
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Validate)]
@@ -87,26 +243,109 @@ pub struct ProjectRequest {
     pub text: String,
 }
 
+impl ModelIn for ProjectRequest {
+    type ActiveModel = project::ActiveModel;
+
+    fn update_model(self, model: &mut Self::ActiveModel) {
+        model.title = ActiveValue::Set(self.title);
+        model.text = ActiveValue::Set(self.text);
+    }
+}
+
+/// The body of a `PATCH`. Fields that are left out keep their current value.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize, Validate)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ProjectPatchRequest {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    pub title: Option<String>,
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    pub text: Option<String>,
+}
+
+impl ModelIn for ProjectPatchRequest {
+    type ActiveModel = project::ActiveModel;
+
+    fn update_model(self, model: &mut Self::ActiveModel) {
+        model.title = set_if_some(self.title);
+        model.text = set_if_some(self.text);
+    }
+}
+
+#[tracing::instrument(name = "Updating a project", skip(ctx, user, data), fields(user_id = %user.user_id))]
+async fn update_project(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+    ValidatedJson(data): ValidatedJson<ProjectRequest>,
+) -> Result<Json<project::Model>> {
+    let project = find_editable(&ctx.db, &user, id).await?;
+    Ok(Json(apply_update(&ctx.db, &project, data).await?))
+}
+
+#[tracing::instrument(name = "Patching a project", skip(ctx, user, data), fields(user_id = %user.user_id))]
+async fn patch_project(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+    ValidatedJson(data): ValidatedJson<ProjectPatchRequest>,
+) -> Result<Json<project::Model>> {
+    let project = find_editable(&ctx.db, &user, id).await?;
+    Ok(Json(apply_update(&ctx.db, &project, data).await?))
+}
+
+#[tracing::instrument(name = "Deleting a project", skip(ctx, user), fields(user_id = %user.user_id))]
+async fn delete_project(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let txn = ctx.db.begin().await?;
+    let project = find_editable(&txn, &user, id).await?;
+
+    // The foreign key cascades too, but SQLite only enforces it when foreign keys are switched
+    // on for the connection, so don't rely on it.
+    task::Entity::delete_many()
+        .filter(task::Column::ProjectId.eq(project.id))
+        .exec(&txn)
+        .await?;
+    project.delete(&txn).await?;
+    txn.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct CreateProjectQuery {
+    /// The workspace to create the project in. Defaults to the caller's personal workspace.
+    pub workspace: Option<Uuid>,
+}
+
 #[tracing::instrument(
     name = "Creating a new project",
-    skip(ctx),
+    skip(ctx, user),
     fields(
+        user_id = %user.user_id,
         title = %data.title,
         text = %data.text
     )
 )]
 async fn create_project(
-    ref ctx: Extension<Server>,
-    data: Json<ProjectRequest>,
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Query(query): Query<CreateProjectQuery>,
+    ValidatedJson(data): ValidatedJson<ProjectRequest>,
 ) -> Result<StatusCode> {
-    project::ActiveModel {
-        title: ActiveValue::Set(data.title.to_owned()),
-        text: ActiveValue::Set(data.text.to_owned()),
+    let workspace_id = query.workspace.unwrap_or(user.user_id);
+    editable_membership(&ctx.db, &user, workspace_id).await?;
+
+    let mut model = project::ActiveModel {
+        user_id: ActiveValue::Set(user.user_id),
+        workspace_id: ActiveValue::Set(workspace_id),
         ..Default::default()
-    }
-    .insert(&ctx.db)
-    .await
-    .map_err(|e| HttpError::bad_request(Some(e.to_string()), None))?;
+    };
+    data.update_model(&mut model);
+    model.insert(&ctx.db).await?;
 
     Ok(StatusCode::CREATED)
 }
@@ -0,0 +1,218 @@
+use super::{
+    projects::{find_editable, find_visible},
+    apply_update, ModelIn,
+};
+use crate::{
+    auth::{
+        access_token::Scope,
+        permission::{require, Permission},
+        AuthUser,
+    },
+    error::HttpError,
+    server::Server,
+    utils::{set_if_some, ValidatedJson},
+    Result,
+};
+use axum::{
+    extract::Extension,
+    extract::Path,
+    http::StatusCode,
+    routing::{get, post, put},
+    Json, Router,
+};
+use entity::task;
+use sea_orm::{prelude::Uuid, ActiveModelTrait, ActiveValue, EntityTrait, ModelTrait, QueryOrder};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+pub fn router() -> Router {
+    Router::new()
+        .route(
+            "/project/:id/tasks",
+            get(get_project_tasks)
+                .route_layer(require(Permission::ViewProjects, Scope::ReadProjects)),
+        )
+        .route(
+            "/project/:id/tasks",
+            post(create_task).route_layer(require(Permission::EditProjects, Scope::WriteTasks)),
+        )
+        .route(
+            "/task/:id",
+            get(get_task).route_layer(require(Permission::ViewProjects, Scope::ReadProjects)),
+        )
+        .route(
+            "/task/:id",
+            put(update_task)
+                .patch(patch_task)
+                .delete(delete_task)
+                .route_layer(require(Permission::EditProjects, Scope::WriteTasks)),
+        )
+        .route(
+            "/task/:id/complete",
+            post(complete_task).route_layer(require(Permission::EditProjects, Scope::WriteTasks)),
+        )
+}
+
+/// Looks up a task whose project the caller can see, or 404s.
+async fn find_visible_task(ctx: &Server, user: &AuthUser, id: Uuid) -> Result<task::Model> {
+    let task = find_task(ctx, id).await?;
+    find_visible(&ctx.db, user, project_of(&task)?).await?;
+    Ok(task)
+}
+
+/// Looks up a task whose project the caller may change. See [`find_editable`].
+async fn find_editable_task(ctx: &Server, user: &AuthUser, id: Uuid) -> Result<task::Model> {
+    let task = find_task(ctx, id).await?;
+    find_editable(&ctx.db, user, project_of(&task)?).await?;
+    Ok(task)
+}
+
+async fn find_task(ctx: &Server, id: Uuid) -> Result<task::Model> {
+    Ok(task::Entity::find_by_id(id)
+        .one(&ctx.db)
+        .await?
+        .ok_or_else(|| HttpError::not_found(None, None))?)
+}
+
+/// Tasks are only reachable through their project.
+fn project_of(task: &task::Model) -> Result<Uuid> {
+    Ok(task
+        .project_id
+        .ok_or_else(|| HttpError::not_found(None, None))?)
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskRequest {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    pub title: String,
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub done: bool,
+}
+
+impl ModelIn for TaskRequest {
+    type ActiveModel = task::ActiveModel;
+
+    fn update_model(self, model: &mut Self::ActiveModel) {
+        model.title = ActiveValue::Set(self.title);
+        model.text = ActiveValue::Set(self.text);
+        model.done = ActiveValue::Set(self.done);
+    }
+}
+
+/// The body of a `PATCH`. Fields that are left out keep their current value.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize, Validate)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TaskPatchRequest {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    pub title: Option<String>,
+    pub text: Option<String>,
+    pub done: Option<bool>,
+}
+
+impl ModelIn for TaskPatchRequest {
+    type ActiveModel = task::ActiveModel;
+
+    fn update_model(self, model: &mut Self::ActiveModel) {
+        model.title = set_if_some(self.title);
+        model.text = set_if_some(self.text);
+        model.done = set_if_some(self.done);
+    }
+}
+
+async fn get_project_tasks(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Vec<task::Model>>> {
+    let project = find_visible(&ctx.db, &user, id).await?;
+
+    Ok(Json(
+        project
+            .find_related(task::Entity)
+            .order_by_asc(task::Column::CreatedAt)
+            .all(&ctx.db)
+            .await?,
+    ))
+}
+
+#[tracing::instrument(
+    name = "Creating a new task",
+    skip(ctx, user, data),
+    fields(user_id = %user.user_id, title = %data.title)
+)]
+async fn create_task(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+    ValidatedJson(data): ValidatedJson<TaskRequest>,
+) -> Result<(StatusCode, Json<task::Model>)> {
+    let project = find_editable(&ctx.db, &user, id).await?;
+
+    let mut model = task::ActiveModel {
+        project_id: ActiveValue::Set(Some(project.id)),
+        ..Default::default()
+    };
+    data.update_model(&mut model);
+
+    Ok((StatusCode::CREATED, Json(model.insert(&ctx.db).await?)))
+}
+
+async fn get_task(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+) -> Result<Json<task::Model>> {
+    Ok(Json(find_visible_task(&ctx, &user, id).await?))
+}
+
+#[tracing::instrument(name = "Updating a task", skip(ctx, user, data), fields(user_id = %user.user_id))]
+async fn update_task(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+    ValidatedJson(data): ValidatedJson<TaskRequest>,
+) -> Result<Json<task::Model>> {
+    let task = find_editable_task(&ctx, &user, id).await?;
+    Ok(Json(apply_update(&ctx.db, &task, data).await?))
+}
+
+#[tracing::instrument(name = "Patching a task", skip(ctx, user, data), fields(user_id = %user.user_id))]
+async fn patch_task(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+    ValidatedJson(data): ValidatedJson<TaskPatchRequest>,
+) -> Result<Json<task::Model>> {
+    let task = find_editable_task(&ctx, &user, id).await?;
+    Ok(Json(apply_update(&ctx.db, &task, data).await?))
+}
+
+#[tracing::instrument(name = "Completing a task", skip(ctx, user), fields(user_id = %user.user_id))]
+async fn complete_task(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+) -> Result<Json<task::Model>> {
+    let data = TaskPatchRequest {
+        done: Some(true),
+        ..Default::default()
+    };
+    let task = find_editable_task(&ctx, &user, id).await?;
+    Ok(Json(apply_update(&ctx.db, &task, data).await?))
+}
+
+#[tracing::instrument(name = "Deleting a task", skip(ctx, user), fields(user_id = %user.user_id))]
+async fn delete_task(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    find_editable_task(&ctx, &user, id)
+        .await?
+        .delete(&ctx.db)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,136 @@
+//! Managing personal access tokens, at `/user/me/tokens`.
+use crate::{
+    auth::{
+        access_token::{self, Scope, Scopes},
+        AuthUser,
+    },
+    error::HttpError,
+    server::Server,
+    utils::ValidatedJson,
+    Result,
+};
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    routing::{delete, get},
+    Json, Router,
+};
+use entity::access_token as token_entity;
+use sea_orm::{
+    prelude::{DateTimeWithTimeZone, Uuid},
+    ColumnTrait, EntityTrait, QueryFilter, QueryOrder,
+};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use validator::{Validate, ValidationError};
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/user/me/tokens", get(list_tokens).post(create_token))
+        .route("/user/me/tokens/:id", delete(revoke_token))
+}
+
+/// A token as listed. The token itself is only ever shown when it is created.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AccessTokenResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<DateTimeWithTimeZone>,
+    pub last_used_at: Option<DateTimeWithTimeZone>,
+    pub created_at: DateTimeWithTimeZone,
+}
+
+impl From<token_entity::Model> for AccessTokenResponse {
+    fn from(token: token_entity::Model) -> Self {
+        Self {
+            id: token.id,
+            name: token.name,
+            scopes: Scopes::parse(&token.scopes).to_vec(),
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+            created_at: token.created_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreatedAccessTokenResponse {
+    #[serde(flatten)]
+    pub details: AccessTokenResponse,
+    /// Send as `Authorization: Bearer <token>`. It is not stored, so this is the only chance to
+    /// copy it.
+    pub token: String,
+}
+
+fn validate_scopes(scopes: &[Scope]) -> std::result::Result<(), ValidationError> {
+    if scopes.is_empty() {
+        let mut error = ValidationError::new("scopes_empty");
+        error.message = Some(Cow::Borrowed("Must grant at least one scope"));
+        return Err(error);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct CreateTokenRequest {
+    #[validate(length(min = 1, max = 100, message = "Must be between 1 and 100 characters long"))]
+    name: String,
+    #[validate(custom = "validate_scopes")]
+    scopes: Vec<Scope>,
+    /// Left out for a token that doesn't expire.
+    #[serde(default)]
+    #[validate(range(min = 1, max = 3650, message = "Must be between 1 and 3650 days"))]
+    expires_in_days: Option<i64>,
+}
+
+async fn list_tokens(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+) -> Result<Json<Vec<AccessTokenResponse>>> {
+    user.require_session()?;
+    let tokens = token_entity::Entity::find()
+        .filter(token_entity::Column::UserId.eq(user.user_id))
+        .order_by_asc(token_entity::Column::CreatedAt)
+        .all(&ctx.db)
+        .await?;
+    Ok(Json(tokens.into_iter().map(Into::into).collect()))
+}
+
+/// Tokens can't be created with a token, so a leaked one can't be used to mint longer-lived ones.
+#[tracing::instrument(name = "Creating an access token", skip(ctx, user, req), fields(user_id = %user.user_id))]
+async fn create_token(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    ValidatedJson(req): ValidatedJson<CreateTokenRequest>,
+) -> Result<(StatusCode, Json<CreatedAccessTokenResponse>)> {
+    user.require_session()?;
+    let scopes: Scopes = req.scopes.into_iter().collect();
+    let (model, token) =
+        access_token::issue(&ctx.db, user.user_id, req.name, scopes, req.expires_in_days).await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedAccessTokenResponse {
+            details: model.into(),
+            token,
+        }),
+    ))
+}
+
+async fn revoke_token(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    user.require_session()?;
+    let deleted = token_entity::Entity::delete_many()
+        .filter(token_entity::Column::Id.eq(id))
+        .filter(token_entity::Column::UserId.eq(user.user_id))
+        .exec(&ctx.db)
+        .await?;
+    if deleted.rows_affected == 0 {
+        return Err(HttpError::not_found(None, None).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
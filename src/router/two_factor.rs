@@ -0,0 +1,351 @@
+//! Optional TOTP two-factor authentication: enrollment, recovery codes and the second login step.
+use super::users::{attempt_keys, check_current_password, current_account, TokenResponse};
+use crate::{
+    auth::{
+        one_time,
+        password::{hash_password, verify_password},
+        permission::ensure_enabled,
+        refresh, totp, AuthUser,
+    },
+    error::{HttpError, ValidationErrorItem},
+    server::Server,
+    utils::ClientIp,
+    Result,
+};
+use axum::{
+    extract::Extension,
+    http::StatusCode,
+    routing::{delete, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use entity::{one_time_token::PURPOSE_TWO_FACTOR_LOGIN, recovery_code, user};
+use sea_orm::{
+    prelude::{DateTimeWithTimeZone, Uuid},
+    sea_query::{Condition, Expr},
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter,
+    TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/user/me/2fa", delete(disable))
+        .route("/user/me/2fa/enroll", post(enroll))
+        .route("/user/me/2fa/confirm", post(confirm))
+        .route("/user/me/2fa/recovery-codes", post(regenerate_recovery_codes))
+        .route("/user/login/2fa", post(login_second_step))
+}
+
+fn already_enabled() -> HttpError {
+    HttpError::conflict(
+        Some("two_factor_enabled".to_owned()),
+        Some("Two-factor authentication is already enabled.".to_owned()),
+    )
+}
+
+fn not_enabled() -> HttpError {
+    HttpError::conflict(
+        Some("two_factor_disabled".to_owned()),
+        Some("Two-factor authentication is not enabled.".to_owned()),
+    )
+}
+
+/// Replaces the recovery codes of `user_id` with the ones hashed in `hashes`.
+async fn replace_recovery_codes<C: ConnectionTrait>(
+    db: &C,
+    user_id: Uuid,
+    hashes: Vec<String>,
+) -> Result<()> {
+    recovery_code::Entity::delete_many()
+        .filter(recovery_code::Column::UserId.eq(user_id))
+        .exec(db)
+        .await?;
+    recovery_code::Entity::insert_many(hashes.into_iter().map(|code_hash| {
+        recovery_code::ActiveModel {
+            user_id: ActiveValue::Set(user_id),
+            code_hash: ActiveValue::Set(code_hash),
+            ..Default::default()
+        }
+    }))
+    .exec(db)
+    .await?;
+    Ok(())
+}
+
+/// New recovery codes and their hashes. Hashed before any transaction is opened, as it is slow.
+/// The plain codes go back to the user once and are never seen again.
+async fn new_recovery_codes() -> Result<(Vec<String>, Vec<String>)> {
+    let codes = totp::generate_recovery_codes();
+    let mut hashes = Vec::with_capacity(codes.len());
+    for code in &codes {
+        hashes.push(hash_password(totp::normalize_recovery_code(code)).await?);
+    }
+    Ok((codes, hashes))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PasswordRequest {
+    password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EnrollmentResponse {
+    /// The base32 secret, for typing into an authenticator app by hand.
+    pub secret: String,
+    /// The same secret as an `otpauth://` URI, usually shown as a QR code.
+    pub provisioning_uri: String,
+}
+
+/// Starts enrollment with a fresh secret. Nothing changes for logins until the secret is
+/// confirmed with a code from it, so starting over is harmless.
+#[tracing::instrument(name = "Enrolling in two-factor authentication", skip(ctx, user, req), fields(user_id = %user.user_id))]
+async fn enroll(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Json(req): Json<PasswordRequest>,
+) -> Result<Json<EnrollmentResponse>> {
+    let account = current_account(&ctx, &user).await?;
+    check_current_password(&account, "password", req.password).await?;
+    if account.totp_enabled_at.is_some() {
+        return Err(already_enabled().into());
+    }
+
+    let secret = totp::generate_secret();
+    user::ActiveModel {
+        user_id: ActiveValue::Unchanged(user.user_id),
+        totp_secret: ActiveValue::Set(Some(secret.clone())),
+        totp_last_used_step: ActiveValue::Set(None),
+        ..ActiveModelTrait::default()
+    }
+    .update(&ctx.db)
+    .await?;
+
+    Ok(Json(EnrollmentResponse {
+        provisioning_uri: totp::provisioning_uri(
+            &secret,
+            &ctx.settings.auth.totp_issuer,
+            &account.username,
+        ),
+        secret,
+    }))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConfirmRequest {
+    code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Turns two-factor authentication on, once the user shows that their app produces the right
+/// codes, and hands out the recovery codes.
+#[tracing::instrument(name = "Confirming two-factor authentication", skip(ctx, user, req), fields(user_id = %user.user_id))]
+async fn confirm(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Json(req): Json<ConfirmRequest>,
+) -> Result<Json<RecoveryCodesResponse>> {
+    let account = current_account(&ctx, &user).await?;
+    if account.totp_enabled_at.is_some() {
+        return Err(already_enabled().into());
+    }
+    let secret = account.totp_secret.ok_or_else(|| {
+        HttpError::conflict(
+            Some("two_factor_not_enrolled".to_owned()),
+            Some("Start with `POST /user/me/2fa/enroll`.".to_owned()),
+        )
+    })?;
+    let now = ctx.clock.now();
+    let step = totp::verify(&secret, &req.code, now, None).ok_or_else(|| {
+        HttpError::unprocessable_entity(vec![ValidationErrorItem {
+            loc: vec!["body".to_owned(), "code".to_owned()],
+            msg: "Is not the current code".to_owned(),
+            ty: "value_error.two_factor_code".to_owned(),
+        }])
+    })?;
+
+    let (codes, hashes) = new_recovery_codes().await?;
+    let txn = ctx.db.begin().await?;
+    user::ActiveModel {
+        user_id: ActiveValue::Unchanged(user.user_id),
+        totp_enabled_at: ActiveValue::Set(Some(DateTimeWithTimeZone::from(now))),
+        totp_last_used_step: ActiveValue::Set(Some(step)),
+        ..ActiveModelTrait::default()
+    }
+    .update(&txn)
+    .await?;
+    replace_recovery_codes(&txn, user.user_id, hashes).await?;
+    txn.commit().await?;
+
+    Ok(Json(RecoveryCodesResponse {
+        recovery_codes: codes,
+    }))
+}
+
+/// Replaces the recovery codes, e.g. once most of them are used up.
+#[tracing::instrument(name = "Regenerating recovery codes", skip(ctx, user, req), fields(user_id = %user.user_id))]
+async fn regenerate_recovery_codes(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Json(req): Json<PasswordRequest>,
+) -> Result<Json<RecoveryCodesResponse>> {
+    let account = current_account(&ctx, &user).await?;
+    check_current_password(&account, "password", req.password).await?;
+    if account.totp_enabled_at.is_none() {
+        return Err(not_enabled().into());
+    }
+
+    let (codes, hashes) = new_recovery_codes().await?;
+    let txn = ctx.db.begin().await?;
+    replace_recovery_codes(&txn, user.user_id, hashes).await?;
+    txn.commit().await?;
+
+    Ok(Json(RecoveryCodesResponse {
+        recovery_codes: codes,
+    }))
+}
+
+#[tracing::instrument(name = "Disabling two-factor authentication", skip(ctx, user, req), fields(user_id = %user.user_id))]
+async fn disable(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Json(req): Json<PasswordRequest>,
+) -> Result<StatusCode> {
+    let account = current_account(&ctx, &user).await?;
+    check_current_password(&account, "password", req.password).await?;
+
+    let txn = ctx.db.begin().await?;
+    user::ActiveModel {
+        user_id: ActiveValue::Unchanged(user.user_id),
+        totp_secret: ActiveValue::Set(None),
+        totp_enabled_at: ActiveValue::Set(None),
+        totp_last_used_step: ActiveValue::Set(None),
+        ..ActiveModelTrait::default()
+    }
+    .update(&txn)
+    .await?;
+    recovery_code::Entity::delete_many()
+        .filter(recovery_code::Column::UserId.eq(user.user_id))
+        .exec(&txn)
+        .await?;
+    txn.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The second step of a login takes either a code from the app or a recovery code.
+#[derive(Deserialize, Debug)]
+pub struct SecondStepRequest {
+    two_factor_token: String,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    recovery_code: Option<String>,
+}
+
+fn invalid_code() -> HttpError {
+    HttpError::unauthorized(
+        Some("invalid_two_factor_code".to_owned()),
+        Some("The code is wrong. Log in again to get another try.".to_owned()),
+    )
+}
+
+/// Records that the code for `step` was used. The update only goes through for a step later than
+/// the last one, so the same code can't log in twice even when two requests race.
+async fn use_step<C: ConnectionTrait>(db: &C, user_id: Uuid, step: i64) -> Result<bool> {
+    let updated = user::Entity::update_many()
+        .col_expr(user::Column::TotpLastUsedStep, Expr::value(Some(step)))
+        .filter(user::Column::UserId.eq(user_id))
+        .filter(
+            Condition::any()
+                .add(user::Column::TotpLastUsedStep.is_null())
+                .add(user::Column::TotpLastUsedStep.lt(step)),
+        )
+        .exec(db)
+        .await?;
+    Ok(updated.rows_affected == 1)
+}
+
+/// Uses up one of the unused recovery codes of `user_id` if `code` is among them.
+async fn use_recovery_code<C: ConnectionTrait>(
+    db: &C,
+    user_id: Uuid,
+    code: &str,
+    now: DateTime<Utc>,
+) -> Result<bool> {
+    let code = totp::normalize_recovery_code(code);
+    let unused = recovery_code::Entity::find()
+        .filter(recovery_code::Column::UserId.eq(user_id))
+        .filter(recovery_code::Column::UsedAt.is_null())
+        .all(db)
+        .await?;
+
+    for candidate in unused {
+        if verify_password(code.clone(), candidate.code_hash).await? {
+            let used = recovery_code::Entity::update_many()
+                .col_expr(
+                    recovery_code::Column::UsedAt,
+                    Expr::value(Some(DateTimeWithTimeZone::from(now))),
+                )
+                .filter(recovery_code::Column::Id.eq(candidate.id))
+                .filter(recovery_code::Column::UsedAt.is_null())
+                .exec(db)
+                .await?;
+            return Ok(used.rows_affected == 1);
+        }
+    }
+    Ok(false)
+}
+
+/// Finishes a login that `POST /user/login` answered with a two-factor challenge.
+///
+/// The challenge token is used up by the first attempt, right or wrong, so a stolen password
+/// only buys one guess per login. Wrong guesses also count as failed logins of the account.
+#[tracing::instrument(name = "Logging in with a second factor", skip(ctx, req))]
+async fn login_second_step(
+    Extension(ctx): Extension<Server>,
+    ClientIp(ip): ClientIp,
+    Json(req): Json<SecondStepRequest>,
+) -> Result<Json<TokenResponse>> {
+    if req.code.is_some() == req.recovery_code.is_some() {
+        return Err(HttpError::unprocessable_entity(vec![ValidationErrorItem {
+            loc: vec!["body".to_owned()],
+            msg: "Send exactly one of `code` and `recovery_code`".to_owned(),
+            ty: "value_error.two_factor".to_owned(),
+        }])
+        .into());
+    }
+    let user =
+        one_time::consume(&ctx.db, &req.two_factor_token, PURPOSE_TWO_FACTOR_LOGIN).await?;
+    ensure_enabled(&user)?;
+    let now = ctx.clock.now();
+    let keys = attempt_keys(&user.username, ip);
+    let attempt = ctx.lockout.reserve(&keys, now).await?;
+
+    let accepted = match (&user.totp_secret, user.totp_enabled_at) {
+        // Turned off since the password step.
+        (None, _) | (_, None) => false,
+        (Some(secret), Some(_)) => match (&req.code, &req.recovery_code) {
+            (Some(code), _) => {
+                match totp::verify(secret, code, now, user.totp_last_used_step) {
+                    Some(step) => use_step(&ctx.db, user.user_id, step).await?,
+                    None => false,
+                }
+            }
+            (None, Some(code)) => use_recovery_code(&ctx.db, user.user_id, code, now).await?,
+            (None, None) => false,
+        },
+    };
+    if !accepted {
+        ctx.lockout.record_failure(attempt);
+        return Err(invalid_code().into());
+    }
+    ctx.lockout.record_success(attempt, &user.username).await?;
+
+    let refresh_token = refresh::issue(&ctx.db, &ctx.settings.auth, user.user_id, None).await?;
+    Ok(Json(TokenResponse::new(&ctx, user.user_id, refresh_token)?))
+}
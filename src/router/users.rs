@@ -1,100 +1,592 @@
-use crate::{error::{HttpError}, server::Server, Result};
+use super::{
+    account::{send_email_change, send_verification_email},
+    avatars::remove_avatar,
+    workspaces::leave_all,
+    apply_update, ModelIn,
+};
+use crate::{
+    auth::{
+        access_token,
+        jwt::issue_access_token,
+        lockout::AttemptKey,
+        one_time,
+        password::{hash_password, verify_password, DUMMY_PASSWORD_HASH},
+        permission::ensure_enabled,
+        refresh, AuthUser,
+    },
+    error::{HttpError, ValidationErrorItem},
+    server::Server,
+    utils::{
+        paginate, set_if_some, to_utc, ClientIp, Page, Pagination, Sorting, ValidatedJson,
+    },
+    Result,
+};
 use axum::{
     extract::Extension,
-    extract::Path,
+    extract::{Path, Query},
     http::StatusCode,
     routing::{get, post},
     Json, Router,
 };
-use entity::user;
-use sea_orm::{prelude::Uuid, ActiveModelTrait, ActiveValue, EntityTrait};
+use entity::{
+    one_time_token::{self, PURPOSE_TWO_FACTOR_LOGIN},
+    access_token as token_entity, project, recovery_code, refresh_token,
+    user::{self, Role},
+    workspace,
+};
+use sea_orm::{
+    prelude::{DateTimeWithTimeZone, Uuid},
+    sea_query::Expr,
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter, TransactionTrait,
+};
 use serde::{Deserialize, Serialize};
-use argon2::{Argon2, PasswordHash, password_hash::SaltString};
-use anyhow::Context;
+use std::{borrow::Cow, net::IpAddr};
+use validator::{Validate, ValidationError};
+
+/// What anyone can see of a user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PublicUserResponse {
+    pub user_id: Uuid,
+    pub username: String,
+    pub bio: String,
+    pub image: Option<String>,
+    pub created_at: DateTimeWithTimeZone,
+}
+
+impl From<user::Model> for PublicUserResponse {
+    fn from(user: user::Model) -> Self {
+        Self {
+            user_id: user.user_id,
+            username: user.username,
+            bio: user.bio,
+            image: user.image,
+            created_at: user.created_at,
+        }
+    }
+}
+
+/// What users can see of themselves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PrivateUserResponse {
+    pub user_id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub two_factor_enabled: bool,
+    pub role: Role,
+    pub bio: String,
+    pub image: Option<String>,
+    pub created_at: DateTimeWithTimeZone,
+    pub updated_at: DateTimeWithTimeZone,
+}
+
+impl From<user::Model> for PrivateUserResponse {
+    fn from(user: user::Model) -> Self {
+        Self {
+            user_id: user.user_id,
+            username: user.username,
+            email: user.email,
+            email_verified: user.email_verified_at.is_some(),
+            two_factor_enabled: user.totp_enabled_at.is_some(),
+            role: user.role,
+            bio: user.bio,
+            image: user.image,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
+}
 
-async fn get_user(ref ctx: Extension<Server>, Path(id): Path<Uuid>) -> Result<Json<user::Model>> {
+async fn get_user(
+    Extension(ctx): Extension<Server>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<PublicUserResponse>> {
     Ok(Json(
         user::Entity::find_by_id(id)
             .one(&ctx.db)
             .await?
-            .ok_or_else(|| HttpError::not_found(None, None))?,
+            .ok_or_else(|| HttpError::not_found(None, None))?
+            .into(),
     ))
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
-#[serde(default)]
-pub struct GetUsersResponse {
-    pub users: Vec<user::Model>,
+/// The caller's own row. The token can outlive the account, in which case this is a 401.
+pub(super) async fn current_account(ctx: &Server, user: &AuthUser) -> Result<user::Model> {
+    Ok(user::Entity::find_by_id(user.user_id)
+        .one(&ctx.db)
+        .await?
+        .ok_or_else(|| HttpError::unauthorized(None, None))?)
 }
 
-async fn get_users(ref ctx: Extension<Server>) -> Result<Json<GetUsersResponse>> {
-    Ok(Json(GetUsersResponse {
-        users: user::Entity::find().all(&ctx.db).await?,
-    }))
+/// Guards changes to the account itself, so that a stolen access token isn't enough to take it
+/// over. A mismatch is a 422 on `field` rather than a 401, which clients take to mean the session
+/// is gone.
+pub(super) async fn check_current_password(account: &user::Model, field: &str, password: String) -> Result<()> {
+    if verify_password(password, account.password_hash.clone()).await? {
+        Ok(())
+    } else {
+        Err(HttpError::unprocessable_entity(vec![ValidationErrorItem {
+            loc: vec!["body".to_owned(), field.to_owned()],
+            msg: "Does not match the current password".to_owned(),
+            ty: "value_error.password_mismatch".to_owned(),
+        }])
+        .into())
+    }
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
-struct UserRes {
-    email: String,
-    username: String,
-    bio: String,
-    image: Option<String>,
+async fn get_me(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+) -> Result<Json<PrivateUserResponse>> {
+    Ok(Json(current_account(&ctx, &user).await?.into()))
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+pub(super) const USER_SORTING: Sorting<user::Entity> = Sorting {
+    columns: &[
+        ("created_at", user::Column::CreatedAt),
+        ("username", user::Column::Username),
+    ],
+    id: user::Column::UserId,
+};
+
+#[derive(Deserialize, Debug, Default)]
 #[serde(default)]
+pub struct GetUsersQuery {
+    pub username_contains: Option<String>,
+    pub created_after: Option<DateTimeWithTimeZone>,
+    pub created_before: Option<DateTimeWithTimeZone>,
+}
+
+async fn get_users(
+    Extension(ctx): Extension<Server>,
+    pagination: Pagination,
+    Query(query): Query<GetUsersQuery>,
+) -> Result<Json<Page<PublicUserResponse>>> {
+    let mut select = user::Entity::find().filter(user::Column::DisabledAt.is_null());
+    if let Some(username) = &query.username_contains {
+        select = select.filter(user::Column::Username.contains(username));
+    }
+    if let Some(after) = query.created_after {
+        select = select.filter(user::Column::CreatedAt.gt(to_utc(after)));
+    }
+    if let Some(before) = query.created_before {
+        select = select.filter(user::Column::CreatedAt.lt(to_utc(before)));
+    }
+
+    Ok(Json(
+        paginate(select, &pagination, &USER_SORTING, &ctx.db)
+            .await?
+            .map(PublicUserResponse::from),
+    ))
+}
+
+/// Usernames show up in URLs and mentions, so they are kept to a plain character set.
+fn validate_username(username: &str) -> std::result::Result<(), ValidationError> {
+    if username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+    {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("username_charset");
+        error.message = Some(Cow::Borrowed(
+            "May only contain letters, digits, `_`, `.` and `-`",
+        ));
+        Err(error)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Validate)]
 pub struct CreateUserRequest {
+    #[validate(
+        email(message = "Must be a valid email address"),
+        length(max = 254, message = "Must be at most 254 characters long")
+    )]
     email: String,
+    #[validate(
+        length(min = 3, max = 32, message = "Must be between 3 and 32 characters long"),
+        custom = "validate_username"
+    )]
     username: String,
+    /// Checked against the configured [`PasswordPolicy`](crate::auth::password::PasswordPolicy).
     password: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+/// The body of `PATCH /user/me`. Fields that are left out keep their current value.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Validate)]
 #[serde(default)]
-pub struct CreateUserResponse {
-    pub success: bool,
+pub struct UserPatchRequest {
+    #[validate(
+        length(min = 3, max = 32, message = "Must be between 3 and 32 characters long"),
+        custom = "validate_username"
+    )]
+    pub username: Option<String>,
+    #[validate(
+        email(message = "Must be a valid email address"),
+        length(max = 254, message = "Must be at most 254 characters long")
+    )]
+    pub email: Option<String>,
+    #[validate(length(max = 1000, message = "Must be at most 1000 characters long"))]
+    pub bio: Option<String>,
+    /// A link to an image elsewhere. Uploads go through `PUT /user/me/avatar` instead.
+    #[validate(url(message = "Must be a URL"))]
+    pub image: Option<String>,
+    /// Needed to change `email`.
+    #[serde(skip_serializing)]
+    pub current_password: Option<String>,
+}
+
+impl ModelIn for UserPatchRequest {
+    type ActiveModel = user::ActiveModel;
+
+    /// Leaves out `email`, which only changes once the new address is confirmed.
+    fn update_model(self, model: &mut Self::ActiveModel) {
+        model.username = set_if_some(self.username);
+        model.bio = set_if_some(self.bio);
+        model.image = set_if_some(self.image.map(Some));
+    }
+}
+
+/// Changes the caller's profile. A new email address needs the current password, and only
+/// replaces the old one once the link sent to it is opened, so a stolen access token can't
+/// redirect the account's mail.
+#[tracing::instrument(name = "Updating a profile", skip(ctx, user, data), fields(user_id = %user.user_id))]
+async fn patch_me(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    ValidatedJson(mut data): ValidatedJson<UserPatchRequest>,
+) -> Result<Json<PrivateUserResponse>> {
+    let account = current_account(&ctx, &user).await?;
+    let replaces_avatar = data.image.is_some();
+    let new_email = data.email.take().filter(|email| *email != account.email);
+
+    if let Some(email) = &new_email {
+        let password = data.current_password.take().ok_or_else(|| {
+            HttpError::unprocessable_entity(vec![ValidationErrorItem {
+                loc: vec!["body".to_owned(), "current_password".to_owned()],
+                msg: "Needed to change the email address".to_owned(),
+                ty: "value_error.missing".to_owned(),
+            }])
+        })?;
+        check_current_password(&account, "current_password", password).await?;
+        let taken = user::Entity::find()
+            .filter(user::Column::Email.eq(email.as_str()))
+            .one(&ctx.db)
+            .await?;
+        if taken.is_some() {
+            return Err(HttpError::conflict_fields(vec![ValidationErrorItem {
+                loc: vec!["body".to_owned(), "email".to_owned()],
+                msg: "is already in use".to_owned(),
+                ty: "value_error.unique".to_owned(),
+            }])
+            .into());
+        }
+    }
+
+    let updated = apply_update(&ctx.db, &account, data).await?;
+
+    if replaces_avatar {
+        remove_avatar(&ctx, account.image.as_deref()).await;
+    }
+    if let Some(email) = new_email {
+        send_email_change(&ctx, &updated, &email).await?;
+    }
+    Ok(Json(updated.into()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+/// Changes the caller's password and ends every other session and personal access token, in case
+/// the old password was known to someone else. The caller gets a fresh session in return.
+#[tracing::instrument(name = "Changing a password", skip(ctx, user, req), fields(user_id = %user.user_id))]
+async fn change_password(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Json(req): Json<ChangePasswordRequest>,
+) -> Result<Json<TokenResponse>> {
+    let account = current_account(&ctx, &user).await?;
+    check_current_password(&account, "current_password", req.current_password).await?;
+    ctx.password_policy.validate("new_password", &req.new_password)?;
+    let password_hash = hash_password(req.new_password).await?;
+
+    let txn = ctx.db.begin().await?;
+    user::ActiveModel {
+        user_id: ActiveValue::Unchanged(user.user_id),
+        password_hash: ActiveValue::Set(password_hash),
+        ..ActiveModelTrait::default()
+    }
+    .update(&txn)
+    .await?;
+    refresh::revoke_all_for_user(&txn, user.user_id).await?;
+    access_token::revoke_all_for_user(&txn, user.user_id).await?;
+    let refresh_token = refresh::issue(&txn, &ctx.settings.auth, user.user_id, None).await?;
+    txn.commit().await?;
+
+    Ok(Json(TokenResponse::new(&ctx, user.user_id, refresh_token)?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteAccountRequest {
+    password: String,
+    /// The username of someone to hand the projects of the caller's personal workspace to. They
+    /// move to the recipient's personal workspace. Without it, they and their tasks are deleted
+    /// along with the account. Projects in shared workspaces are never affected.
+    #[serde(default)]
+    transfer_projects_to: Option<String>,
+}
+
+#[tracing::instrument(name = "Deleting an account", skip(ctx, user, req), fields(user_id = %user.user_id))]
+async fn delete_me(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Json(req): Json<DeleteAccountRequest>,
+) -> Result<StatusCode> {
+    let account = current_account(&ctx, &user).await?;
+    check_current_password(&account, "password", req.password).await?;
+
+    let txn = ctx.db.begin().await?;
+    if let Some(username) = &req.transfer_projects_to {
+        let recipient = user::Entity::find_by_name(username)
+            .one(&txn)
+            .await?
+            .filter(|recipient| recipient.user_id != user.user_id)
+            .ok_or_else(|| {
+                HttpError::unprocessable_entity(vec![ValidationErrorItem {
+                    loc: vec!["body".to_owned(), "transfer_projects_to".to_owned()],
+                    msg: "Must be the username of another user".to_owned(),
+                    ty: "value_error.unknown_user".to_owned(),
+                }])
+            })?;
+        project::Entity::update_many()
+            .col_expr(project::Column::UserId, Expr::value(recipient.user_id))
+            .col_expr(project::Column::WorkspaceId, Expr::value(recipient.user_id))
+            .filter(project::Column::WorkspaceId.eq(user.user_id))
+            .exec(&txn)
+            .await?;
+    }
+    // Whatever is still in the personal workspace goes with it, while projects in shared
+    // workspaces stay where they are.
+    leave_all(&txn, user.user_id).await?;
+    refresh_token::Entity::delete_many()
+        .filter(refresh_token::Column::UserId.eq(user.user_id))
+        .exec(&txn)
+        .await?;
+    one_time_token::Entity::delete_many()
+        .filter(one_time_token::Column::UserId.eq(user.user_id))
+        .exec(&txn)
+        .await?;
+    recovery_code::Entity::delete_many()
+        .filter(recovery_code::Column::UserId.eq(user.user_id))
+        .exec(&txn)
+        .await?;
+    token_entity::Entity::delete_many()
+        .filter(token_entity::Column::UserId.eq(user.user_id))
+        .exec(&txn)
+        .await?;
+    user::Entity::delete_by_id(user.user_id).exec(&txn).await?;
+    txn.commit().await?;
+
+    remove_avatar(&ctx, account.image.as_deref()).await;
+    Ok(StatusCode::NO_CONTENT)
 }
 
 async fn create_user(
-    ref ctx: Extension<Server>,
-    Json(req): Json<CreateUserRequest>,
+    Extension(ctx): Extension<Server>,
+    ValidatedJson(req): ValidatedJson<CreateUserRequest>,
 ) -> Result<StatusCode> {
+    ctx.password_policy.validate("password", &req.password)?;
     let pass = hash_password(req.password).await?;
-    user::ActiveModel {
+    // New accounts start out unverified.
+    let user = user::ActiveModel {
         username: ActiveValue::Set(req.username.to_owned()),
         email: ActiveValue::Set(req.email.to_owned()),
-        //todo fix argon2 hashing
         password_hash: ActiveValue::Set(pass),
         bio: ActiveValue::Set("".to_owned()),
+        email_verified_at: ActiveValue::Set(None),
         ..Default::default()
-    }
-    .insert(&ctx.db)
-    .await
-    .map_err(|e| HttpError::bad_request(Some(e.to_string()), None))?;
+    };
+    let txn = ctx.db.begin().await?;
+    let user = user.insert(&txn).await?;
+    workspace::create_personal(&txn, &user).await?;
+    txn.commit().await?;
+    send_verification_email_or_log(&ctx, &user).await;
 
     Ok(StatusCode::CREATED)
 }
 
+/// The account change has already been saved, so a mail that can't be sent is only logged. The
+/// user can ask for a new one with `POST /user/me/verify-email`.
+async fn send_verification_email_or_log(ctx: &Server, user: &user::Model) {
+    if let Err(e) = send_verification_email(ctx, user).await {
+        tracing::error!("could not send a verification email: {}", e);
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    /// Seconds until `access_token` expires.
+    pub expires_in: i64,
+    /// Single-use token for `POST /user/refresh`. Each refresh hands out a new one.
+    pub refresh_token: String,
+}
+
+impl TokenResponse {
+    pub(super) fn new(ctx: &Server, user_id: Uuid, refresh_token: String) -> Result<Self> {
+        let auth = &ctx.settings.auth;
+        Ok(TokenResponse {
+            access_token: issue_access_token(auth, user_id)?,
+            token_type: "Bearer".to_owned(),
+            expires_in: auth.access_token_ttl_secs,
+            refresh_token,
+        })
+    }
+}
+
+/// How long the second step of a login may take, in seconds.
+const TWO_FACTOR_LOGIN_TTL_SECS: i64 = 5 * 60;
+
+/// Returned by `POST /user/login` instead of tokens when the account has two-factor
+/// authentication. The login is finished with the token and a code at `POST /user/login/2fa`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TwoFactorChallenge {
+    pub two_factor_required: bool,
+    pub two_factor_token: String,
+    /// Seconds until `two_factor_token` expires.
+    pub expires_in: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Tokens(TokenResponse),
+    TwoFactorRequired(TwoFactorChallenge),
+}
+
+/// The keys that failed logins for `username` from `ip` are counted against.
+pub(super) fn attempt_keys(username: &str, ip: Option<IpAddr>) -> Vec<AttemptKey<'_>> {
+    let mut keys = vec![AttemptKey::Account(username)];
+    keys.extend(ip.map(AttemptKey::Ip));
+    keys
+}
+
+/// Too many failures for the account or the client address get a 429 with `Retry-After` before
+/// the password is even looked at.
+#[tracing::instrument(name = "Logging in", skip(ctx, req), fields(username = %req.username))]
+async fn login(
+    Extension(ctx): Extension<Server>,
+    ClientIp(ip): ClientIp,
+    Json(req): Json<LoginRequest>,
+) -> Result<Json<LoginResponse>> {
+    let now = ctx.clock.now();
+    let keys = attempt_keys(&req.username, ip);
+    let attempt = ctx.lockout.reserve(&keys, now).await?;
+
+    let user = user::Entity::find_by_name(&req.username)
+        .one(&ctx.db)
+        .await?;
+    let password_hash = match &user {
+        Some(user) => user.password_hash.clone(),
+        None => DUMMY_PASSWORD_HASH.to_owned(),
+    };
+    let verified = verify_password(req.password, password_hash).await?;
+    let user = match user {
+        Some(user) if verified => user,
+        // Unknown usernames and wrong passwords get the same answer, after the same work, so that
+        // the endpoint can't be used to find out which accounts exist.
+        _ => {
+            ctx.lockout.record_failure(attempt);
+            return Err(HttpError::unauthorized(
+                None,
+                Some("Invalid username or password.".to_owned()),
+            )
+            .into());
+        }
+    };
+    // The password was right, so the attempt isn't a failure, even if the login can't go on.
+    if let Err(e) = ensure_enabled(&user) {
+        ctx.lockout.release(attempt).await?;
+        return Err(e);
+    }
+
+    if user.totp_enabled_at.is_some() {
+        ctx.lockout.release(attempt).await?;
+        let token = one_time::issue(
+            &ctx.db,
+            &user,
+            PURPOSE_TWO_FACTOR_LOGIN,
+            TWO_FACTOR_LOGIN_TTL_SECS,
+        )
+        .await?;
+        return Ok(Json(LoginResponse::TwoFactorRequired(TwoFactorChallenge {
+            two_factor_required: true,
+            two_factor_token: token,
+            expires_in: TWO_FACTOR_LOGIN_TTL_SECS,
+        })));
+    }
+
+    // With two-factor authentication the failures are only forgotten once the second step is done.
+    ctx.lockout.record_success(attempt, &req.username).await?;
+    let refresh_token = refresh::issue(&ctx.db, &ctx.settings.auth, user.user_id, None).await?;
+    Ok(Json(LoginResponse::Tokens(TokenResponse::new(
+        &ctx,
+        user.user_id,
+        refresh_token,
+    )?)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+async fn refresh_session(
+    Extension(ctx): Extension<Server>,
+    Json(req): Json<RefreshRequest>,
+) -> Result<Json<TokenResponse>> {
+    let (user_id, refresh_token) =
+        refresh::rotate(&ctx.db, &ctx.settings.auth, &req.refresh_token).await?;
+    Ok(Json(TokenResponse::new(&ctx, user_id, refresh_token)?))
+}
+
+/// Ends the session that the given refresh token belongs to.
+async fn logout(
+    Extension(ctx): Extension<Server>,
+    Json(req): Json<RefreshRequest>,
+) -> Result<StatusCode> {
+    refresh::revoke(&ctx.db, &req.refresh_token).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Ends every session of the calling user. Access tokens already handed out stay valid until they
+/// expire, which is why they are kept short-lived.
+async fn logout_all(Extension(ctx): Extension<Server>, user: AuthUser) -> Result<StatusCode> {
+    refresh::revoke_all_for_user(&ctx.db, user.user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 pub fn router() -> Router {
     // By having each module responsible for setting up its own routing,
     // it makes the root module a lot cleaner.
     Router::new()
+        .route("/user/me", get(get_me).patch(patch_me).delete(delete_me))
+        .route("/user/me/password", post(change_password))
         .route("/user/:id", get(get_user))
         .route("/user", post(create_user))
+        .route("/user/login", post(login))
+        .route("/user/refresh", post(refresh_session))
+        .route("/user/logout", post(logout))
+        .route("/user/logout-all", post(logout_all))
         .route("/users", get(get_users))
 }
-
-async fn hash_password(password: String) -> Result<String> {
-    // Argon2 hashing is designed to be computationally intensive,
-    // so we need to do this on a blocking thread.
-    Ok(tokio::task::spawn_blocking(move || -> Result<String> {
-        let salt = SaltString::generate(rand::thread_rng());
-        Ok(
-            PasswordHash::generate(Argon2::default(), password, salt.as_str())
-                .map_err(|e| anyhow::anyhow!("failed to generate password hash: {}", e))?
-                .to_string(),
-        )
-    })
-    .await
-    .context("panic in generating password hash")??)
-}
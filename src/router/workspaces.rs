@@ -0,0 +1,709 @@
+//! Workspaces, their members and invitations to join them.
+//!
+//! Projects belong to a workspace, and everyone who is a member of it can see them. Owners manage
+//! the members and invite new ones, either by email or with a link they pass on themselves.
+use super::account::link;
+use crate::{
+    auth::{
+        access_token::Scope,
+        permission::{require, Permission},
+        refresh::{generate_token, hash_token},
+        AuthUser,
+    },
+    error::{HttpError, ValidationErrorItem},
+    mail::Email,
+    server::Server,
+    utils::ValidatedJson,
+    Result,
+};
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use chrono::{Duration, Utc};
+use entity::{
+    project, task, user, workspace, workspace_invitation,
+    workspace_member::{self, WorkspaceRole},
+};
+use sea_orm::{
+    prelude::{DateTimeWithTimeZone, Uuid},
+    sea_query::{Expr, Query as SubQuery},
+    ActiveModelTrait, ActiveValue, ColumnTrait, Condition, ConnectionTrait, EntityTrait,
+    PaginatorTrait, QueryFilter, QueryOrder, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use validator::Validate;
+
+pub fn router() -> Router {
+    Router::new()
+        .route(
+            "/workspaces",
+            get(list_workspaces).route_layer(require(Permission::ViewProjects, Scope::Admin)),
+        )
+        .route(
+            "/workspaces",
+            post(create_workspace).route_layer(require(Permission::EditProjects, Scope::Admin)),
+        )
+        .route(
+            "/workspaces/join",
+            post(join_workspace).route_layer(require(Permission::ViewProjects, Scope::Admin)),
+        )
+        .route(
+            "/workspace/:id",
+            delete(delete_workspace).route_layer(require(Permission::EditProjects, Scope::Admin)),
+        )
+        .route(
+            "/workspace/:id/members",
+            get(list_members).route_layer(require(Permission::ViewProjects, Scope::Admin)),
+        )
+        .route(
+            "/workspace/:id/members/:user_id",
+            put(set_member_role).route_layer(require(Permission::EditProjects, Scope::Admin)),
+        )
+        // Members can always leave, so only owners removing others need more than this.
+        .route(
+            "/workspace/:id/members/:user_id",
+            delete(remove_member).route_layer(require(Permission::ViewProjects, Scope::Admin)),
+        )
+        .route(
+            "/workspace/:id/invitations",
+            get(list_invitations)
+                .post(invite)
+                .route_layer(require(Permission::EditProjects, Scope::Admin)),
+        )
+        .route(
+            "/workspace/:id/invitations/:invitation_id",
+            delete(revoke_invitation).route_layer(require(Permission::EditProjects, Scope::Admin)),
+        )
+}
+
+/// The caller's membership of the workspace `id`. Workspaces they don't belong to 404, so that
+/// they are indistinguishable from ones that don't exist.
+pub(super) async fn membership<C: ConnectionTrait>(
+    db: &C,
+    user: &AuthUser,
+    id: Uuid,
+) -> Result<workspace_member::Model> {
+    Ok(workspace_member::Entity::find_membership(id, user.user_id)
+        .one(db)
+        .await?
+        .ok_or_else(|| HttpError::not_found(None, None))?)
+}
+
+/// Like [`membership`], but refuses viewers.
+pub(super) async fn editable_membership<C: ConnectionTrait>(
+    db: &C,
+    user: &AuthUser,
+    id: Uuid,
+) -> Result<workspace_member::Model> {
+    let member = membership(db, user, id).await?;
+    if !member.role.can_edit() {
+        return Err(read_only().into());
+    }
+    Ok(member)
+}
+
+pub(super) fn read_only() -> HttpError {
+    HttpError::forbidden(
+        Some("read_only".to_owned()),
+        Some("You can only view the projects of this workspace.".to_owned()),
+    )
+}
+
+/// Like [`membership`], but only for owners.
+async fn owner_membership<C: ConnectionTrait>(
+    db: &C,
+    user: &AuthUser,
+    id: Uuid,
+) -> Result<workspace_member::Model> {
+    let member = membership(db, user, id).await?;
+    if member.role != WorkspaceRole::Owner {
+        return Err(HttpError::forbidden(
+            Some("not_owner".to_owned()),
+            Some("Only owners of the workspace can do this.".to_owned()),
+        )
+        .into());
+    }
+    Ok(member)
+}
+
+/// The personal workspace of a user shares their id, and always keeps them as its owner.
+fn is_personal(workspace_id: Uuid, user_id: Uuid) -> bool {
+    workspace_id == user_id
+}
+
+fn personal_workspace() -> HttpError {
+    HttpError::conflict(
+        Some("personal_workspace".to_owned()),
+        Some("A personal workspace can't be deleted or lose its owner.".to_owned()),
+    )
+}
+
+async fn owner_count<C: ConnectionTrait>(db: &C, workspace_id: Uuid) -> Result<usize> {
+    Ok(workspace_member::Entity::find()
+        .filter(workspace_member::Column::WorkspaceId.eq(workspace_id))
+        .filter(workspace_member::Column::Role.eq(WorkspaceRole::Owner))
+        .count(db)
+        .await?)
+}
+
+/// Refuses to take away the last owner of a workspace, which would leave nobody to manage it.
+async fn keep_an_owner<C: ConnectionTrait>(db: &C, member: &workspace_member::Model) -> Result<()> {
+    if is_personal(member.workspace_id, member.user_id) {
+        return Err(personal_workspace().into());
+    }
+    if member.role == WorkspaceRole::Owner && owner_count(db, member.workspace_id).await? <= 1 {
+        return Err(HttpError::conflict(
+            Some("last_owner".to_owned()),
+            Some("Make someone else an owner first.".to_owned()),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// A workspace as one of its members sees it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WorkspaceResponse {
+    pub id: Uuid,
+    pub name: String,
+    /// The caller's role in the workspace.
+    pub role: WorkspaceRole,
+    pub personal: bool,
+    pub created_at: DateTimeWithTimeZone,
+    pub updated_at: DateTimeWithTimeZone,
+}
+
+impl WorkspaceResponse {
+    fn new(workspace: workspace::Model, member: &workspace_member::Model) -> Self {
+        Self {
+            personal: is_personal(workspace.id, member.user_id),
+            id: workspace.id,
+            name: workspace.name,
+            role: member.role,
+            created_at: workspace.created_at,
+            updated_at: workspace.updated_at,
+        }
+    }
+}
+
+async fn list_workspaces(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+) -> Result<Json<Vec<WorkspaceResponse>>> {
+    let memberships = workspace_member::Entity::find()
+        .filter(workspace_member::Column::UserId.eq(user.user_id))
+        .find_also_related(workspace::Entity)
+        .order_by_asc(workspace_member::Column::CreatedAt)
+        .all(&ctx.db)
+        .await?;
+
+    Ok(Json(
+        memberships
+            .into_iter()
+            .filter_map(|(member, workspace)| Some(WorkspaceResponse::new(workspace?, &member)))
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct WorkspaceRequest {
+    #[validate(length(min = 1, max = 100, message = "Must be between 1 and 100 characters long"))]
+    name: String,
+}
+
+#[tracing::instrument(name = "Creating a workspace", skip(ctx, user, req), fields(user_id = %user.user_id))]
+async fn create_workspace(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    ValidatedJson(req): ValidatedJson<WorkspaceRequest>,
+) -> Result<(StatusCode, Json<WorkspaceResponse>)> {
+    let txn = ctx.db.begin().await?;
+    let workspace = workspace::ActiveModel {
+        name: ActiveValue::Set(req.name),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    let member = workspace_member::ActiveModel {
+        workspace_id: ActiveValue::Set(workspace.id),
+        user_id: ActiveValue::Set(user.user_id),
+        role: ActiveValue::Set(WorkspaceRole::Owner),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    txn.commit().await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(WorkspaceResponse::new(workspace, &member)),
+    ))
+}
+
+/// Deletes a workspace along with its projects, their tasks and the invitations to it.
+#[tracing::instrument(name = "Deleting a workspace", skip(ctx, user), fields(user_id = %user.user_id))]
+async fn delete_workspace(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode> {
+    let txn = ctx.db.begin().await?;
+    let member = owner_membership(&txn, &user, id).await?;
+    if is_personal(id, member.user_id) {
+        return Err(personal_workspace().into());
+    }
+    delete_workspaces(&txn, Condition::all().add(workspace::Column::Id.eq(id))).await?;
+    txn.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Deletes the workspaces matching `condition` and everything in them.
+async fn delete_workspaces<C: ConnectionTrait>(db: &C, condition: Condition) -> Result<()> {
+    let workspaces = SubQuery::select()
+        .column(workspace::Column::Id)
+        .from(workspace::Entity)
+        .cond_where(condition)
+        .to_owned();
+
+    // The foreign keys cascade too, but SQLite only enforces them when foreign keys are switched
+    // on for the connection, so don't rely on it.
+    task::Entity::delete_many()
+        .filter(
+            task::Column::ProjectId.in_subquery(
+                SubQuery::select()
+                    .column(project::Column::Id)
+                    .from(project::Entity)
+                    .and_where(project::Column::WorkspaceId.in_subquery(workspaces.clone()))
+                    .to_owned(),
+            ),
+        )
+        .exec(db)
+        .await?;
+    project::Entity::delete_many()
+        .filter(project::Column::WorkspaceId.in_subquery(workspaces.clone()))
+        .exec(db)
+        .await?;
+    workspace_invitation::Entity::delete_many()
+        .filter(workspace_invitation::Column::WorkspaceId.in_subquery(workspaces.clone()))
+        .exec(db)
+        .await?;
+    workspace_member::Entity::delete_many()
+        .filter(workspace_member::Column::WorkspaceId.in_subquery(workspaces.clone()))
+        .exec(db)
+        .await?;
+    workspace::Entity::delete_many()
+        .filter(workspace::Column::Id.in_subquery(workspaces))
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+/// Takes `user_id` out of every workspace and deletes their personal one, for when the account is
+/// deleted. Refused while they are the only owner of a shared workspace, which they have to hand
+/// over or delete first.
+///
+/// The projects they created in shared workspaces stay there, and pass to one of its owners.
+pub(super) async fn leave_all<C: ConnectionTrait>(db: &C, user_id: Uuid) -> Result<()> {
+    let owned = workspace_member::Entity::find()
+        .filter(workspace_member::Column::UserId.eq(user_id))
+        .filter(workspace_member::Column::Role.eq(WorkspaceRole::Owner))
+        .filter(workspace_member::Column::WorkspaceId.ne(user_id))
+        .all(db)
+        .await?;
+    for member in owned {
+        if owner_count(db, member.workspace_id).await? <= 1 {
+            return Err(HttpError::conflict(
+                Some("last_owner".to_owned()),
+                Some(
+                    "You are the only owner of a workspace. Make someone else an owner or delete \
+                     it first."
+                        .to_owned(),
+                ),
+            )
+            .into());
+        }
+    }
+
+    let created = project::Entity::find()
+        .filter(project::Column::UserId.eq(user_id))
+        .filter(project::Column::WorkspaceId.ne(user_id))
+        .all(db)
+        .await?;
+    let shared: BTreeSet<Uuid> = created.iter().map(|project| project.workspace_id).collect();
+    for workspace_id in shared {
+        let heir = workspace_member::Entity::find()
+            .filter(workspace_member::Column::WorkspaceId.eq(workspace_id))
+            .filter(workspace_member::Column::Role.eq(WorkspaceRole::Owner))
+            .filter(workspace_member::Column::UserId.ne(user_id))
+            .order_by_asc(workspace_member::Column::CreatedAt)
+            .one(db)
+            .await?
+            .ok_or_else(|| {
+                tracing::error!(%workspace_id, "shared workspace without another owner");
+                HttpError::internal_server_errer(None, None)
+            })?;
+        project::Entity::update_many()
+            .col_expr(project::Column::UserId, Expr::value(heir.user_id))
+            .filter(project::Column::UserId.eq(user_id))
+            .filter(project::Column::WorkspaceId.eq(workspace_id))
+            .exec(db)
+            .await?;
+    }
+
+    delete_workspaces(db, Condition::all().add(workspace::Column::Id.eq(user_id))).await?;
+    workspace_member::Entity::delete_many()
+        .filter(workspace_member::Column::UserId.eq(user_id))
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MemberResponse {
+    pub user_id: Uuid,
+    pub username: String,
+    pub role: WorkspaceRole,
+    /// When they joined.
+    pub created_at: DateTimeWithTimeZone,
+}
+
+async fn list_members(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Vec<MemberResponse>>> {
+    membership(&ctx.db, &user, id).await?;
+    let members = workspace_member::Entity::find()
+        .filter(workspace_member::Column::WorkspaceId.eq(id))
+        .find_also_related(user::Entity)
+        .order_by_asc(workspace_member::Column::CreatedAt)
+        .all(&ctx.db)
+        .await?;
+
+    Ok(Json(
+        members
+            .into_iter()
+            .filter_map(|(member, account)| {
+                Some(MemberResponse {
+                    user_id: member.user_id,
+                    username: account?.username,
+                    role: member.role,
+                    created_at: member.created_at,
+                })
+            })
+            .collect(),
+    ))
+}
+
+async fn find_member<C: ConnectionTrait>(
+    db: &C,
+    workspace_id: Uuid,
+    user_id: Uuid,
+) -> Result<workspace_member::Model> {
+    Ok(workspace_member::Entity::find_membership(workspace_id, user_id)
+        .one(db)
+        .await?
+        .ok_or_else(|| HttpError::not_found(None, None))?)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetMemberRoleRequest {
+    role: WorkspaceRole,
+}
+
+#[tracing::instrument(name = "Changing the role of a member", skip(ctx, user), fields(user_id = %user.user_id))]
+async fn set_member_role(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path((id, user_id)): Path<(Uuid, Uuid)>,
+    Json(req): Json<SetMemberRoleRequest>,
+) -> Result<StatusCode> {
+    let txn = ctx.db.begin().await?;
+    owner_membership(&txn, &user, id).await?;
+    let member = find_member(&txn, id, user_id).await?;
+    if req.role != WorkspaceRole::Owner {
+        keep_an_owner(&txn, &member).await?;
+    }
+    workspace_member::ActiveModel {
+        id: ActiveValue::Unchanged(member.id),
+        role: ActiveValue::Set(req.role),
+        ..ActiveModelTrait::default()
+    }
+    .update(&txn)
+    .await?;
+    txn.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Owners can remove anyone, and everyone can remove themselves to leave the workspace.
+#[tracing::instrument(name = "Removing a member", skip(ctx, user), fields(user_id = %user.user_id))]
+async fn remove_member(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path((id, user_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode> {
+    let txn = ctx.db.begin().await?;
+    if user_id == user.user_id {
+        membership(&txn, &user, id).await?;
+    } else {
+        owner_membership(&txn, &user, id).await?;
+    }
+    let member = find_member(&txn, id, user_id).await?;
+    keep_an_owner(&txn, &member).await?;
+    workspace_member::Entity::delete_by_id(member.id)
+        .exec(&txn)
+        .await?;
+    txn.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// An invitation as listed. The token is only ever shown when it is created.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InvitationResponse {
+    pub id: Uuid,
+    pub workspace_id: Uuid,
+    /// Left out for invitations that anyone with the link can accept.
+    pub email: Option<String>,
+    pub role: WorkspaceRole,
+    pub expires_at: DateTimeWithTimeZone,
+    pub created_at: DateTimeWithTimeZone,
+}
+
+impl From<workspace_invitation::Model> for InvitationResponse {
+    fn from(invitation: workspace_invitation::Model) -> Self {
+        Self {
+            id: invitation.id,
+            workspace_id: invitation.workspace_id,
+            email: invitation.email,
+            role: invitation.role,
+            expires_at: invitation.expires_at,
+            created_at: invitation.created_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreatedInvitationResponse {
+    #[serde(flatten)]
+    pub details: InvitationResponse,
+    /// For `POST /workspaces/join`. It is not stored, so this is the only chance to copy it.
+    pub token: String,
+    /// Where the frontend accepts the invitation, to pass on to whoever is invited.
+    pub link: String,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct InvitationRequest {
+    /// Left out for a link that anyone who has it can use.
+    #[serde(default)]
+    #[validate(email(message = "Must be a valid email address"))]
+    email: Option<String>,
+    role: WorkspaceRole,
+}
+
+async fn list_invitations(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+) -> Result<Json<Vec<InvitationResponse>>> {
+    owner_membership(&ctx.db, &user, id).await?;
+    let invitations = workspace_invitation::Entity::find()
+        .filter(workspace_invitation::Column::WorkspaceId.eq(id))
+        .filter(workspace_invitation::Column::UsedAt.is_null())
+        .filter(workspace_invitation::Column::ExpiresAt.gt(DateTimeWithTimeZone::from(Utc::now())))
+        .order_by_asc(workspace_invitation::Column::CreatedAt)
+        .all(&ctx.db)
+        .await?;
+    Ok(Json(invitations.into_iter().map(Into::into).collect()))
+}
+
+/// Invites someone to the workspace. With an `email`, the invitation is also sent there.
+#[tracing::instrument(name = "Inviting to a workspace", skip(ctx, user, req), fields(user_id = %user.user_id))]
+async fn invite(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path(id): Path<Uuid>,
+    ValidatedJson(req): ValidatedJson<InvitationRequest>,
+) -> Result<(StatusCode, Json<CreatedInvitationResponse>)> {
+    owner_membership(&ctx.db, &user, id).await?;
+    // Everything in it goes when the account does, so nobody else may come to rely on it.
+    if is_personal(id, user.user_id) {
+        return Err(HttpError::conflict(
+            Some("personal_workspace".to_owned()),
+            Some("A personal workspace can't be shared, create a workspace instead.".to_owned()),
+        )
+        .into());
+    }
+    // Owners are made by promoting members, so that a leaked link can't hand out ownership.
+    if req.role == WorkspaceRole::Owner {
+        return Err(HttpError::unprocessable_entity(vec![ValidationErrorItem {
+            loc: vec!["body".to_owned(), "role".to_owned()],
+            msg: "Invitations can't make owners".to_owned(),
+            ty: "value_error.role".to_owned(),
+        }])
+        .into());
+    }
+    let workspace = workspace::Entity::find_by_id(id)
+        .one(&ctx.db)
+        .await?
+        .ok_or_else(|| HttpError::not_found(None, None))?;
+
+    let ttl = ctx.settings.auth.workspace_invitation_ttl_secs;
+    let token = generate_token();
+    let invitation = workspace_invitation::ActiveModel {
+        workspace_id: ActiveValue::Set(id),
+        email: ActiveValue::Set(req.email),
+        role: ActiveValue::Set(req.role),
+        token_hash: ActiveValue::Set(hash_token(&token)),
+        invited_by: ActiveValue::Set(user.user_id),
+        expires_at: ActiveValue::Set((Utc::now() + Duration::seconds(ttl)).into()),
+        ..Default::default()
+    }
+    .insert(&ctx.db)
+    .await?;
+    let link = link(&ctx, "join-workspace", &token)?;
+
+    // The invitation is saved and its link returned either way, so a mail that can't be sent is
+    // only logged.
+    if let Some(email) = &invitation.email {
+        let sent = ctx
+            .mailer
+            .send(Email {
+                to: email.clone(),
+                subject: format!("You are invited to {}", workspace.name),
+                body: format!(
+                    "Hi,\n\nYou are invited to share the projects of {}. To join, open this link \
+                     and log in with this email address:\n\n{}\n\nThe link is valid for {} days.",
+                    workspace.name,
+                    link,
+                    ttl / (24 * 3600)
+                ),
+            })
+            .await;
+        if let Err(e) = sent {
+            tracing::error!("could not send a workspace invitation: {}", e);
+        }
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedInvitationResponse {
+            details: invitation.into(),
+            token,
+            link,
+        }),
+    ))
+}
+
+async fn revoke_invitation(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Path((id, invitation_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode> {
+    owner_membership(&ctx.db, &user, id).await?;
+    let deleted = workspace_invitation::Entity::delete_many()
+        .filter(workspace_invitation::Column::Id.eq(invitation_id))
+        .filter(workspace_invitation::Column::WorkspaceId.eq(id))
+        .exec(&ctx.db)
+        .await?;
+    if deleted.rows_affected == 0 {
+        return Err(HttpError::not_found(None, None).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JoinRequest {
+    token: String,
+}
+
+fn invalid_invitation() -> HttpError {
+    HttpError::bad_request(
+        Some("invalid_invitation".to_owned()),
+        Some("The invitation is invalid, has expired or has already been used.".to_owned()),
+    )
+}
+
+/// Accepts an invitation and makes the caller a member.
+///
+/// An invitation sent by email only counts for the account with that address, once it is
+/// verified, and is used up by the conditional update that marks it, so two requests racing with
+/// it can't both succeed.
+#[tracing::instrument(name = "Joining a workspace", skip(ctx, user, req), fields(user_id = %user.user_id))]
+async fn join_workspace(
+    Extension(ctx): Extension<Server>,
+    user: AuthUser,
+    Json(req): Json<JoinRequest>,
+) -> Result<Json<WorkspaceResponse>> {
+    let now = DateTimeWithTimeZone::from(Utc::now());
+    let txn = ctx.db.begin().await?;
+    let invitation = workspace_invitation::Entity::find()
+        .filter(workspace_invitation::Column::TokenHash.eq(hash_token(&req.token)))
+        .filter(workspace_invitation::Column::UsedAt.is_null())
+        .filter(workspace_invitation::Column::ExpiresAt.gt(now))
+        .one(&txn)
+        .await?
+        .ok_or_else(invalid_invitation)?;
+
+    if workspace_member::Entity::find_membership(invitation.workspace_id, user.user_id)
+        .one(&txn)
+        .await?
+        .is_some()
+    {
+        return Err(HttpError::conflict(
+            Some("already_member".to_owned()),
+            Some("You are already a member of this workspace.".to_owned()),
+        )
+        .into());
+    }
+
+    if let Some(email) = &invitation.email {
+        let account = user::Entity::find_by_id(user.user_id)
+            .one(&txn)
+            .await?
+            .ok_or_else(|| HttpError::unauthorized(None, None))?;
+        if account.email_verified_at.is_none() || !account.email.eq_ignore_ascii_case(email) {
+            return Err(HttpError::forbidden(
+                Some("wrong_account".to_owned()),
+                Some(
+                    "The invitation was sent to another email address, or yours isn't verified \
+                     yet."
+                        .to_owned(),
+                ),
+            )
+            .into());
+        }
+        let used = workspace_invitation::Entity::update_many()
+            .col_expr(workspace_invitation::Column::UsedAt, Expr::value(Some(now)))
+            .filter(workspace_invitation::Column::Id.eq(invitation.id))
+            .filter(workspace_invitation::Column::UsedAt.is_null())
+            .exec(&txn)
+            .await?;
+        if used.rows_affected != 1 {
+            return Err(invalid_invitation().into());
+        }
+    }
+
+    let member = workspace_member::ActiveModel {
+        workspace_id: ActiveValue::Set(invitation.workspace_id),
+        user_id: ActiveValue::Set(user.user_id),
+        role: ActiveValue::Set(invitation.role),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    let workspace = workspace::Entity::find_by_id(invitation.workspace_id)
+        .one(&txn)
+        .await?
+        .ok_or_else(invalid_invitation)?;
+    txn.commit().await?;
+
+    Ok(Json(WorkspaceResponse::new(workspace, &member)))
+}
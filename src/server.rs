@@ -1,45 +1,207 @@
-use crate::settings::Settings;
+use crate::auth::lockout::{self, Lockout};
+use crate::auth::password::PasswordPolicy;
+use crate::clock::{Clock, SystemClock};
+use crate::database::DatabaseHealth;
+use crate::mail::{self, Mailer};
+use crate::settings::{ServerSettings, Settings};
+use crate::storage::{self, BlobStore};
 use sea_orm::DatabaseConnection;
-use std::{net::SocketAddr};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use anyhow::Context;
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use tokio_util::sync::CancellationToken;
 use tower::ServiceBuilder;
 use tower_http::add_extension::AddExtensionLayer;
 use tower_http::trace::TraceLayer;
-use tower_http::cors::{Any, CorsLayer, Origin};
-use axum::http::Method;
-use crate::router::api_router;
+use crate::router::{api_router, starting_router};
+use crate::telemetry::{make_request_span, request_id};
+use axum::{middleware, Router};
 
 #[derive(Clone)]
 pub struct Server {
     pub settings: Arc<Settings>,
     pub db: DatabaseConnection,
+    pub db_health: DatabaseHealth,
+    pub password_policy: Arc<PasswordPolicy>,
+    /// Counts failed logins and turns away clients that have had too many.
+    pub lockout: Arc<Lockout>,
+    /// Where uploads such as avatars go.
+    pub blobs: Arc<dyn BlobStore>,
+    pub mailer: Arc<dyn Mailer>,
+    /// Where two-factor codes get the time from, so that tests can fix it.
+    pub clock: Arc<dyn Clock>,
+    /// Cancelled when the server starts shutting down. Background work spawned by the server
+    /// should watch it and stop, so that the database can be closed after the drain.
+    pub shutdown: CancellationToken,
 }
 
-pub async fn serve(settings: Settings, db: DatabaseConnection) -> anyhow::Result<()> {
-    let address: SocketAddr = format!(
-        "{}:{}",
-        settings.server.host, settings.server.port
-    ).parse().context("could not parse address")?;
+impl Server {
+    /// A server around an already established connection.
+    pub fn new(settings: Settings, db: DatabaseConnection) -> anyhow::Result<Self> {
+        let db_health = DatabaseHealth::default();
+        db_health.set_connected();
+        Self::with_health(settings, db, db_health)
+    }
 
-    let app = api_router().layer(
+    /// Fails if the files the settings point to can't be loaded.
+    pub fn with_health(
+        settings: Settings,
+        db: DatabaseConnection,
+        db_health: DatabaseHealth,
+    ) -> anyhow::Result<Self> {
+        let password_policy = PasswordPolicy::from_settings(&settings.auth.password)?;
+        let lockout = Lockout::new(
+            lockout::store_from_settings(&settings.auth.lockout, &db),
+            settings.auth.lockout.clone(),
+        );
+        let blobs = storage::from_settings(&settings.storage);
+        let mailer = mail::from_settings(&settings.mail)?;
+        Ok(Self {
+            settings: Arc::new(settings),
+            db,
+            db_health,
+            password_policy: Arc::new(password_policy),
+            lockout: Arc::new(lockout),
+            blobs,
+            mailer,
+            clock: Arc::new(SystemClock),
+            shutdown: CancellationToken::new(),
+        })
+    }
+}
+
+/// The API with all of its middleware.
+pub fn app(server: Server) -> anyhow::Result<Router> {
+    let cors = server.settings.server.cors.layer().map_err(anyhow::Error::msg)?;
+    Ok(api_router().layer(
         ServiceBuilder::new()
-            .layer(AddExtensionLayer::new(Server {
-                settings: Arc::new(settings),
-                db
-            }))
+            .layer(AddExtensionLayer::new(server))
+            // Outside of the trace layer, so that the id is in the request span.
+            .layer(middleware::from_fn(request_id))
             // Enables logging. Use `RUST_LOG=tower_http=debug`
-            .layer(TraceLayer::new_for_http())
-            .layer(
-                CorsLayer::new()
-                    .allow_origin(Origin::exact("http://localhost:3000".parse().unwrap()))
-                    .allow_methods(vec![Method::GET, Method::POST, Method::PUT, Method::DELETE])
-                    .allow_headers(Any),
-            ),
-    );
-    axum::Server::bind(&address)
-        .serve(app.into_make_service())
-        .await
-        .context("error running HTTP server")
-
-}
\ No newline at end of file
+            .layer(TraceLayer::new_for_http().make_span_with(make_request_span))
+            .layer(cors),
+    ))
+}
+
+/// Resolves once the process is asked to stop, with Ctrl+C or, on Unix, `SIGTERM`.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install the Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install the SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Serves the API until `shutdown` resolves, then stops accepting connections and waits up to
+/// `server.shutdown_timeout_secs` for the requests in flight to finish.
+pub async fn serve_with_shutdown<F>(server: Server, shutdown: F) -> anyhow::Result<()>
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    let settings = server.settings.clone();
+    let drain_timeout = settings.server.shutdown_timeout();
+
+    let handle = Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        let token = server.shutdown.clone();
+        async move {
+            shutdown.await;
+            tracing::info!("shutting down, draining in-flight requests");
+            token.cancel();
+            // Connections still open after the timeout are dropped.
+            handle.graceful_shutdown(Some(drain_timeout));
+        }
+    });
+    tokio::spawn({
+        let handle = handle.clone();
+        let tls = settings.server.tls.is_some();
+        async move {
+            if let Some(address) = handle.listening().await {
+                tracing::info!(%address, tls, "listening");
+            }
+        }
+    });
+
+    bind(&settings.server, handle, app(server)?).await
+}
+
+/// Serves `app` on the configured address, with TLS if it is set up, until `handle` stops it.
+async fn bind(settings: &ServerSettings, handle: Handle, app: Router) -> anyhow::Result<()> {
+    let address = settings.address().map_err(anyhow::Error::msg)?;
+    match &settings.tls {
+        Some(tls) => {
+            let config = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+                .await
+                .with_context(|| {
+                    format!(
+                        "could not load the TLS certificate {} and key {}",
+                        tls.cert_path.display(),
+                        tls.key_path.display()
+                    )
+                })?;
+            axum_server::bind_rustls(address, config)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+        }
+        None => {
+            axum_server::bind(address)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+        }
+    }
+    .context("error running HTTP server")
+}
+
+/// Answers the health checks from [`DatabaseHealth`] while `starting` runs, typically connecting
+/// to the database and migrating it, and hands back its result once the address is free again
+/// for [`serve`].
+pub async fn serve_while_starting<T, F>(
+    settings: &Settings,
+    db_health: DatabaseHealth,
+    starting: F,
+) -> anyhow::Result<T>
+where
+    F: std::future::Future<Output = anyhow::Result<T>>,
+{
+    let handle = Handle::new();
+    let app = starting_router().layer(AddExtensionLayer::new(db_health));
+    let probes = bind(&settings.server, handle.clone(), app);
+    tokio::pin!(probes, starting);
+    let result = tokio::select! {
+        result = &mut starting => result,
+        // Only ends early if it can't listen, which `serve` couldn't either.
+        served = &mut probes => {
+            served?;
+            anyhow::bail!("the HTTP server stopped while starting up");
+        }
+    };
+
+    handle.shutdown();
+    probes.await?;
+    result
+}
+
+/// Serves the API until the process receives Ctrl+C or `SIGTERM`.
+pub async fn serve(server: Server) -> anyhow::Result<()> {
+    serve_with_shutdown(server, shutdown_signal()).await
+}
@@ -1,71 +1,863 @@
 use config::{Config, ConfigError, File};
 use sea_orm::ConnectOptions;
-//use secrecy::{ExposeSecret, Secret};
+use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
-use serde_derive::Deserialize;
+use serde::{Deserialize as _, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use axum::http::{header::HeaderName, HeaderValue, Method};
 use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tower_http::cors::{Any, CorsLayer, Origin};
+use url::Url;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(unused)]
 pub struct Settings {
+    /// Taken from `RUN_MODE` rather than the configuration files.
+    #[serde(skip_deserializing)]
+    pub environment: Environment,
     pub database: DatabaseSettings,
     pub server: ServerSettings,
+    pub auth: AuthSettings,
+    #[serde(default)]
+    pub storage: StorageSettings,
+    #[serde(default)]
+    pub mail: MailSettings,
 }
 
-#[derive(serde::Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 #[allow(unused)]
 pub struct ServerSettings {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub host: String,
+    /// How long to let in-flight requests finish after a shutdown signal before dropping them.
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    #[serde(default)]
+    pub cors: CorsSettings,
+    /// Serve HTTPS directly instead of plain HTTP.
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
+    /// Take the client address from the last `X-Forwarded-For` entry instead of the connection.
+    /// Only turn this on behind a reverse proxy that sets the header, or clients can pick their
+    /// own address.
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+impl ServerSettings {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    /// The address to listen on.
+    pub fn address(&self) -> Result<SocketAddr, String> {
+        let ip: IpAddr = self.host.parse().map_err(|_| {
+            format!(
+                "server.host: `{}` is not an IP address, use e.g. 0.0.0.0 or 127.0.0.1",
+                self.host
+            )
+        })?;
+        Ok(SocketAddr::new(ip, self.port))
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        if self.port == 0 {
+            problems.push("server.port must not be 0".to_owned());
+        }
+        if let Err(e) = self.address() {
+            problems.push(e);
+        }
+        if let Err(e) = self.cors.layer() {
+            problems.push(e);
+        }
+        if let Some(tls) = &self.tls {
+            for (key, path) in [("cert_path", &tls.cert_path), ("key_path", &tls.key_path)] {
+                if !path.is_file() {
+                    problems.push(format!(
+                        "server.tls.{}: `{}` does not exist or is not a file",
+                        key,
+                        path.display()
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Which browser origins may call the API. Each list can be written as a YAML list or, which is
+/// handier in environment variables, as a comma separated string. `*` allows anything.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct CorsSettings {
+    /// Origins such as `https://projects.example.com`.
+    #[serde(deserialize_with = "string_or_list")]
+    pub allowed_origins: Vec<String>,
+    #[serde(deserialize_with = "string_or_list")]
+    pub allowed_methods: Vec<String>,
+    #[serde(deserialize_with = "string_or_list")]
+    pub allowed_headers: Vec<String>,
+    /// Allow cookies and `Authorization` headers on cross-origin requests. Browsers refuse this
+    /// together with a wildcard, so it can't be combined with `*`.
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response. `0` leaves it to the browser.
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec!["http://localhost:3000".to_owned()],
+            allowed_methods: ["GET", "POST", "PUT", "PATCH", "DELETE"]
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            allowed_headers: vec!["authorization".to_owned(), "content-type".to_owned()],
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+/// Whether `list` is the `*` wildcard. A `*` mixed in with other values is an error.
+fn is_wildcard(key: &str, list: &[String]) -> Result<bool, String> {
+    match list {
+        [only] if only == "*" => Ok(true),
+        _ if list.iter().any(|v| v == "*") => Err(format!(
+            "server.cors.{}: `*` has to be the only entry",
+            key
+        )),
+        _ => Ok(false),
+    }
+}
+
+impl CorsSettings {
+    /// The CORS layer these settings describe, or what is wrong with them.
+    pub fn layer(&self) -> Result<CorsLayer, String> {
+        let mut layer = CorsLayer::new().allow_credentials(self.allow_credentials);
+        let mut wildcards = Vec::new();
+
+        if is_wildcard("allowed_origins", &self.allowed_origins)? {
+            wildcards.push("allowed_origins");
+            layer = layer.allow_origin(Any);
+        } else {
+            let origins = self
+                .allowed_origins
+                .iter()
+                .map(|origin| parse_origin(origin))
+                .collect::<Result<Vec<_>, _>>()?;
+            layer = layer.allow_origin(Origin::list(origins));
+        }
+
+        if is_wildcard("allowed_methods", &self.allowed_methods)? {
+            wildcards.push("allowed_methods");
+            layer = layer.allow_methods(Any);
+        } else {
+            let methods = self
+                .allowed_methods
+                .iter()
+                .map(|method| {
+                    Method::from_bytes(method.trim().to_uppercase().as_bytes()).map_err(|_| {
+                        format!("server.cors.allowed_methods: `{}` is not an HTTP method", method)
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            layer = layer.allow_methods(methods);
+        }
+
+        if is_wildcard("allowed_headers", &self.allowed_headers)? {
+            wildcards.push("allowed_headers");
+            layer = layer.allow_headers(Any);
+        } else {
+            let headers = self
+                .allowed_headers
+                .iter()
+                .map(|header| {
+                    HeaderName::from_bytes(header.trim().as_bytes()).map_err(|_| {
+                        format!("server.cors.allowed_headers: `{}` is not a header name", header)
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            layer = layer.allow_headers(headers);
+        }
+
+        if self.allow_credentials && !wildcards.is_empty() {
+            return Err(format!(
+                "server.cors.allow_credentials can't be combined with `*` in {}",
+                wildcards.join(", ")
+            ));
+        }
+
+        if self.max_age_secs > 0 {
+            layer = layer.max_age(Duration::from_secs(self.max_age_secs));
+        }
+        Ok(layer)
+    }
+}
+
+/// Browsers send the origin as `scheme://host[:port]`, so anything else would never match.
+fn parse_origin(origin: &str) -> Result<HeaderValue, String> {
+    let origin = origin.trim().trim_end_matches('/');
+    let invalid = || {
+        format!(
+            "server.cors.allowed_origins: `{}` is not an origin like https://example.com",
+            origin
+        )
+    };
+
+    let url = Url::parse(origin).map_err(|_| invalid())?;
+    if !matches!(url.scheme(), "http" | "https") || url.origin().ascii_serialization() != origin {
+        return Err(invalid());
+    }
+    HeaderValue::from_str(origin).map_err(|_| invalid())
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TlsSettings {
+    /// PEM encoded certificate chain.
+    pub cert_path: PathBuf,
+    /// PEM encoded private key.
+    pub key_path: PathBuf,
+}
+
+/// Accepts either a list of strings or a single comma separated string.
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        List(Vec<String>),
+        String(String),
+    }
+
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::List(list) => list,
+        StringOrList::String(s) => s
+            .split(',')
+            .map(|v| v.trim().to_owned())
+            .filter(|v| !v.is_empty())
+            .collect(),
+    })
+}
+
+/// Writes a secret as `********` so it can't leak through `--print-config`. An empty secret is
+/// left empty, to show that it isn't set.
+fn serialize_secret<S: Serializer>(secret: &Secret<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(if secret.expose_secret().is_empty() {
+        ""
+    } else {
+        "********"
+    })
+}
+
+/// The placeholder secret in `configuration/default.yaml`.
+const PLACEHOLDER_JWT_SECRET: &str = "change-me-in-production";
+
+#[derive(Deserialize, Serialize, Debug)]
+#[allow(unused)]
+pub struct AuthSettings {
+    #[serde(serialize_with = "serialize_secret")]
+    jwt_secret: Secret<String>,
+    /// How long an access token is valid for, in seconds.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub access_token_ttl_secs: i64,
+    /// How long a refresh token is valid for, in seconds. Each refresh starts the clock again.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub refresh_token_ttl_secs: i64,
+    #[serde(default)]
+    pub password: PasswordSettings,
+    /// How long a password reset link is valid for, in seconds.
+    #[serde(
+        default = "default_password_reset_ttl_secs",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub password_reset_ttl_secs: i64,
+    /// How long an email verification link is valid for, in seconds.
+    #[serde(
+        default = "default_email_verification_ttl_secs",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub email_verification_ttl_secs: i64,
+    /// How long an invitation to a workspace is valid for, in seconds.
+    #[serde(
+        default = "default_workspace_invitation_ttl_secs",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub workspace_invitation_ttl_secs: i64,
+    /// The name authenticator apps show next to two-factor codes.
+    #[serde(default = "default_totp_issuer")]
+    pub totp_issuer: String,
+    #[serde(default)]
+    pub lockout: LockoutSettings,
+}
+
+fn default_totp_issuer() -> String {
+    "Home Projects".to_owned()
+}
+
+fn default_password_reset_ttl_secs() -> i64 {
+    60 * 60
+}
+
+fn default_email_verification_ttl_secs() -> i64 {
+    24 * 60 * 60
+}
+
+fn default_workspace_invitation_ttl_secs() -> i64 {
+    7 * 24 * 60 * 60
+}
+
+/// The rules new passwords have to meet.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct PasswordSettings {
+    pub min_length: usize,
+    /// Hashing gets slower the longer the password, so there is a cap.
+    pub max_length: usize,
+    /// A file of known breached passwords, one per line, that are refused.
+    pub breached_passwords_file: Option<PathBuf>,
+}
+
+impl Default for PasswordSettings {
+    fn default() -> Self {
+        Self {
+            min_length: 10,
+            max_length: 128,
+            breached_passwords_file: None,
+        }
+    }
+}
+
+/// Where failed login attempts are counted.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttemptBackend {
+    /// In the memory of each instance.
+    Memory,
+    /// In the database, shared by every instance.
+    Database,
+}
+
+/// How failed logins are slowed down. After the free failures, each failure doubles the wait
+/// before the next attempt, starting at `base_delay_secs`, and at the maximum the account or
+/// address is locked for `lockout_secs`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct LockoutSettings {
+    pub backend: AttemptBackend,
+    pub account_free_failures: u32,
+    pub account_max_failures: u32,
+    /// Higher than for accounts, as several people can share an address.
+    pub ip_free_failures: u32,
+    pub ip_max_failures: u32,
+    pub base_delay_secs: u64,
+    pub lockout_secs: u64,
+    /// Failures are forgotten this long after the last one.
+    pub window_secs: u64,
+}
+
+impl Default for LockoutSettings {
+    fn default() -> Self {
+        Self {
+            backend: AttemptBackend::Memory,
+            account_free_failures: 3,
+            account_max_failures: 10,
+            ip_free_failures: 10,
+            ip_max_failures: 100,
+            base_delay_secs: 1,
+            lockout_secs: 15 * 60,
+            window_secs: 15 * 60,
+        }
+    }
+}
+
+impl LockoutSettings {
+    fn validate(&self, problems: &mut Vec<String>) {
+        for (kind, free, max) in [
+            ("account", self.account_free_failures, self.account_max_failures),
+            ("ip", self.ip_free_failures, self.ip_max_failures),
+        ] {
+            if max == 0 || free > max {
+                problems.push(format!(
+                    "auth.lockout.{}_max_failures must be at least 1 and at least {}_free_failures",
+                    kind, kind
+                ));
+            }
+        }
+        if self.base_delay_secs == 0 || self.base_delay_secs > self.lockout_secs {
+            problems.push(
+                "auth.lockout.base_delay_secs must be at least 1 and at most lockout_secs"
+                    .to_owned(),
+            );
+        }
+        if self.window_secs == 0 {
+            problems.push("auth.lockout.window_secs must be positive".to_owned());
+        }
+    }
+}
+
+impl AuthSettings {
+    pub fn jwt_secret(&self) -> &[u8] {
+        self.jwt_secret.expose_secret().as_bytes()
+    }
+
+    fn validate(&self, environment: Environment, problems: &mut Vec<String>) {
+        let secret = self.jwt_secret.expose_secret();
+        if secret.is_empty() {
+            problems.push("auth.jwt_secret must not be empty".to_owned());
+        } else if environment == Environment::Production
+            && (secret == PLACEHOLDER_JWT_SECRET || secret.len() < 32)
+        {
+            problems.push(
+                "auth.jwt_secret must be set to a random value of at least 32 characters in \
+                 production, e.g. with APP__AUTH__JWT_SECRET"
+                    .to_owned(),
+            );
+        }
+        if self.access_token_ttl_secs <= 0 {
+            problems.push("auth.access_token_ttl_secs must be positive".to_owned());
+        }
+        if self.refresh_token_ttl_secs <= 0 {
+            problems.push("auth.refresh_token_ttl_secs must be positive".to_owned());
+        }
+        if self.password_reset_ttl_secs <= 0 {
+            problems.push("auth.password_reset_ttl_secs must be positive".to_owned());
+        }
+        if self.email_verification_ttl_secs <= 0 {
+            problems.push("auth.email_verification_ttl_secs must be positive".to_owned());
+        }
+        if self.workspace_invitation_ttl_secs <= 0 {
+            problems.push("auth.workspace_invitation_ttl_secs must be positive".to_owned());
+        }
+        if self.totp_issuer.is_empty() || self.totp_issuer.contains(':') {
+            problems.push("auth.totp_issuer must not be empty or contain `:`".to_owned());
+        }
+        if self.password.min_length == 0 || self.password.min_length > self.password.max_length {
+            problems.push(
+                "auth.password.min_length must be at least 1 and at most max_length".to_owned(),
+            );
+        }
+        self.lockout.validate(problems);
+        if let Some(path) = &self.password.breached_passwords_file {
+            if !path.is_file() {
+                problems.push(format!(
+                    "auth.password.breached_passwords_file: `{}` does not exist or is not a file",
+                    path.display()
+                ));
+            }
+        }
+    }
+}
+
+/// Where uploaded files are kept.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Files below `storage.path` on the local disk.
+    Local,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct StorageSettings {
+    pub backend: StorageBackend,
+    pub path: PathBuf,
+    /// The largest avatar image accepted, in bytes.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_avatar_bytes: usize,
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Local,
+            path: PathBuf::from("uploads"),
+            max_avatar_bytes: 2 * 1024 * 1024,
+        }
+    }
+}
+
+impl StorageSettings {
+    fn validate(&self, problems: &mut Vec<String>) {
+        if self.path.as_os_str().is_empty() {
+            problems.push("storage.path must not be empty".to_owned());
+        } else if self.path.exists() && !self.path.is_dir() {
+            problems.push(format!(
+                "storage.path: `{}` is not a directory",
+                self.path.display()
+            ));
+        }
+        if self.max_avatar_bytes == 0 {
+            problems.push("storage.max_avatar_bytes must be positive".to_owned());
+        }
+    }
+}
+
+/// How emails are delivered.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailBackend {
+    Smtp,
+    /// Each email is written to a file below `mail.file_dir`, for development and tests.
+    File,
+    /// Emails are only logged. Links in them grant access, so this is for development only.
+    Log,
+}
+
+/// How the connection to the SMTP server is secured.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// TLS from the start, usually on port 465.
+    Tls,
+    /// Upgraded with `STARTTLS`, usually on port 587.
+    StartTls,
+    /// Plain text. Only for a relay on the same host.
+    None,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default = "empty_secret", serialize_with = "serialize_secret")]
+    password: Secret<String>,
+    pub security: SmtpSecurity,
+}
+
+fn empty_secret() -> Secret<String> {
+    Secret::new(String::new())
+}
+
+impl SmtpSettings {
+    pub fn password(&self) -> &str {
+        self.password.expose_secret()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(default)]
+pub struct MailSettings {
+    pub backend: MailBackend,
+    /// The sender, e.g. `Home Projects <no-reply@example.com>`.
+    pub from: String,
+    /// Where the links in emails point to, usually the frontend.
+    pub public_url: String,
+    pub file_dir: PathBuf,
+    pub smtp: Option<SmtpSettings>,
+}
+
+impl Default for MailSettings {
+    fn default() -> Self {
+        Self {
+            backend: MailBackend::Log,
+            from: "Home Projects <no-reply@localhost>".to_owned(),
+            public_url: "http://localhost:3000".to_owned(),
+            file_dir: PathBuf::from("mail"),
+            smtp: None,
+        }
+    }
+}
+
+impl MailSettings {
+    fn validate(&self, environment: Environment, problems: &mut Vec<String>) {
+        if let Err(e) = self.from.parse::<lettre::message::Mailbox>() {
+            problems.push(format!("mail.from: `{}` is not a valid sender: {}", self.from, e));
+        }
+        match Url::parse(&self.public_url) {
+            Ok(url) if url.cannot_be_a_base() => problems.push(format!(
+                "mail.public_url: `{}` can not have paths added to it",
+                self.public_url
+            )),
+            Ok(url) if environment == Environment::Production && is_loopback(&url) => {
+                problems.push(format!(
+                    "mail.public_url: `{}` points at this machine, so links in emails would not \
+                     work in production",
+                    self.public_url
+                ))
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(format!(
+                "mail.public_url: `{}` is not a valid URL: {}",
+                self.public_url, e
+            )),
+        }
+        if environment == Environment::Production && self.backend == MailBackend::Log {
+            // Reset and verification links would end up in the logs.
+            problems.push(
+                "mail.backend must not be `log` in production, e.g. set APP__MAIL__BACKEND=smtp"
+                    .to_owned(),
+            );
+        }
+        match (&self.backend, &self.smtp) {
+            (MailBackend::Smtp, None) => {
+                problems.push("mail.smtp must be set when mail.backend is `smtp`".to_owned())
+            }
+            (MailBackend::Smtp, Some(smtp)) => {
+                if smtp.host.is_empty() {
+                    problems.push("mail.smtp.host must not be empty".to_owned());
+                }
+                if smtp.port == 0 {
+                    problems.push("mail.smtp.port must not be 0".to_owned());
+                }
+            }
+            (MailBackend::File, _) if self.file_dir.as_os_str().is_empty() => {
+                problems.push("mail.file_dir must not be empty".to_owned())
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_loopback(url: &Url) -> bool {
+    match url.host() {
+        Some(url::Host::Domain(domain)) => {
+            domain == "localhost" || domain.ends_with(".localhost")
+        }
+        Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    }
+}
+
+/// The database servers we can run against.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseBackend {
+    Sqlite,
+    Postgres,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 #[allow(unused)]
 pub struct DatabaseSettings {
-    pub db_type: String,
+    pub db_type: DatabaseBackend,
+    /// The database name on Postgres, or the path of the database file on SQLite.
     pub db_name: String,
     pub username: String,
-    password: String,
+    #[serde(serialize_with = "serialize_secret")]
+    password: Secret<String>,
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub host: String,
     pub require_ssl: bool,
+    /// Apply pending schema migrations at startup. When this is off the server refuses to start
+    /// against an out of date schema instead.
+    pub run_migrations: bool,
+    #[serde(default)]
+    pub pool: PoolSettings,
+    #[serde(default)]
+    pub connect_retry: RetrySettings,
+}
+
+/// How to retry the initial database connection.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetrySettings {
+    /// Give up after this many attempts. `0` retries forever.
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+        }
+    }
+}
+
+impl RetrySettings {
+    /// How long to wait after the given failed attempt, counting from 1. The wait doubles after
+    /// every attempt until it reaches `max_backoff_ms`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+        Duration::from_millis(
+            self.initial_backoff_ms
+                .saturating_mul(factor)
+                .min(self.max_backoff_ms),
+        )
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct PoolSettings {
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub connect_timeout_secs: u64,
+    /// Connections idle for longer than this are closed. `0` keeps them open.
+    pub idle_timeout_secs: u64,
+    /// Log every SQL statement. Very noisy, but useful when debugging queries.
+    pub log_statements: bool,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            min_connections: 1,
+            connect_timeout_secs: 10,
+            idle_timeout_secs: 600,
+            log_statements: false,
+        }
+    }
 }
 
 impl DatabaseSettings {
-    pub fn database_connect(&self) ->  ConnectOptions{
-        if self.db_type == "sqlite" {
-            return ConnectOptions::new(format!("{}", self.db_name)) 
-        } else {
-            return ConnectOptions::new(format!("postgres://{}:{}@{}:{}/", self.username, self.password, self.host, self.port)) 
-        };
+    fn validate(&self, problems: &mut Vec<String>) {
+        if self.db_name.trim().is_empty() {
+            problems.push("database.db_name must not be empty".to_owned());
+        }
+        if self.db_type == DatabaseBackend::Postgres {
+            if self.port == 0 {
+                problems.push("database.port must not be 0".to_owned());
+            }
+            if self.host.is_empty() || url::Host::parse(&self.host).is_err() {
+                problems.push(format!(
+                    "database.host: `{}` is not a valid host name or IP address",
+                    self.host
+                ));
+            }
+        }
+        if let Err(e) = self.connection_url() {
+            problems.push(format!("database: can't build a connection URL: {}", e));
+        }
+        if self.pool.max_connections == 0 {
+            problems.push("database.pool.max_connections must be at least 1".to_owned());
+        }
+        if self.pool.min_connections > self.pool.max_connections {
+            problems.push(
+                "database.pool.min_connections can't be more than max_connections".to_owned(),
+            );
+        }
+        if self.connect_retry.initial_backoff_ms > self.connect_retry.max_backoff_ms {
+            problems.push(
+                "database.connect_retry.initial_backoff_ms can't be more than max_backoff_ms"
+                    .to_owned(),
+            );
+        }
+    }
+
+    /// The connection URL for the configured backend.
+    pub fn connection_url(&self) -> Result<String, url::ParseError> {
+        match self.db_type {
+            // `mode=rwc` creates the database file on first start.
+            DatabaseBackend::Sqlite => Ok(format!("sqlite:{}?mode=rwc", self.db_name)),
+            DatabaseBackend::Postgres => {
+                let mut url = Url::parse(&format!("postgres://{}:{}", self.host, self.port))?;
+                // Setting credentials only fails on URLs that can't have them, and postgres://
+                // URLs always can. `Url` takes care of percent-encoding them.
+                let _ = url.set_username(&self.username);
+                let password = self.password.expose_secret();
+                if !password.is_empty() {
+                    let _ = url.set_password(Some(password));
+                }
+                url.set_path(&self.db_name);
+                url.query_pairs_mut().append_pair(
+                    "sslmode",
+                    if self.require_ssl { "require" } else { "prefer" },
+                );
+                Ok(url.into())
+            }
+        }
+    }
+
+    pub fn database_connect(&self) -> Result<ConnectOptions, url::ParseError> {
+        let mut options = ConnectOptions::new(self.connection_url()?);
+        options
+            .max_connections(self.pool.max_connections)
+            .min_connections(self.pool.min_connections)
+            .connect_timeout(Duration::from_secs(self.pool.connect_timeout_secs))
+            .sqlx_logging(self.pool.log_statements);
+        if self.pool.idle_timeout_secs > 0 {
+            options.idle_timeout(Duration::from_secs(self.pool.idle_timeout_secs));
+        }
+        Ok(options)
     }
 }
+
 impl Settings {
+    /// Loads and validates the configuration for the environment named by `RUN_MODE`.
     pub fn new() -> Result<Settings, ConfigError> {
-        let run_mode = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
+        let settings = Self::load()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Loads the configuration without validating it, e.g. to show what was loaded.
+    pub fn load() -> Result<Settings, ConfigError> {
+        let environment = Environment::current()?;
         let s = Config::builder()
             // Start off by merging in the "default" configuration file
             .add_source(File::with_name("configuration/default"))
             // Add in the current environment file
-            // Default to 'development' env
             // Note that this file is _optional_
-            .add_source(File::with_name(&format!("configuration/{}", run_mode)).required(false))
+            .add_source(
+                File::with_name(&format!("configuration/{}", environment.as_str()))
+                    .required(false),
+            )
             .add_source(config::Environment::with_prefix("app").separator("__"))
             .build()?;
 
-        s.try_deserialize()
+        let mut settings: Settings = s.try_deserialize()?;
+        settings.environment = environment;
+        Ok(settings)
+    }
+
+    /// Checks everything that deserializing can't, reporting every problem at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        self.server.validate(&mut problems);
+        self.database.validate(&mut problems);
+        self.auth.validate(self.environment, &mut problems);
+        self.storage.validate(&mut problems);
+        self.mail.validate(self.environment, &mut problems);
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Message(format!(
+                "invalid configuration:\n  - {}",
+                problems.join("\n  - ")
+            )))
+        }
     }
 }
 
 /// The possible runtime environment for our application.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 pub enum Environment {
+    #[default]
     Local,
     Production,
 }
 
 impl Environment {
+    /// The environment named by `RUN_MODE`, or `local` when it isn't set.
+    pub fn current() -> Result<Self, ConfigError> {
+        match env::var("RUN_MODE") {
+            Ok(run_mode) => Self::try_from(run_mode).map_err(ConfigError::Message),
+            Err(_) => Ok(Self::Local),
+        }
+    }
+
     pub fn as_str(&self) -> &'static str {
         match self {
             Environment::Local => "local",
@@ -79,7 +871,8 @@ impl TryFrom<String> for Environment {
 
     fn try_from(s: String) -> Result<Self, Self::Error> {
         match s.to_lowercase().as_str() {
-            "local" => Ok(Self::Local),
+            // `development` was the default before the environments were typed.
+            "local" | "development" => Ok(Self::Local),
             "production" => Ok(Self::Production),
             other => Err(format!(
                 "{} is not a supported environment. Use either `local` or `production`.",
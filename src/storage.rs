@@ -0,0 +1,105 @@
+//! Storage for uploaded files, such as avatars.
+//!
+//! Files are stored under keys like `avatars/<name>.png`. The [`BlobStore`] trait keeps the
+//! handlers independent of where the bytes end up, so another backend only has to implement it
+//! and be added to [`StorageBackend`].
+use crate::{
+    settings::{StorageBackend, StorageSettings},
+    Result,
+};
+use anyhow::Context;
+use axum::async_trait;
+use bytes::Bytes;
+use std::{
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Stores `data` under `key`, replacing whatever was there.
+    async fn put(&self, key: &str, data: Bytes) -> Result<()>;
+
+    /// The data stored under `key`, if there is any.
+    async fn get(&self, key: &str) -> Result<Option<Bytes>>;
+
+    /// Removes `key`. Removing a key that doesn't exist is not an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// The blob store the settings ask for.
+pub fn from_settings(settings: &StorageSettings) -> Arc<dyn BlobStore> {
+    match settings.backend {
+        StorageBackend::Local => Arc::new(LocalBlobStore::new(settings.path.clone())),
+    }
+}
+
+/// Keeps blobs as files below a directory.
+#[derive(Debug, Clone)]
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Keys come from our own code, but make sure none can point outside of the root anyway.
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let relative = Path::new(key);
+        let plain = !key.is_empty()
+            && relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+        if !plain {
+            return Err(anyhow::anyhow!("invalid blob key {:?}", key).into());
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, key: &str, data: Bytes) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("could not create {}", parent.display()))?;
+        }
+
+        // Write next to the target and rename, so readers never see a half written file.
+        let partial = path.with_extension("partial");
+        tokio::fs::write(&partial, &data)
+            .await
+            .with_context(|| format!("could not write {}", partial.display()))?;
+        tokio::fs::rename(&partial, &path)
+            .await
+            .with_context(|| format!("could not move {} into place", path.display()))?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        let path = self.path(key)?;
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(Some(data.into())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow::Error::new(e)
+                .context(format!("could not read {}", path.display()))
+                .into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(anyhow::Error::new(e)
+                .context(format!("could not remove {}", path.display()))
+                .into()),
+        }
+    }
+}
@@ -1,13 +1,25 @@
 use crate::error::{Error, HttpError, Result, ValidationErrorItem};
+use crate::server::Server;
 use validator::Validate;
 use axum::{
     async_trait,
     body::{HttpBody},
-    extract::{FromRequest, RequestParts},
+    extract::{ConnectInfo, FromRequest, Query, RequestParts},
     BoxError,
 };
-use serde::{de::DeserializeOwned};
-use std::{borrow::Cow, error::Error as StdError};
+use sea_orm::{
+    prelude::{DateTimeWithTimeZone, Uuid},
+    sea_query::Order,
+    ActiveValue, ColumnTrait, Condition, ConnectionTrait, EntityTrait, ModelTrait, QueryFilter,
+    QueryOrder, QuerySelect, Select, Value,
+};
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    error::Error as StdError,
+    net::{IpAddr, SocketAddr},
+};
 
 /// Recursively searches a [`validator::ValidationErrors`] tree into a linear list of errors to be
 /// sent to the user
@@ -105,4 +117,313 @@ where
         })?;
         Ok(ValidatedJson(value))
     }
-}
\ No newline at end of file
+}
+
+/// The address of the client, as far as it is known. Requests that didn't come through a socket,
+/// such as in tests, have none.
+///
+/// With `server.trust_forwarded_for` the address is the last entry of `X-Forwarded-For`, which is
+/// the one the reverse proxy in front of us saw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for ClientIp {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self> {
+        let trust_forwarded_for = req
+            .extensions()
+            .get::<Server>()
+            .is_some_and(|ctx| ctx.settings.server.trust_forwarded_for);
+        if trust_forwarded_for {
+            let forwarded = req
+                .headers()
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .last()
+                .and_then(|ip| ip.trim().parse().ok());
+            if forwarded.is_some() {
+                return Ok(ClientIp(forwarded));
+            }
+        }
+
+        Ok(ClientIp(
+            req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(address)| address.ip()),
+        ))
+    }
+}
+
+/// Turns an optional field of a partial update into an [`ActiveValue`]. Fields that were left out
+/// become [`ActiveValue::NotSet`], so the column keeps whatever value it already has.
+pub fn set_if_some<V: Into<Value>>(value: Option<V>) -> ActiveValue<V> {
+    match value {
+        Some(value) => ActiveValue::Set(value),
+        None => ActiveValue::NotSet,
+    }
+}
+
+/// Moves a client supplied timestamp into UTC, which is how every timestamp column is stored. The
+/// SQLite backend compares timestamps as text, so mixed offsets would compare wrongly.
+pub fn to_utc(timestamp: DateTimeWithTimeZone) -> DateTimeWithTimeZone {
+    timestamp.with_timezone(&Utc).into()
+}
+
+/// The number of rows a list endpoint returns when no `limit` is given.
+pub const DEFAULT_PAGE_SIZE: u64 = 20;
+/// The largest `limit` a list endpoint accepts.
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+fn query_error(field: &str, msg: String) -> HttpError {
+    HttpError::unprocessable_entity(vec![ValidationErrorItem {
+        loc: vec!["query".to_owned(), field.to_owned()],
+        msg,
+        ty: "value_error".to_owned(),
+    }])
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl From<SortOrder> for Order {
+    fn from(order: SortOrder) -> Order {
+        match order {
+            SortOrder::Asc => Order::Asc,
+            SortOrder::Desc => Order::Desc,
+        }
+    }
+}
+
+/// The sort value of the last row of a page, in a form that survives a round trip through JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum CursorValue {
+    Timestamp(DateTimeWithTimeZone),
+    Uuid(Uuid),
+    Text(String),
+}
+
+impl CursorValue {
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::ChronoDateTimeWithTimeZone(Some(v)) => Some(CursorValue::Timestamp(*v)),
+            Value::Uuid(Some(v)) => Some(CursorValue::Uuid(*v)),
+            Value::String(Some(v)) => Some(CursorValue::Text(*v)),
+            _ => None,
+        }
+    }
+}
+
+impl From<CursorValue> for Value {
+    fn from(value: CursorValue) -> Value {
+        match value {
+            CursorValue::Timestamp(v) => v.into(),
+            CursorValue::Uuid(v) => v.into(),
+            CursorValue::Text(v) => v.into(),
+        }
+    }
+}
+
+/// Where the previous page ended. Handed to clients as an opaque base64 string.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Cursor {
+    order_by: String,
+    order: SortOrder,
+    value: CursorValue,
+    id: Uuid,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("cursor is always serializable");
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let json = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct PaginationQuery {
+    limit: Option<u64>,
+    cursor: Option<String>,
+    order_by: Option<String>,
+    order: Option<SortOrder>,
+}
+
+/// The `?limit=`, `?cursor=`, `?order_by=` and `?order=` parameters shared by list endpoints.
+///
+/// Pass it to [`paginate`] together with the entity's [`Sorting`] to run the query.
+#[derive(Debug, Clone)]
+pub struct Pagination {
+    pub limit: u64,
+    pub order_by: Option<String>,
+    pub order: SortOrder,
+    cursor: Option<Cursor>,
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Pagination {
+    type Rejection = Error;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self> {
+        let Query(query) = Query::<PaginationQuery>::from_request(req)
+            .await
+            .map_err(|e| {
+                HttpError::unprocessable_entity(vec![ValidationErrorItem {
+                    loc: vec!["query".to_owned()],
+                    msg: e.to_string(),
+                    ty: "value_error".to_owned(),
+                }])
+            })?;
+
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return Err(query_error(
+                "limit",
+                format!("Must be between 1 and {}", MAX_PAGE_SIZE),
+            )
+            .into());
+        }
+
+        let cursor = query
+            .cursor
+            .map(|c| Cursor::decode(&c).ok_or_else(|| query_error("cursor", "Invalid cursor".to_owned())))
+            .transpose()?;
+
+        Ok(Pagination {
+            limit,
+            order_by: query.order_by,
+            order: query.order.unwrap_or_default(),
+            cursor,
+        })
+    }
+}
+
+/// The columns an entity's list endpoint may be sorted by.
+pub struct Sorting<E: EntityTrait> {
+    /// The public names accepted by `?order_by=` and the columns they map to. The first entry is
+    /// the default.
+    pub columns: &'static [(&'static str, E::Column)],
+    /// The primary key. It breaks ties between rows with equal sort values, so it must be a
+    /// [`Uuid`].
+    pub id: E::Column,
+}
+
+/// One page of a list endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Pass this as `?cursor=` to get the next page. `None` on the last page.
+    pub next_cursor: Option<String>,
+    pub has_more: bool,
+}
+
+impl<T> Page<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+            has_more: self.has_more,
+        }
+    }
+}
+
+/// Runs `select` as a keyset-paginated query, ordered by the column the client asked for and then
+/// by id.
+pub async fn paginate<E, C>(
+    select: Select<E>,
+    pagination: &Pagination,
+    sorting: &Sorting<E>,
+    db: &C,
+) -> Result<Page<E::Model>>
+where
+    E: EntityTrait,
+    C: ConnectionTrait,
+{
+    let order_by = pagination
+        .order_by
+        .as_deref()
+        .unwrap_or(sorting.columns[0].0);
+    let column = sorting
+        .columns
+        .iter()
+        .find(|(name, _)| *name == order_by)
+        .map(|(_, column)| *column)
+        .ok_or_else(|| {
+            let names: Vec<_> = sorting.columns.iter().map(|(name, _)| *name).collect();
+            query_error("order_by", format!("Must be one of: {}", names.join(", ")))
+        })?;
+    let order = pagination.order;
+
+    let mut select = select;
+    if let Some(cursor) = &pagination.cursor {
+        if cursor.order_by != order_by || cursor.order != order {
+            return Err(query_error(
+                "cursor",
+                "The cursor belongs to a different order_by or order".to_owned(),
+            )
+            .into());
+        }
+
+        let value = Value::from(cursor.value.clone());
+        let (past_value, past_id) = match order {
+            SortOrder::Asc => (column.gt(value.clone()), sorting.id.gt(cursor.id)),
+            SortOrder::Desc => (column.lt(value.clone()), sorting.id.lt(cursor.id)),
+        };
+        select = select.filter(
+            Condition::any()
+                .add(past_value)
+                .add(Condition::all().add(column.eq(value)).add(past_id)),
+        );
+    }
+
+    let mut items = select
+        .order_by(column, order.into())
+        .order_by(sorting.id, order.into())
+        .limit(pagination.limit + 1)
+        .all(db)
+        .await?;
+
+    let has_more = items.len() as u64 > pagination.limit;
+    items.truncate(pagination.limit as usize);
+
+    let next_cursor = match items.last() {
+        Some(last) if has_more => {
+            let value = CursorValue::from_value(last.get(column)).ok_or_else(|| {
+                anyhow::anyhow!("column {} can not be used in a cursor", order_by)
+            })?;
+            let id = match last.get(sorting.id) {
+                Value::Uuid(Some(id)) => *id,
+                _ => return Err(anyhow::anyhow!("sorting id column is not a uuid").into()),
+            };
+            Some(
+                Cursor {
+                    order_by: order_by.to_owned(),
+                    order,
+                    value,
+                    id,
+                }
+                .encode(),
+            )
+        }
+        _ => None,
+    };
+
+    Ok(Page {
+        items,
+        next_cursor,
+        has_more,
+    })
+}
@@ -0,0 +1,204 @@
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use home_projects::auth::{
+        access_token::{Scope, Scopes},
+        lockout::{
+            AttemptKey, AttemptStore, DatabaseAttemptStore, Lockout, MemoryAttemptStore, Policy,
+            Reserved,
+        },
+        password::{verify_password, DUMMY_PASSWORD_HASH},
+        permission::Permission,
+        totp,
+    };
+    use entity::user::Role;
+    use home_projects::database::migrations;
+    use home_projects::settings::LockoutSettings;
+    use sea_orm::Database;
+    use std::sync::Arc;
+
+    /// The ASCII secret `12345678901234567890` from RFC 6238, base32 encoded.
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn totp_codes_match_the_rfc_test_vectors() {
+        // The RFC lists 8 digit codes; 6 digit codes are their last 6 digits.
+        for (time, code) in [
+            (59, "287082"),
+            (1_111_111_109, "081804"),
+            (1_111_111_111, "050471"),
+            (1_234_567_890, "005924"),
+            (2_000_000_000, "279037"),
+        ] {
+            let step = totp::step_at(Utc.timestamp(time, 0));
+            assert_eq!(totp::code_at(RFC_SECRET, step).unwrap(), code, "at {}", time);
+        }
+        assert_eq!(totp::code_at("not base32!", 1), None);
+    }
+
+    #[test]
+    fn totp_verification_allows_drift_but_not_reuse() {
+        let now = Utc.timestamp(1_234_567_890, 0);
+        let step = totp::step_at(now);
+        let code = |step| totp::code_at(RFC_SECRET, step).unwrap();
+
+        assert_eq!(totp::verify(RFC_SECRET, &code(step), now, None), Some(step));
+        assert_eq!(totp::verify(RFC_SECRET, "005 924", now, None), Some(step));
+        assert_eq!(totp::verify(RFC_SECRET, &code(step - 1), now, None), Some(step - 1));
+        assert_eq!(totp::verify(RFC_SECRET, &code(step + 1), now, None), Some(step + 1));
+        assert_eq!(totp::verify(RFC_SECRET, &code(step - 2), now, None), None);
+        assert_eq!(totp::verify(RFC_SECRET, "12345", now, None), None);
+
+        // Nothing at or before the last used step.
+        assert_eq!(totp::verify(RFC_SECRET, &code(step), now, Some(step)), None);
+        assert_eq!(totp::verify(RFC_SECRET, &code(step + 1), now, Some(step)), Some(step + 1));
+    }
+
+    #[test]
+    fn provisioning_uris_and_recovery_codes() {
+        assert_eq!(
+            totp::provisioning_uri("ABC", "Home Projects", "kim@home"),
+            "otpauth://totp/Home%20Projects:kim%40home?secret=ABC&issuer=Home%20Projects\
+             &algorithm=SHA1&digits=6&period=30"
+        );
+
+        let codes = totp::generate_recovery_codes();
+        assert_eq!(codes.len(), totp::RECOVERY_CODES);
+        assert!(codes.iter().all(|code| code.len() == 14 && code.matches('-').count() == 2));
+        assert_eq!(totp::normalize_recovery_code(" K3FQ-8mzt w2xa "), "k3fq8mztw2xa");
+        assert_eq!(totp::generate_secret().len(), 32);
+    }
+
+    #[test]
+    fn scopes_are_stored_by_name_and_admin_covers_the_rest() {
+        let scopes: Scopes = [Scope::ReadProjects, Scope::WriteTasks].into_iter().collect();
+        assert_eq!(scopes.to_string(), "projects:read tasks:write");
+        assert_eq!(Scopes::parse("tasks:write projects:read unknown"), scopes);
+        assert!(!scopes.contains(Scope::Admin));
+        let admin: Scopes = [Scope::Admin].into_iter().collect();
+        assert!(admin.contains(Scope::WriteTasks));
+    }
+
+    /// Both stores count the same way: free failures, doubling delays, the lockout, and
+    /// forgetting once the window has passed.
+    #[tokio::test]
+    async fn attempt_stores_slow_down_and_lock_out() -> anyhow::Result<()> {
+        let settings = LockoutSettings {
+            account_free_failures: 2,
+            account_max_failures: 5,
+            base_delay_secs: 10,
+            lockout_secs: 60,
+            window_secs: 300,
+            ..LockoutSettings::default()
+        };
+        let policy = Policy::for_key(&settings, &AttemptKey::Account("alice"));
+        let db = Database::connect("sqlite::memory:").await?;
+        migrations::run(&db).await?;
+        let stores: [(&str, Box<dyn AttemptStore>); 2] = [
+            ("memory", Box::new(MemoryAttemptStore::default())),
+            ("database", Box::new(DatabaseAttemptStore::new(db))),
+        ];
+
+        for (name, store) in stores {
+            let reserve = |now| store.reserve("account:alice", &policy, now);
+            let mut now = Utc.timestamp(1_700_000_000, 0);
+            let mut waits = Vec::new();
+            for _ in 0..5 {
+                let attempts = match reserve(now).await? {
+                    Reserved::Counted { after, .. } => after,
+                    locked => panic!("{}: {:?}", name, locked),
+                };
+                let wait = attempts.retry_after(now);
+                waits.push(wait.map(|wait| wait.num_seconds()));
+                // Attempts before the wait is over are refused, and not counted.
+                if let Some(wait) = wait {
+                    assert_eq!(reserve(now).await?, Reserved::Locked(wait), "{}", name);
+                    now = now + wait;
+                }
+            }
+            assert_eq!(waits, [None, None, Some(10), Some(20), Some(60)], "{}", name);
+
+            let stored = store.get("account:alice").await?.unwrap();
+            assert_eq!(stored.failures, 5, "{}", name);
+            assert_eq!(stored.retry_after(now), None, "{}", name);
+
+            // Failures after the window start from scratch.
+            let later = now + Duration::seconds(300);
+            let (before, after) = match reserve(later).await? {
+                Reserved::Counted { before, after } => (before, after),
+                locked => panic!("{}: {:?}", name, locked),
+            };
+            assert_eq!(after.failures, 1, "{}", name);
+
+            // An attempt that didn't fail is taken back, but only while nothing else counted.
+            store.release("account:alice", before, after).await?;
+            assert_eq!(store.get("account:alice").await?, before, "{}", name);
+            reserve(later).await?;
+            reserve(later).await?;
+            store.release("account:alice", before, after).await?;
+            assert_eq!(store.get("account:alice").await?.unwrap().failures, 2, "{}", name);
+
+            store.clear("account:alice").await?;
+            assert_eq!(store.get("account:alice").await?, None, "{}", name);
+        }
+        Ok(())
+    }
+
+    /// A burst of parallel attempts can't all get past the check before any of them is counted.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn parallel_attempts_are_counted_before_they_are_let_through() -> anyhow::Result<()> {
+        let settings = LockoutSettings {
+            account_free_failures: 2,
+            base_delay_secs: 10,
+            ..LockoutSettings::default()
+        };
+        let db = Database::connect("sqlite::memory:").await?;
+        migrations::run(&db).await?;
+        let stores: [(&str, Arc<dyn AttemptStore>); 2] = [
+            ("memory", Arc::new(MemoryAttemptStore::default())),
+            ("database", Arc::new(DatabaseAttemptStore::new(db))),
+        ];
+
+        for (name, store) in stores {
+            let lockout = Arc::new(Lockout::new(store, settings.clone()));
+            let now = Utc.timestamp(1_700_000_000, 0);
+            let attempts: Vec<_> = (0..10)
+                .map(|_| {
+                    let lockout = lockout.clone();
+                    tokio::spawn(async move {
+                        let attempt = lockout.reserve(&[AttemptKey::Account("alice")], now).await;
+                        attempt.map(|attempt| lockout.record_failure(attempt)).is_ok()
+                    })
+                })
+                .collect();
+            let mut let_through = 0;
+            for attempt in attempts {
+                let_through += attempt.await? as usize;
+            }
+            // The two free failures, and the one that earned the wait.
+            assert_eq!(let_through, 3, "{}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn roles_grant_permissions() {
+        use Permission::*;
+        let granted = |role| {
+            [ViewProjects, EditProjects, ManageUsers]
+                .into_iter()
+                .filter(|permission| permission.granted_to(role))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(granted(Role::Admin), [ViewProjects, EditProjects, ManageUsers]);
+        assert_eq!(granted(Role::Member), [ViewProjects, EditProjects]);
+        assert_eq!(granted(Role::Guest), [ViewProjects]);
+    }
+
+    /// Logins for unknown users check against this hash, which has to be a real one to cost as much.
+    #[tokio::test]
+    async fn dummy_password_hash_is_checked_like_a_real_one() -> anyhow::Result<()> {
+        assert!(!verify_password("hunter2".to_owned(), DUMMY_PASSWORD_HASH.to_owned()).await?);
+        Ok(())
+    }
+}
@@ -1,12 +1,24 @@
 #[cfg(test)]
 mod tests {
     use chrono::Timelike;
-    use entity::{project, task};
+    use entity::{
+        project, task, user, workspace,
+        workspace_member::{self, WorkspaceRole},
+    };
     use sea_orm::{
         ActiveModelTrait, ColumnTrait, ConnectionTrait, Database, DatabaseConnection, DbErr,
-        EntityTrait, QueryFilter, Schema, Set, Statement,
+        DbBackend, EntityTrait, QueryFilter, Schema, Set, Statement,
+        sea_query::{Alias, Query},
+    };
+    use home_projects::database::{
+        constraint::{classify, ConstraintKind},
+        get_db_pool, migrations, verify_schema, DatabaseHealth, DatabaseStatus,
     };
+    use home_projects::auth::refresh;
+    use home_projects::error::Error;
+    use home_projects::settings::{DatabaseBackend, Settings};
     use tokio_stream::{ StreamExt};
+    use axum::{http::StatusCode, response::IntoResponse};
 
     async fn setup_tests() -> Result<DatabaseConnection, DbErr> {
         let db = Database::connect("sqlite::memory:")
@@ -20,10 +32,30 @@ mod tests {
             .await?;
         db.execute(sqlite.build(&schema.create_table_from_entity(task::Entity)))
             .await?;
+        db.execute(sqlite.build(&schema.create_table_from_entity(user::Entity)))
+            .await?;
+        db.execute(sqlite.build(&schema.create_table_from_entity(workspace::Entity)))
+            .await?;
+        db.execute(sqlite.build(&schema.create_table_from_entity(workspace_member::Entity)))
+            .await?;
 
         Ok(db)
     }
 
+    async fn insert_user(db: &DatabaseConnection) -> Result<user::Model, DbErr> {
+        let user = user::ActiveModel {
+            username: Set("owner".to_owned()),
+            email: Set("owner@example.com".to_owned()),
+            bio: Set("".to_owned()),
+            password_hash: Set("not-a-real-hash".to_owned()),
+            ..Default::default()
+        }
+        .insert(db)
+        .await?;
+        workspace::create_personal(db, &user).await?;
+        Ok(user)
+    }
+
     #[tokio::test]
     async fn create_a_sea_orm_table() -> Result<(), DbErr> {
         let db = Database::connect("sqlite::memory:")
@@ -37,13 +69,17 @@ mod tests {
             sqlite.build(&schema.create_table_from_entity(project::Entity)),
             Statement::from_string(
                 sqlite,
-                vec![
+                [
                     r#"CREATE TABLE "project" ("#,
                     r#""id" text(36) NOT NULL PRIMARY KEY,"#,
                     r#""title" text NOT NULL,"#,
                     r#""text" text NOT NULL,"#,
                     r#""created_at" text NOT NULL,"#,
-                    r#""updated_at" text NOT NULL"#,
+                    r#""updated_at" text NOT NULL,"#,
+                    r#""user_id" text(36) NOT NULL,"#,
+                    r#""workspace_id" text(36) NOT NULL,"#,
+                    r#"FOREIGN KEY ("user_id") REFERENCES "user" ("user_id") ON DELETE CASCADE,"#,
+                    r#"FOREIGN KEY ("workspace_id") REFERENCES "workspace" ("id") ON DELETE CASCADE"#,
                     r#")"#,
                 ]
                 .join(" ")
@@ -56,10 +92,12 @@ mod tests {
     #[tokio::test]
     async fn insert_into_tables() -> Result<(), DbErr> {
         let db = setup_tests().await?;
+        let owner = insert_user(&db).await?;
 
         let project_insert_res = project::ActiveModel {
             title: Set("Hello World".to_owned()),
             text: Set("Hello World".to_owned()),
+            user_id: Set(owner.user_id),
             ..Default::default()
         }
         .insert(&db)
@@ -92,10 +130,12 @@ mod tests {
     #[tokio::test]
     async fn select_from_tables() -> Result<(), DbErr> {
         let db = setup_tests().await?;
+        let owner = insert_user(&db).await?;
 
         let project = project::ActiveModel {
             title: Set("Project Title".to_owned()),
             text: Set("Project Description".to_owned()),
+            user_id: Set(owner.user_id),
             ..Default::default()
         }
         .insert(&db)
@@ -139,4 +179,318 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn migrations_apply_once_and_match_entities() -> Result<(), DbErr> {
+        let db = Database::connect("sqlite::memory:")
+            .await
+            .expect("Database connection failed");
+
+        let applied = migrations::run(&db).await?;
+        assert_eq!(applied.len(), migrations::all().len());
+        assert!(migrations::run(&db).await?.is_empty());
+        assert!(migrations::pending(&db).await?.is_empty());
+
+        verify_schema(&db).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspaces_migration_moves_existing_projects_into_personal_workspaces(
+    ) -> Result<(), DbErr> {
+        let db = Database::connect("sqlite::memory:")
+            .await
+            .expect("Database connection failed");
+        let sqlite = db.get_database_backend();
+        let (earlier, later): (Vec<_>, Vec<_>) =
+            migrations::all().into_iter().partition(|m| m.version < 7);
+        for statement in earlier.iter().flat_map(|m| m.statements(sqlite)) {
+            db.execute(statement).await?;
+        }
+
+        // Rows as they were before workspaces, so written without the entities.
+        let user_id = sea_orm::prelude::Uuid::new_v4();
+        let now = sea_orm::prelude::DateTimeWithTimeZone::from(chrono::Utc::now());
+        db.execute(sqlite.build(
+            Query::insert()
+                .into_table(Alias::new("user"))
+                .columns(
+                    ["user_id", "username", "email", "password_hash", "created_at", "updated_at"]
+                        .map(Alias::new),
+                )
+                .values_panic(vec![
+                    user_id.into(),
+                    "owner".into(),
+                    "owner@example.com".into(),
+                    "not-a-real-hash".into(),
+                    now.into(),
+                    now.into(),
+                ]),
+        ))
+        .await?;
+        db.execute(sqlite.build(
+            Query::insert()
+                .into_table(Alias::new("project"))
+                .columns(
+                    ["id", "title", "text", "user_id", "created_at", "updated_at"].map(Alias::new),
+                )
+                .values_panic(vec![
+                    sea_orm::prelude::Uuid::new_v4().into(),
+                    "Old".into(),
+                    "From before workspaces".into(),
+                    user_id.into(),
+                    now.into(),
+                    now.into(),
+                ]),
+        ))
+        .await?;
+
+        for statement in later.iter().flat_map(|m| m.statements(sqlite)) {
+            db.execute(statement).await?;
+        }
+
+        let workspace = workspace::Entity::find_by_id(user_id).one(&db).await?.unwrap();
+        assert_eq!(workspace.name, "owner");
+        let member = workspace_member::Entity::find_membership(user_id, user_id)
+            .one(&db)
+            .await?
+            .unwrap();
+        assert_eq!(member.role, WorkspaceRole::Owner);
+        let project = project::Entity::find_by_title("Old").one(&db).await?.unwrap();
+        assert_eq!(project.workspace_id, user_id);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_schema_reports_missing_columns() -> Result<(), DbErr> {
+        let db = setup_tests().await?;
+
+        // `setup_tests` builds the tables straight from some of the entities and leaves out
+        // refresh_token entirely.
+        let err = verify_schema(&db).await.unwrap_err();
+        assert!(err.to_string().contains("refresh_token.token_hash"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrations_build_for_postgres() {
+        let sql = migrations::sql(DbBackend::Postgres).join("\n");
+        assert!(sql.contains(r#"CREATE TABLE "user""#));
+        assert!(sql.contains(r#""user_id" uuid NOT NULL PRIMARY KEY"#));
+        assert!(sql.contains("timestamp with time zone"));
+    }
+
+    /// Runs the migrations against a real Postgres when `TEST_POSTGRES_URL` is set, e.g.
+    /// `postgres://postgres@localhost/home_projects_test`. The database should be empty.
+    #[tokio::test]
+    async fn migrations_apply_on_postgres() -> Result<(), DbErr> {
+        let url = match std::env::var("TEST_POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(()),
+        };
+        let db = Database::connect(url).await?;
+
+        migrations::run(&db).await?;
+        assert!(migrations::pending(&db).await?.is_empty());
+        verify_schema(&db).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connection_retries_then_gives_up() -> anyhow::Result<()> {
+        let mut settings = Settings::new()?;
+        settings.database.db_type = DatabaseBackend::Postgres;
+        settings.database.host = "127.0.0.1".to_owned();
+        // Nothing listens on port 1.
+        settings.database.port = 1;
+        settings.database.pool.connect_timeout_secs = 1;
+        settings.database.connect_retry.max_attempts = 3;
+        settings.database.connect_retry.initial_backoff_ms = 1;
+
+        let health = DatabaseHealth::default();
+        assert!(get_db_pool(&settings, &health).await.is_err());
+
+        let snapshot = health.snapshot();
+        assert_eq!(snapshot.status, DatabaseStatus::Connecting);
+        assert!(snapshot.last_error.is_some());
+
+        Ok(())
+    }
+
+    /// Provokes each kind of violation on `db` and checks that it is recognised.
+    async fn assert_constraints_classified(db: &DatabaseConnection) -> Result<(), DbErr> {
+        let name = format!("user-{}", sea_orm::prelude::Uuid::new_v4());
+        let new_user = |username: &str, email: &str| user::ActiveModel {
+            username: Set(username.to_owned()),
+            email: Set(email.to_owned()),
+            bio: Set("".to_owned()),
+            password_hash: Set("not-a-real-hash".to_owned()),
+            ..Default::default()
+        };
+        new_user(&name, &format!("{}@example.com", name)).insert(db).await?;
+
+        let err = new_user(&name, "other@example.com").insert(db).await.unwrap_err();
+        let violation = classify(&err).expect("unique violation");
+        assert_eq!(violation.kind, ConstraintKind::Unique);
+        assert_eq!(violation.column_among(&["username", "email"]), Some("username"));
+
+        let err = project::ActiveModel {
+            title: Set("Orphan".to_owned()),
+            text: Set("".to_owned()),
+            user_id: Set(sea_orm::prelude::Uuid::new_v4()),
+            ..Default::default()
+        }
+        .insert(db)
+        .await
+        .unwrap_err();
+        assert_eq!(classify(&err).expect("foreign key violation").kind, ConstraintKind::ForeignKey);
+
+        let backend = db.get_database_backend();
+        let err = db
+            .execute(Statement::from_string(
+                backend,
+                r#"UPDATE "user" SET "email" = NULL"#.to_owned(),
+            ))
+            .await
+            .unwrap_err();
+        let violation = classify(&err).expect("not-null violation");
+        assert_eq!(violation.kind, ConstraintKind::NotNull);
+        assert_eq!(violation.column.as_deref(), Some("email"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn constraint_violations_are_classified_on_sqlite() -> Result<(), DbErr> {
+        let db = Database::connect("sqlite::memory:").await?;
+        migrations::run(&db).await?;
+        assert_constraints_classified(&db).await
+    }
+
+    #[tokio::test]
+    async fn constraint_violations_are_classified_on_postgres() -> Result<(), DbErr> {
+        let url = match std::env::var("TEST_POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(()),
+        };
+        let db = Database::connect(url).await?;
+        migrations::run(&db).await?;
+        assert_constraints_classified(&db).await
+    }
+
+    #[test]
+    fn postgres_messages_are_classified() {
+        let classify_message =
+            |message: &str| classify(&DbErr::Query(format!("error returned from database: {}", message)));
+
+        let violation = classify_message(
+            r#"duplicate key value violates unique constraint "refresh_token_token_hash_key""#,
+        )
+        .unwrap();
+        assert_eq!(violation.kind, ConstraintKind::Unique);
+        assert_eq!(violation.column, None);
+        assert_eq!(violation.column_among(&["hash", "token_hash"]), Some("token_hash"));
+        assert_eq!(violation.column_among(&["username", "email"]), None);
+
+        let violation =
+            classify_message(r#"duplicate key value violates unique constraint "user_email_key""#)
+                .unwrap();
+        assert_eq!(violation.column_among(&["username", "email"]), Some("email"));
+
+        let violation = classify_message(
+            r#"duplicate key value violates unique constraint "idx_workspace_member_workspace_user""#,
+        )
+        .unwrap();
+        assert_eq!(violation.column_among(&["username", "email"]), None);
+
+        let violation = classify_message(
+            r#"null value in column "title" of relation "project" violates not-null constraint"#,
+        )
+        .unwrap();
+        assert_eq!(violation.kind, ConstraintKind::NotNull);
+        assert_eq!(violation.column.as_deref(), Some("title"));
+
+        let violation = classify_message(
+            r#"new row for relation "task" violates check constraint "task_title_check""#,
+        )
+        .unwrap();
+        assert_eq!(violation.kind, ConstraintKind::Check);
+        assert_eq!(violation.constraint.as_deref(), Some("task_title_check"));
+
+        assert!(classify_message("relation \"nope\" does not exist").is_none());
+        assert!(classify(&DbErr::Conn("connection refused".to_owned())).is_none());
+    }
+
+    #[tokio::test]
+    async fn only_body_columns_are_named_in_constraint_errors() {
+        let response = |message: &str| {
+            Error::from(DbErr::Exec(format!("error returned from database: {}", message)))
+                .into_response()
+        };
+        let body_json = |response: axum::response::Response| async {
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let named = response("UNIQUE constraint failed: user.email");
+        assert_eq!(named.status(), StatusCode::CONFLICT);
+        let loc = body_json(named).await["detail"][0]["loc"].clone();
+        assert_eq!(loc, serde_json::json!(["body", "email"]));
+
+        let internal = response("UNIQUE constraint failed: refresh_token.token_hash");
+        assert_eq!(internal.status(), StatusCode::CONFLICT);
+        let body = body_json(internal).await;
+        assert_eq!(body["code"], "conflict");
+        assert!(!body.to_string().contains("token_hash"));
+
+        let internal = response("UNIQUE constraint failed: login_attempt.attempt_key");
+        assert!(!body_json(internal).await.to_string().contains("attempt_key"));
+
+        let internal = response("NOT NULL constraint failed: project.workspace_id");
+        assert_eq!(internal.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!body_json(internal).await.to_string().contains("workspace_id"));
+    }
+
+    /// Refreshes racing with the same token on Postgres: only one may get a new token, and the
+    /// others count as reuse, which revokes the family.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_refreshes_with_one_token_count_as_reuse() -> anyhow::Result<()> {
+        let url = match std::env::var("TEST_POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(()),
+        };
+        let db = Database::connect(url).await?;
+        migrations::run(&db).await?;
+        let settings = std::sync::Arc::new(Settings::new()?);
+        let name = format!("user-{}", sea_orm::prelude::Uuid::new_v4());
+        let user = user::ActiveModel {
+            username: Set(name.clone()),
+            email: Set(format!("{}@example.com", name)),
+            bio: Set("".to_owned()),
+            password_hash: Set("not-a-real-hash".to_owned()),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await?;
+        let token = refresh::issue(&db, &settings.auth, user.user_id, None).await?;
+
+        let attempts = (0..8).map(|_| {
+            let (db, settings, token) = (db.clone(), settings.clone(), token.clone());
+            tokio::spawn(async move { refresh::rotate(&db, &settings.auth, &token).await })
+        });
+        let mut rotated = Vec::new();
+        for attempt in attempts.collect::<Vec<_>>() {
+            if let Ok((_, token)) = attempt.await? {
+                rotated.push(token);
+            }
+        }
+        assert_eq!(rotated.len(), 1);
+        assert!(refresh::rotate(&db, &settings.auth, &rotated[0]).await.is_err());
+
+        Ok(())
+    }
 }
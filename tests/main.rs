@@ -1,2 +1,4 @@
+mod auth;
 mod database;
-mod router;
\ No newline at end of file
+mod router;
+mod settings;
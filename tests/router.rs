@@ -4,13 +4,30 @@ mod tests {
         body::Body,
         http::{self, Request, StatusCode},
     };
-    use entity::{project, task};
-    use home_projects::router::api_router;
-    use home_projects::{server::Server, settings::Settings};
-    use sea_orm::{ConnectionTrait, Database, DatabaseConnection, DbErr, Schema, ActiveModelTrait};
+    use axum::{routing::get, Router};
+    use entity::{project, recovery_code, task, user, workspace};
+    use entity::one_time_token::PURPOSE_PASSWORD_RESET;
+    use home_projects::auth::jwt::issue_access_token;
+    use home_projects::auth::access_token::{self, Scope};
+    use home_projects::auth::{one_time, refresh as refresh_token, totp};
+    use home_projects::clock::{Clock, FixedClock};
+    use chrono::{Duration, TimeZone, Utc};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use axum::extract::ConnectInfo;
+    use home_projects::database::{migrations, DatabaseHealth};
+    use home_projects::auth::{AuthUser, MaybeAuthUser};
+    use home_projects::router::{api_router, starting_router};
+    use home_projects::{
+        server::{app, serve_while_starting, serve_with_shutdown, Server},
+        settings::{MailBackend, Settings},
+    };
+    use sea_orm::{
+        ActiveModelTrait, ColumnTrait, ConnectionTrait, Database, DatabaseConnection, DbErr,
+        EntityTrait, QueryFilter, Statement,
+    };
     use sea_orm::ActiveValue::Set;
     use serde_json::{json, Value};
-    use std::sync::Arc;
     use tower::ServiceBuilder;
     use tower::ServiceExt;
     use tower_http::add_extension::AddExtensionLayer;
@@ -19,35 +36,129 @@ mod tests {
         let db = Database::connect("sqlite::memory:")
             .await
             .expect("Database connection failed");
-        let sqlite = db.get_database_backend();
-        let schema = Schema::new(sqlite);
-
-        //create tables from entities
-        db.execute(sqlite.build(&schema.create_table_from_entity(project::Entity)))
-            .await?;
-        db.execute(sqlite.build(&schema.create_table_from_entity(task::Entity)))
-            .await?;
+        migrations::run(&db).await?;
 
         Ok(db)
     }
 
+    /// Inserts a user straight into the database, skipping the slow password hashing, along with
+    /// their personal workspace.
+    async fn insert_user(db: &DatabaseConnection, username: &str) -> Result<user::Model, DbErr> {
+        let user = user::ActiveModel {
+            username: Set(username.to_owned()),
+            email: Set(format!("{}@example.com", username)),
+            bio: Set("".to_owned()),
+            password_hash: Set("not-a-real-hash".to_owned()),
+            ..Default::default()
+        }
+        .insert(db)
+        .await?;
+        workspace::create_personal(db, &user).await?;
+        Ok(user)
+    }
+
+    /// An `Authorization` header value for `user`.
+    fn bearer(user: &user::Model) -> String {
+        let settings = Settings::new().unwrap();
+        format!(
+            "Bearer {}",
+            issue_access_token(&settings.auth, user.user_id).unwrap()
+        )
+    }
+
+    fn authed(mut request: Request<Body>, user: &user::Model) -> Request<Body> {
+        request
+            .headers_mut()
+            .insert(http::header::AUTHORIZATION, bearer(user).parse().unwrap());
+        request
+    }
+
+    fn with_server(router: Router, settings: Settings, db: DatabaseConnection) -> Router {
+        router.layer(ServiceBuilder::new().layer(AddExtensionLayer::new(Server::new(settings, db).unwrap())))
+    }
+
+    fn json_request(method: http::Method, uri: &str, body: Value) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn body_json(response: axum::response::Response) -> Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Registers a user through the API and logs them in, returning the access token.
+    async fn register_and_login(app: &Router, username: &str, password: &str) -> String {
+        register_and_login_tokens(app, username, password).await["access_token"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    /// Like [`register_and_login`], but returns the whole token response.
+    async fn register_and_login_tokens(app: &Router, username: &str, password: &str) -> Value {
+        let response = app
+            .clone()
+            .oneshot(json_request(
+                http::Method::POST,
+                "/user",
+                json!({
+                    "username": username,
+                    "email": format!("{}@example.com", username),
+                    "password": password,
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app
+            .clone()
+            .oneshot(json_request(
+                http::Method::POST,
+                "/user/login",
+                json!({ "username": username, "password": password }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        body_json(response).await
+    }
+
+    async fn refresh(app: &Router, refresh_token: &Value) -> axum::response::Response {
+        app.clone()
+            .oneshot(json_request(
+                http::Method::POST,
+                "/user/refresh",
+                json!({ "refresh_token": refresh_token }),
+            ))
+            .await
+            .unwrap()
+    }
+
     #[tokio::test]
     async fn create_project() -> anyhow::Result<()> {
         let settings = Settings::new()?;
         let db = setup_tests().await?;
+        let owner = insert_user(&db, "owner").await?;
 
-        let app = api_router().layer(ServiceBuilder::new().layer(AddExtensionLayer::new(Server {
-            settings: Arc::new(settings),
-            db,
-        })));
+        let app = api_router().layer(
+            ServiceBuilder::new().layer(AddExtensionLayer::new(Server::new(settings, db)?)),
+        );
         // `Router` implements `tower::Service<Request<Body>>` so we can
         // call it like any tower service, no need to run an HTTP server.
         let response = app
+            .clone()
             .oneshot(
                 Request::builder()
                     .method(http::Method::POST)
                     .uri("/projects/")
                     .header(http::header::CONTENT_TYPE, "application/json")
+                    .header(http::header::AUTHORIZATION, bearer(&owner))
                     .body(Body::from(
                         json!({
                             "title": "test",
@@ -63,41 +174,1880 @@ mod tests {
 
         assert_eq!(response.status(), StatusCode::CREATED);
 
+        let response = app
+            .oneshot(authed(
+                json_request(http::Method::POST, "/projects/", json!({ "title": "", "text": "x" })),
+                &owner,
+            ))
+            .await?;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body_json(response).await["detail"][0]["loc"], json!(["body", "title"]));
+
         Ok(())
     }
 
     #[tokio::test]
-    async fn get_projects() -> anyhow::Result<()> {
+    async fn create_project_requires_authentication() -> anyhow::Result<()> {
         let settings = Settings::new()?;
         let db = setup_tests().await?;
+        let app = with_server(api_router(), settings, db);
 
-        let app = api_router().layer(ServiceBuilder::new().layer(AddExtensionLayer::new(Server {
-            settings: Arc::new(settings),
-            db,
-        })));
+        let response = app
+            .oneshot(json_request(
+                http::Method::POST,
+                "/projects/",
+                json!({ "title": "test", "text": "test" }),
+            ))
+            .await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
 
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_projects() -> anyhow::Result<()> {
+        let settings = Settings::new()?;
         let db = setup_tests().await?;
+        let owner = insert_user(&db, "owner").await?;
 
         let project = project::ActiveModel {
             title: Set("Project Title".to_owned()),
             text: Set("Project Description".to_owned()),
+            user_id: Set(owner.user_id),
             ..Default::default()
         }
         .insert(&db)
         .await?;
 
+        let app = with_server(api_router(), settings, db);
+
         let response = app
             .oneshot(
                 Request::builder()
-                    .uri("/projects/")
+                    .uri("/projects/?include=tasks")
+                    .header(http::header::AUTHORIZATION, bearer(&owner))
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
+        let body = body_json(response).await;
+        assert_eq!(
+            body,
+            json!({
+                "items": [{ "project": project, "tasks": [] }],
+                "next_cursor": null,
+                "has_more": false,
+            })
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn projects_are_scoped_to_their_owner() -> anyhow::Result<()> {
+        let settings = Settings::new()?;
+        let db = setup_tests().await?;
+        let owner = insert_user(&db, "owner").await?;
+        let stranger = insert_user(&db, "stranger").await?;
+
+        let project = project::ActiveModel {
+            title: Set("Private".to_owned()),
+            text: Set("Not for strangers".to_owned()),
+            user_id: Set(owner.user_id),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await?;
+
+        let app = with_server(api_router(), settings, db);
+        let get = |uri: String, user: &user::Model| {
+            Request::builder()
+                .uri(uri)
+                .header(http::header::AUTHORIZATION, bearer(user))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(get("/projects/".to_owned(), &stranger)).await?;
+        assert_eq!(body_json(response).await["items"], json!([]));
+
+        let uri = format!("/project/{}", project.id);
+        let response = app.clone().oneshot(get(uri.clone(), &stranger)).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app.oneshot(get(uri, &owner)).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn login_issues_usable_access_token() -> anyhow::Result<()> {
+        let settings = Settings::new()?;
+        let db = setup_tests().await?;
+        let app = with_server(
+            api_router().route("/whoami", get(|user: AuthUser| async move { user.user_id.to_string() })),
+            settings,
+            db,
+        );
+
+        let token = register_and_login(&app, "alice", "correct horse battery").await;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/whoami")
+                    .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(Request::builder().uri("/whoami").body(Body::empty()).unwrap())
+            .await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn login_rejects_wrong_password() -> anyhow::Result<()> {
+        let settings = Settings::new()?;
+        let db = setup_tests().await?;
+        let app = with_server(api_router(), settings, db);
+
+        register_and_login(&app, "bob", "correct horse battery").await;
+
+        let response = app
+            .oneshot(json_request(
+                http::Method::POST,
+                "/user/login",
+                json!({ "username": "bob", "password": "wrong" }),
+            ))
+            .await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn maybe_auth_user_rejects_bad_token() -> anyhow::Result<()> {
+        let settings = Settings::new()?;
+        let db = setup_tests().await?;
+        let app = with_server(
+            Router::new().route(
+                "/whoami",
+                get(|MaybeAuthUser(user): MaybeAuthUser| async move { format!("{:?}", user) }),
+            ),
+            settings,
+            db,
+        );
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/whoami").body(Body::empty()).unwrap())
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/whoami")
+                    .header(http::header::AUTHORIZATION, "Bearer not-a-jwt")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_rotates_and_reuse_revokes_family() -> anyhow::Result<()> {
+        let settings = Settings::new()?;
+        let db = setup_tests().await?;
+        let app = with_server(api_router(), settings, db);
+
+        let tokens = register_and_login_tokens(&app, "carol", "correct horse battery").await;
+        let first = tokens["refresh_token"].clone();
+
+        let response = refresh(&app, &first).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let second = body_json(response).await["refresh_token"].clone();
+        assert_ne!(first, second);
+
+        // Replaying the first token is treated as theft and kills the whole family...
+        assert_eq!(refresh(&app, &first).await.status(), StatusCode::UNAUTHORIZED);
+        // ...including the token that was legitimately rotated in.
+        assert_eq!(refresh(&app, &second).await.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn logout_all_revokes_every_session() -> anyhow::Result<()> {
+        let settings = Settings::new()?;
+        let db = setup_tests().await?;
+        let app = with_server(api_router(), settings, db);
+
+        let phone = register_and_login_tokens(&app, "dave", "correct horse battery").await;
+        let response = app
+            .clone()
+            .oneshot(json_request(
+                http::Method::POST,
+                "/user/login",
+                json!({ "username": "dave", "password": "correct horse battery" }),
+            ))
+            .await?;
+        let web = body_json(response).await;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/user/logout-all")
+                    .header(
+                        http::header::AUTHORIZATION,
+                        format!("Bearer {}", web["access_token"].as_str().unwrap()),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        assert_eq!(refresh(&app, &phone["refresh_token"]).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(refresh(&app, &web["refresh_token"]).await.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_and_patch_project() -> anyhow::Result<()> {
+        let settings = Settings::new()?;
+        let db = setup_tests().await?;
+        let owner = insert_user(&db, "owner").await?;
+        let project = project::ActiveModel {
+            title: Set("Kitchen".to_owned()),
+            text: Set("Paint the walls".to_owned()),
+            user_id: Set(owner.user_id),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await?;
+        let app = with_server(api_router(), settings, db);
+        let uri = format!("/project/{}", project.id);
+
+        let request = json_request(http::Method::PATCH, &uri, json!({ "text": "Paint it blue" }));
+        let response = app.clone().oneshot(authed(request, &owner)).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_json(response).await;
+        assert_eq!(body["title"], "Kitchen");
+        assert_eq!(body["text"], "Paint it blue");
+        assert_eq!(body["created_at"], json!(project.created_at));
+
+        let request = json_request(http::Method::PUT, &uri, json!({ "title": "Bathroom" }));
+        let response = app.clone().oneshot(authed(request, &owner)).await?;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let request = json_request(
+            http::Method::PUT,
+            &uri,
+            json!({ "title": "Bathroom", "text": "Regrout the tiles" }),
+        );
+        let response = app.oneshot(authed(request, &owner)).await?;
+        let body = body_json(response).await;
+        assert_eq!(body["title"], "Bathroom");
+        assert_eq!(body["text"], "Regrout the tiles");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_project_removes_its_tasks() -> anyhow::Result<()> {
+        let settings = Settings::new()?;
+        let db = setup_tests().await?;
+        let owner = insert_user(&db, "owner").await?;
+        let stranger = insert_user(&db, "stranger").await?;
+        let project = project::ActiveModel {
+            title: Set("Garden".to_owned()),
+            text: Set("Weeding".to_owned()),
+            user_id: Set(owner.user_id),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await?;
+        task::ActiveModel {
+            title: Set("Pull weeds".to_owned()),
+            text: Set("All of them".to_owned()),
+            project_id: Set(Some(project.id)),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await?;
+        let app = with_server(api_router(), settings, db.clone());
+        let delete = |user: &user::Model| {
+            Request::builder()
+                .method(http::Method::DELETE)
+                .uri(format!("/project/{}", project.id))
+                .header(http::header::AUTHORIZATION, bearer(user))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(delete(&stranger)).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app.oneshot(delete(&owner)).await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(project::Entity::find().all(&db).await?.is_empty());
+        assert!(task::Entity::find().all(&db).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn task_lifecycle() -> anyhow::Result<()> {
+        let settings = Settings::new()?;
+        let db = setup_tests().await?;
+        let owner = insert_user(&db, "owner").await?;
+        let stranger = insert_user(&db, "stranger").await?;
+        let project = project::ActiveModel {
+            title: Set("Garage".to_owned()),
+            text: Set("Tidy up".to_owned()),
+            user_id: Set(owner.user_id),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await?;
+        let app = with_server(api_router(), settings, db);
+        let tasks_uri = format!("/project/{}/tasks", project.id);
+
+        let request = json_request(http::Method::POST, &tasks_uri, json!({ "title": "" }));
+        let response = app.clone().oneshot(authed(request, &owner)).await?;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let request = json_request(http::Method::POST, &tasks_uri, json!({ "title": "Sweep" }));
+        let response = app.clone().oneshot(authed(request, &stranger)).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let request = json_request(http::Method::POST, &tasks_uri, json!({ "title": "Sweep" }));
+        let response = app.clone().oneshot(authed(request, &owner)).await?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let task = body_json(response).await;
+        assert_eq!(task["done"], false);
+        let task_uri = format!("/task/{}", task["id"].as_str().unwrap());
+
+        let request = json_request(http::Method::POST, &format!("{}/complete", task_uri), json!({}));
+        let response = app.clone().oneshot(authed(request, &owner)).await?;
+        assert_eq!(body_json(response).await["done"], true);
+
+        let request = json_request(http::Method::PATCH, &task_uri, json!({ "text": "Then mop" }));
+        let response = app.clone().oneshot(authed(request, &owner)).await?;
+        let body = body_json(response).await;
+        assert_eq!(body["title"], "Sweep");
+        assert_eq!(body["done"], true);
+
+        let request = Request::builder().uri(&tasks_uri).body(Body::empty())?;
+        let response = app.clone().oneshot(authed(request, &owner)).await?;
+        assert_eq!(body_json(response).await.as_array().unwrap().len(), 1);
+
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri(&task_uri)
+            .body(Body::empty())?;
+        let response = app.clone().oneshot(authed(request, &stranger)).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri(&task_uri)
+            .body(Body::empty())?;
+        let response = app.clone().oneshot(authed(request, &owner)).await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let request = Request::builder().uri(&tasks_uri).body(Body::empty())?;
+        let response = app.oneshot(authed(request, &owner)).await?;
+        assert_eq!(body_json(response).await, json!([]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn projects_return_all_of_their_tasks() -> anyhow::Result<()> {
+        let settings = Settings::new()?;
+        let db = setup_tests().await?;
+        let owner = insert_user(&db, "owner").await?;
+        let project = project::ActiveModel {
+            title: Set("Attic".to_owned()),
+            text: Set("Insulate".to_owned()),
+            user_id: Set(owner.user_id),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await?;
+        for title in ["Measure", "Buy wool", "Lay wool"] {
+            task::ActiveModel {
+                title: Set(title.to_owned()),
+                text: Set("".to_owned()),
+                project_id: Set(Some(project.id)),
+                ..Default::default()
+            }
+            .insert(&db)
+            .await?;
+        }
+        let app = with_server(api_router(), settings, db);
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(authed(get(&format!("/project/{}", project.id)), &owner))
+            .await?;
+        let body = body_json(response).await;
+        assert_eq!(body["tasks"].as_array().unwrap().len(), 3);
+
+        let response = app
+            .clone()
+            .oneshot(authed(get("/projects/?include=tasks"), &owner))
+            .await?;
+        let body = body_json(response).await["items"].clone();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["tasks"].as_array().unwrap().len(), 3);
+
+        let response = app.oneshot(authed(get("/projects/"), &owner)).await?;
+        let body = body_json(response).await["items"].clone();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert!(body[0].get("tasks").is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_projects_with_cursor_filter_and_sort() -> anyhow::Result<()> {
+        let settings = Settings::new()?;
+        let db = setup_tests().await?;
+        let owner = insert_user(&db, "owner").await?;
+        for title in ["Deck", "Attic", "Cellar", "Bathroom", "Eaves"] {
+            project::ActiveModel {
+                title: Set(title.to_owned()),
+                text: Set("".to_owned()),
+                user_id: Set(owner.user_id),
+                ..Default::default()
+            }
+            .insert(&db)
+            .await?;
+        }
+        let app = with_server(api_router(), settings, db);
+        let list = |app: &Router, uri: String| {
+            let request = authed(Request::builder().uri(uri).body(Body::empty()).unwrap(), &owner);
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                (response.status(), body_json(response).await)
+            }
+        };
+
+        let mut titles = Vec::new();
+        let mut uri = "/projects/?limit=2&order_by=title&order=desc".to_owned();
+        loop {
+            let (status, page) = list(&app, uri.clone()).await;
+            assert_eq!(status, StatusCode::OK);
+            for item in page["items"].as_array().unwrap() {
+                titles.push(item["project"]["title"].as_str().unwrap().to_owned());
+            }
+            match page["next_cursor"].as_str() {
+                Some(cursor) => {
+                    assert_eq!(page["has_more"], true);
+                    uri = format!("/projects/?limit=2&order_by=title&order=desc&cursor={}", cursor);
+                }
+                None => break,
+            }
+        }
+        assert_eq!(titles, ["Eaves", "Deck", "Cellar", "Bathroom", "Attic"]);
+
+        let (_, page) = list(&app, "/projects/?title_contains=ll".to_owned()).await;
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        assert_eq!(page["items"][0]["project"]["title"], "Cellar");
+
+        let (status, _) = list(&app, "/projects/?order_by=text".to_owned()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let (status, _) = list(&app, "/projects/?limit=1000".to_owned()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn health_checks_report_database_and_migrations() -> Result<(), DbErr> {
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let app = with_server(api_router(), Settings::new().unwrap(), setup_tests().await?);
+
+        let response = app.clone().oneshot(get("/health/live")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.clone().oneshot(get("/health/ready")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_json(response).await;
+        assert_eq!(body["status"], "ok");
+        let names: Vec<_> = body["checks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["database", "migrations", "accepting_requests"]);
+        assert!(body["checks"][0]["latency_ms"].is_number());
+        assert_eq!(body["database"]["status"], "connected");
+
+        // A database that was never migrated is reachable but not ready.
+        let unmigrated = Database::connect("sqlite::memory:").await?;
+        let app = with_server(api_router(), Settings::new().unwrap(), unmigrated.clone());
+        let response = app.oneshot(get("/health/ready")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = body_json(response).await;
+        assert_eq!(body["status"], "failed");
+        assert_eq!(body["checks"][0]["status"], "ok");
+        assert_eq!(body["checks"][1]["status"], "failed");
+        assert!(body["checks"][1]["error"]
+            .as_str()
+            .unwrap()
+            .contains("pending migrations"));
+        // The probe only reads, it doesn't create the history table.
+        let history = unmigrated
+            .query_one(Statement::from_string(
+                unmigrated.get_database_backend(),
+                "SELECT name FROM sqlite_master WHERE name = 'schema_migrations'".to_owned(),
+            ))
+            .await?;
+        assert!(history.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn health_checks_answer_while_the_database_is_starting() -> anyhow::Result<()> {
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let health = DatabaseHealth::default();
+        let app = starting_router().layer(AddExtensionLayer::new(health.clone()));
+
+        let response = app.clone().oneshot(get("/health/live")).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.clone().oneshot(get("/health/ready")).await?;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = body_json(response).await;
+        assert_eq!(body["status"], "failed");
+        assert_eq!(body["database"]["status"], "connecting");
+
+        health.set_failed(DatabaseHealth::UNREACHABLE);
+        let body = body_json(app.clone().oneshot(get("/health/ready")).await?).await;
+        assert_eq!(body["checks"][0]["error"], "database unreachable");
+        assert_eq!(body["database"]["last_error"], "database unreachable");
+
+        let response = app.oneshot(get("/projects/")).await?;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body_json(response).await["code"], "starting");
+
+        // Once started, the address is handed back for the real server.
+        let mut settings = Settings::new()?;
+        settings.server.host = "127.0.0.1".to_owned();
+        settings.server.port = 0;
+        let started = serve_while_starting(&settings, health, async { Ok(7) }).await?;
+        assert_eq!(started, 7);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serve_stops_on_shutdown_and_cancels_the_token() -> anyhow::Result<()> {
+        let mut settings = Settings::new()?;
+        settings.server.host = "127.0.0.1".to_owned();
+        settings.server.port = 0;
+        settings.server.shutdown_timeout_secs = 1;
+        let server = Server::new(settings, setup_tests().await?)?;
+        let token = server.shutdown.clone();
+
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let handle = tokio::spawn(serve_with_shutdown(server, async {
+            stopped.await.ok();
+        }));
+        assert!(!token.is_cancelled());
+
+        stop.send(()).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), handle).await???;
+        assert!(token.is_cancelled());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn duplicate_registration_is_a_conflict_naming_the_field() -> Result<(), DbErr> {
+        let app = with_server(api_router(), Settings::new().unwrap(), setup_tests().await?);
+        let register = |username: &str, email: &str| {
+            json_request(
+                http::Method::POST,
+                "/user",
+                json!({ "username": username, "email": email, "password": "correct horse" }),
+            )
+        };
+
+        let response = app.clone().oneshot(register("alice", "alice@example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app.clone().oneshot(register("alice", "other@example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = body_json(response).await;
+        assert_eq!(body["detail"][0]["loc"], json!(["body", "username"]));
+        assert_eq!(body["detail"][0]["type"], "value_error.unique");
+        assert!(!body.to_string().contains("constraint"));
+
+        let response = app.oneshot(register("bob", "alice@example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(body_json(response).await["detail"][0]["loc"], json!(["body", "email"]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn errors_carry_the_request_id_and_a_stable_code() -> anyhow::Result<()> {
+        let db = setup_tests().await?;
+        let owner = insert_user(&db, "owner").await?;
+        let app = app(Server::new(Settings::new()?, db.clone())?)?;
+
+        let get = |uri: &str, request_id: Option<&str>| {
+            let mut request = Request::builder().uri(uri);
+            if let Some(id) = request_id {
+                request = request.header("x-request-id", id);
+            }
+            authed(request.body(Body::empty()).unwrap(), &owner)
+        };
+
+        // A client supplied id is kept and shows up in the error body.
+        let missing = format!("/project/{}", sea_orm::prelude::Uuid::new_v4());
+        let response = app.clone().oneshot(get(&missing, Some("client-id-1"))).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["x-request-id"], "client-id-1");
+        let body = body_json(response).await;
+        assert_eq!(body["request_id"], "client-id-1");
+        assert_eq!(body["code"], "not_found");
+
+        // Ids that aren't plain are replaced with a generated one.
+        let response = app.clone().oneshot(get(&missing, Some("bad id\t<script>"))).await?;
+        let generated = response.headers()["x-request-id"].to_str()?.to_owned();
+        assert_ne!(generated, "bad id\t<script>");
+        assert_eq!(body_json(response).await["request_id"], generated.as_str());
+
+        // Internal errors get a stable code, and the details stay in the logs.
+        db.execute(Statement::from_string(
+            db.get_database_backend(),
+            "DROP TABLE project".to_owned(),
+        ))
+        .await?;
+        let response = app.oneshot(get("/projects/", None)).await?;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let request_id = response.headers()["x-request-id"].to_str()?.to_owned();
+        let body = body_json(response).await;
+        assert_eq!(body["code"], "database_error");
+        assert_eq!(body["request_id"], request_id.as_str());
+        assert!(!body.to_string().contains("project"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn registration_is_validated() -> anyhow::Result<()> {
+        let breached = std::env::temp_dir().join(format!("breached-{}.txt", std::process::id()));
+        std::fs::write(&breached, "password123\nqwertyuiop\n")?;
+        let mut settings = Settings::new()?;
+        settings.auth.password.breached_passwords_file = Some(breached.clone());
+        let app = with_server(api_router(), settings, setup_tests().await?);
+        std::fs::remove_file(&breached)?;
+
+        let register = |body: Value| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(json_request(http::Method::POST, "/user", body))
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+            }
+        };
+        let locs = |body: &Value| -> Vec<Value> {
+            body["detail"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["loc"].clone())
+                .collect()
+        };
+
+        let (status, body) = register(json!({
+            "username": "no spaces!",
+            "email": "not-an-email",
+            "password": "correct horse battery",
+        }))
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let locs = locs(&body);
+        assert!(locs.contains(&json!(["body", "username"])), "{}", body);
+        assert!(locs.contains(&json!(["body", "email"])), "{}", body);
+
+        for password in ["short", "qwertyuiop"] {
+            let (status, body) = register(json!({
+                "username": "erin",
+                "email": "erin@example.com",
+                "password": password,
+            }))
+            .await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(body["detail"][0]["loc"], json!(["body", "password"]));
+        }
+
+        // Missing fields are no longer filled in with empty strings.
+        let (status, _) = register(json!({ "username": "erin" })).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let (status, _) = register(json!({
+            "username": "erin.o-k_1",
+            "email": "erin@example.com",
+            "password": "correct horse battery",
+        }))
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_responses_never_include_the_password_hash() -> anyhow::Result<()> {
+        let db = setup_tests().await?;
+        let alice = insert_user(&db, "alice").await?;
+        insert_user(&db, "bob").await?;
+        let app = with_server(api_router(), Settings::new()?, db);
+
+        let get = |uri: String, user: Option<&user::Model>| {
+            let mut request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            if let Some(user) = user {
+                request = authed(request, user);
+            }
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                (response.status(), body_json(response).await)
+            }
+        };
+
+        let (status, me) = get("/user/me".to_owned(), Some(&alice)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(me["username"], "alice");
+        assert_eq!(me["email"], "alice@example.com");
+        assert!(me.get("password_hash").is_none());
+
+        let (status, _) = get("/user/me".to_owned(), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, public) = get(format!("/user/{}", alice.user_id), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(public["username"], "alice");
+        assert!(public.get("email").is_none());
+        assert!(public.get("password_hash").is_none());
+
+        let (status, page) = get("/users".to_owned(), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let items = page["items"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert!(!page.to_string().contains("password_hash"));
+        assert!(!page.to_string().contains("not-a-real-hash"));
+
+        Ok(())
+    }
+
+    /// `request` with `token` as its bearer token.
+    fn with_token(mut request: Request<Body>, token: &str) -> Request<Body> {
+        request.headers_mut().insert(
+            http::header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        request
+    }
+
+    /// Sends `request`, returning the status and the JSON body, or `null` for an empty body.
+    async fn send(app: &Router, request: Request<Body>) -> (StatusCode, Value) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
         let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
-        let body: Value = serde_json::from_slice(&body).unwrap();
-        assert_eq!(body, json!(project));
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn profile_and_password_can_be_changed() -> anyhow::Result<()> {
+        let app = with_server(api_router(), Settings::new()?, setup_tests().await?);
+        let tokens = register_and_login_tokens(&app, "frank", "correct horse battery").await;
+        let token = tokens["access_token"].as_str().unwrap().to_owned();
+        register_and_login(&app, "grace", "correct horse battery").await;
+        let patch = |body: Value| {
+            with_token(json_request(http::Method::PATCH, "/user/me", body), &token)
+        };
+
+        let (status, me) = send(
+            &app,
+            patch(json!({ "bio": "Builds sheds", "image": "https://example.com/frank.png" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(me["bio"], "Builds sheds");
+        assert_eq!(me["image"], "https://example.com/frank.png");
+        // Left out, so unchanged.
+        assert_eq!(me["username"], "frank");
+
+        let (status, body) = send(&app, patch(json!({ "username": "grace" }))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["detail"][0]["loc"], json!(["body", "username"]));
+
+        let (status, _) = send(&app, patch(json!({ "email": "nope" }))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let taken = json!({
+            "email": "grace@example.com",
+            "current_password": "correct horse battery",
+        });
+        let (status, body) = send(&app, patch(taken)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["detail"][0]["loc"], json!(["body", "email"]));
+
+        let change = |current: &str, new: &str| {
+            with_token(
+                json_request(
+                    http::Method::POST,
+                    "/user/me/password",
+                    json!({ "current_password": current, "new_password": new }),
+                ),
+                &token,
+            )
+        };
+        let (status, body) = send(&app, change("wrong password", "a brand new password")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["detail"][0]["loc"], json!(["body", "current_password"]));
+
+        let (status, body) = send(&app, change("correct horse battery", "short")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["detail"][0]["loc"], json!(["body", "new_password"]));
+
+        let (status, body) =
+            send(&app, change("correct horse battery", "a brand new password")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Sessions from before the change are over, the one handed back is not.
+        assert_eq!(
+            refresh(&app, &tokens["refresh_token"]).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            refresh(&app, &body["refresh_token"]).await.status(),
+            StatusCode::OK
+        );
+
+        let login = |password: &str| {
+            json_request(
+                http::Method::POST,
+                "/user/login",
+                json!({ "username": "frank", "password": password }),
+            )
+        };
+        assert_eq!(
+            send(&app, login("correct horse battery")).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(send(&app, login("a brand new password")).await.0, StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn avatars_are_checked_stored_and_served() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("avatars-{}", std::process::id()));
+        let mut settings = Settings::new()?;
+        settings.storage.path = root.clone();
+        settings.storage.max_avatar_bytes = 64;
+        let db = setup_tests().await?;
+        let heidi = insert_user(&db, "heidi").await?;
+        let app = with_server(api_router(), settings, db);
+
+        let upload = |data: Vec<u8>| {
+            let request = Request::builder()
+                .method(http::Method::PUT)
+                .uri("/user/me/avatar")
+                .header(http::header::CONTENT_TYPE, "image/png")
+                .body(Body::from(data))
+                .unwrap();
+            send(&app, authed(request, &heidi))
+        };
+        let png = |len: usize| {
+            let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+            data.resize(len, 0);
+            data
+        };
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        // Claiming to be a PNG isn't enough.
+        let (status, _) = upload(b"<svg></svg>".to_vec()).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let (status, _) = upload(png(65)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let (status, me) = upload(png(64)).await;
+        assert_eq!(status, StatusCode::OK);
+        let first = me["image"].as_str().unwrap().to_owned();
+        assert!(first.starts_with("/avatars/") && first.ends_with(".png"), "{}", first);
+
+        let response = app.clone().oneshot(get(&first)).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "image/png");
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        assert_eq!(body.to_vec(), png(64));
+
+        // A new upload replaces the file of the old one.
+        let (_, me) = upload(png(32)).await;
+        let second = me["image"].as_str().unwrap().to_owned();
+        assert_ne!(first, second);
+        assert_eq!(send(&app, get(&first)).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(&app, get(&second)).await.0, StatusCode::OK);
+
+        let request = Request::builder()
+            .method(http::Method::DELETE)
+            .uri("/user/me/avatar")
+            .body(Body::empty())?;
+        assert_eq!(send(&app, authed(request, &heidi)).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send(&app, get(&second)).await.0, StatusCode::NOT_FOUND);
+        let (_, me) = send(&app, authed(get("/user/me"), &heidi)).await;
+        assert_eq!(me["image"], Value::Null);
+
+        std::fs::remove_dir_all(&root).ok();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deleting_an_account_removes_or_hands_off_projects() -> anyhow::Result<()> {
+        let db = setup_tests().await?;
+        let app = with_server(api_router(), Settings::new()?, db.clone());
+        let ivan = register_and_login(&app, "ivan", "correct horse battery").await;
+        let judy = register_and_login(&app, "judy", "correct horse battery").await;
+        let mallory = register_and_login(&app, "mallory", "correct horse battery").await;
+
+        for (token, title) in [(&ivan, "Shed"), (&ivan, "Fence"), (&mallory, "Pond")] {
+            let request = json_request(
+                http::Method::POST,
+                "/projects/",
+                json!({ "title": title, "text": "..." }),
+            );
+            assert_eq!(send(&app, with_token(request, token)).await.0, StatusCode::CREATED);
+        }
+        let delete_me = |token: &str, body: Value| {
+            with_token(json_request(http::Method::DELETE, "/user/me", body), token)
+        };
+
+        let (status, body) =
+            send(&app, delete_me(&ivan, json!({ "password": "wrong password" }))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["detail"][0]["loc"], json!(["body", "password"]));
+
+        for recipient in ["nobody", "ivan"] {
+            let (status, body) = send(
+                &app,
+                delete_me(
+                    &ivan,
+                    json!({ "password": "correct horse battery", "transfer_projects_to": recipient }),
+                ),
+            )
+            .await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(body["detail"][0]["loc"], json!(["body", "transfer_projects_to"]));
+        }
+
+        let (status, _) = send(
+            &app,
+            delete_me(
+                &ivan,
+                json!({ "password": "correct horse battery", "transfer_projects_to": "judy" }),
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let me = with_token(Request::builder().uri("/user/me").body(Body::empty())?, &ivan);
+        assert_eq!(send(&app, me).await.0, StatusCode::UNAUTHORIZED);
+        let projects = with_token(Request::builder().uri("/projects/").body(Body::empty())?, &judy);
+        assert_eq!(send(&app, projects).await.1["items"].as_array().unwrap().len(), 2);
+
+        let (status, _) =
+            send(&app, delete_me(&mallory, json!({ "password": "correct horse battery" }))).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let titles: Vec<String> = project::Entity::find()
+            .all(&db)
+            .await?
+            .into_iter()
+            .map(|project| project.title)
+            .collect();
+        assert_eq!(titles.len(), 2);
+        assert!(!titles.contains(&"Pond".to_owned()));
+        assert_eq!(user::Entity::find().all(&db).await?.len(), 1);
+
+        Ok(())
+    }
+
+    /// The emails the file mailer wrote to `dir`, oldest first, once there are at least `count`.
+    async fn wait_for_mails(dir: &std::path::Path, count: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let mut names: Vec<_> = std::fs::read_dir(dir)
+                .map(|entries| entries.map(|entry| entry.unwrap().path()).collect())
+                .unwrap_or_default();
+            if names.len() >= count {
+                names.sort();
+                return names
+                    .iter()
+                    .map(|path| std::fs::read_to_string(path).unwrap())
+                    .collect();
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("expected {} emails in {}", count, dir.display());
+    }
+
+    fn token_from_mail(mail: &str) -> String {
+        mail.split("token=")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .unwrap()
+            .to_owned()
+    }
+
+    #[tokio::test]
+    async fn password_reset_and_email_verification_go_through_the_mailer() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("mail-{}", std::process::id()));
+        let mut settings = Settings::new()?;
+        settings.mail.backend = MailBackend::File;
+        settings.mail.file_dir = dir.clone();
+        let db = setup_tests().await?;
+        let app = with_server(api_router(), settings, db.clone());
+
+        let tokens = register_and_login_tokens(&app, "kate", "correct horse battery").await;
+        let access = tokens["access_token"].as_str().unwrap().to_owned();
+        let me = || with_token(Request::builder().uri("/user/me").body(Body::empty()).unwrap(), &access);
+        assert_eq!(send(&app, me()).await.1["email_verified"], false);
+
+        let mails = wait_for_mails(&dir, 1).await;
+        assert!(mails[0].contains("To: kate@example.com"), "{}", mails[0]);
+        assert!(mails[0].contains("http://localhost:3000/verify-email?token="));
+        let confirm_email = json!({ "token": token_from_mail(&mails[0]) });
+        let (status, _) = send(
+            &app,
+            json_request(http::Method::POST, "/user/verify-email/confirm", confirm_email.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(send(&app, me()).await.1["email_verified"], true);
+
+        // Single use.
+        let (status, body) = send(
+            &app,
+            json_request(http::Method::POST, "/user/verify-email/confirm", confirm_email),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_token");
+
+        let resend = with_token(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/user/me/verify-email")
+                .body(Body::empty())?,
+            &access,
+        );
+        assert_eq!(send(&app, resend).await.0, StatusCode::CONFLICT);
+
+        let create_token = with_token(
+            json_request(
+                http::Method::POST,
+                "/user/me/tokens",
+                json!({ "name": "script", "scopes": ["admin"] }),
+            ),
+            &access,
+        );
+        let (_, created) = send(&app, create_token).await;
+        let pat = created["token"].as_str().unwrap().to_owned();
+        let with_pat = || {
+            with_token(Request::builder().uri("/user/me").body(Body::empty()).unwrap(), &pat)
+        };
+        assert_eq!(send(&app, with_pat()).await.0, StatusCode::OK);
+
+        // Unknown addresses get the same answer, and no email.
+        for email in ["nobody@example.com", "kate@example.com"] {
+            let (status, _) = send(
+                &app,
+                json_request(http::Method::POST, "/user/password-reset", json!({ "email": email })),
+            )
+            .await;
+            assert_eq!(status, StatusCode::ACCEPTED);
+        }
+        let mails = wait_for_mails(&dir, 2).await;
+        assert!(mails[1].contains("Subject: Reset your password"), "{}", mails[1]);
+        let reset_token = token_from_mail(&mails[1]);
+        let confirm_reset = |password: &str| {
+            json_request(
+                http::Method::POST,
+                "/user/password-reset/confirm",
+                json!({ "token": reset_token, "new_password": password }),
+            )
+        };
+
+        // A rejected password doesn't use up the token.
+        assert_eq!(
+            send(&app, confirm_reset("short")).await.0,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            send(&app, confirm_reset("a brand new password")).await.0,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            send(&app, confirm_reset("another new password")).await.0,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            refresh(&app, &tokens["refresh_token"]).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(send(&app, with_pat()).await.0, StatusCode::UNAUTHORIZED);
+        let login = json_request(
+            http::Method::POST,
+            "/user/login",
+            json!({ "username": "kate", "password": "a brand new password" }),
+        );
+        assert_eq!(send(&app, login).await.0, StatusCode::OK);
+
+        // Expired tokens are refused.
+        let kate = user::Entity::find().one(&db).await?.unwrap();
+        let expired = one_time::issue(&db, &kate, PURPOSE_PASSWORD_RESET, -1).await?;
+        let request = json_request(
+            http::Method::POST,
+            "/user/password-reset/confirm",
+            json!({ "token": expired, "new_password": "yet another password" }),
+        );
+        assert_eq!(send(&app, request).await.0, StatusCode::BAD_REQUEST);
+
+        // A new address needs the password, and only replaces the old one once it is confirmed.
+        let patch = |body: Value| {
+            with_token(json_request(http::Method::PATCH, "/user/me", body), &access)
+        };
+        for body in [
+            json!({ "email": "kate@example.org" }),
+            json!({ "email": "kate@example.org", "current_password": "correct horse battery" }),
+        ] {
+            let (status, body) = send(&app, patch(body)).await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(body["detail"][0]["loc"], json!(["body", "current_password"]));
+        }
+        let change = json!({
+            "email": "kate@example.org",
+            "current_password": "a brand new password",
+        });
+        let (status, profile) = send(&app, patch(change)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(profile["email"], "kate@example.com");
+        assert_eq!(profile["email_verified"], true);
+        let mails = wait_for_mails(&dir, 3).await;
+        assert!(mails[2].contains("To: kate@example.org"), "{}", mails[2]);
+        assert!(mails[2].contains("http://localhost:3000/confirm-email-change?token="));
+        let confirm_change = json!({ "token": token_from_mail(&mails[2]) });
+        let (status, _) = send(
+            &app,
+            json_request(http::Method::POST, "/user/email-change/confirm", confirm_change.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (_, profile) = send(&app, me()).await;
+        assert_eq!(profile["email"], "kate@example.org");
+        assert_eq!(profile["email_verified"], true);
+        let (status, _) = send(
+            &app,
+            json_request(http::Method::POST, "/user/email-change/confirm", confirm_change),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        std::fs::remove_dir_all(&dir).ok();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn password_reset_emails_are_dropped_once_shutting_down() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("mail-shutdown-{}", std::process::id()));
+        let mut settings = Settings::new()?;
+        settings.mail.backend = MailBackend::File;
+        settings.mail.file_dir = dir.clone();
+        let db = setup_tests().await?;
+        insert_user(&db, "liam").await?;
+        let server = Server::new(settings, db)?;
+        server.shutdown.cancel();
+        let app = app(server)?;
+
+        let request = json_request(
+            http::Method::POST,
+            "/user/password-reset",
+            json!({ "email": "liam@example.com" }),
+        );
+        assert_eq!(send(&app, request).await.0, StatusCode::ACCEPTED);
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(std::fs::read_dir(&dir).map_or(true, |mut entries| entries.next().is_none()));
+
+        std::fs::remove_dir_all(&dir).ok();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn two_factor_login_needs_a_code_or_a_recovery_code() -> anyhow::Result<()> {
+        let clock = Arc::new(FixedClock::new(Utc.timestamp(1_700_000_000, 0)));
+        let db = setup_tests().await?;
+        let mut server = Server::new(Settings::new()?, db.clone())?;
+        server.clock = clock.clone();
+        let app = api_router().layer(ServiceBuilder::new().layer(AddExtensionLayer::new(server)));
+
+        let access = register_and_login(&app, "leo", "correct horse battery").await;
+        let post = |uri: &str, body: Value| json_request(http::Method::POST, uri, body);
+        let authed_post = |uri: &str, body: Value| with_token(post(uri, body), &access);
+
+        let (status, _) = send(
+            &app,
+            authed_post("/user/me/2fa/enroll", json!({ "password": "wrong password" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (status, enrollment) = send(
+            &app,
+            authed_post("/user/me/2fa/enroll", json!({ "password": "correct horse battery" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let secret = enrollment["secret"].as_str().unwrap().to_owned();
+        assert!(enrollment["provisioning_uri"]
+            .as_str()
+            .unwrap()
+            .starts_with(&format!("otpauth://totp/Home%20Projects:leo?secret={}", secret)));
+        let code = || totp::code_at(&secret, totp::step_at(clock.now())).unwrap();
+
+        // Still a plain login until confirmed.
+        let login = || post("/user/login", json!({ "username": "leo", "password": "correct horse battery" }));
+        assert!(send(&app, login()).await.1["access_token"].is_string());
+
+        let (status, _) =
+            send(&app, authed_post("/user/me/2fa/confirm", json!({ "code": "000000" }))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (status, body) =
+            send(&app, authed_post("/user/me/2fa/confirm", json!({ "code": code() }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let recovery_codes: Vec<String> = serde_json::from_value(body["recovery_codes"].clone())?;
+        assert_eq!(recovery_codes.len(), totp::RECOVERY_CODES);
+
+        let me = with_token(Request::builder().uri("/user/me").body(Body::empty())?, &access);
+        assert_eq!(send(&app, me).await.1["two_factor_enabled"], true);
+
+        let challenge = || async {
+            let (status, body) = send(&app, login()).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["two_factor_required"], true);
+            assert!(body.get("access_token").is_none());
+            body["two_factor_token"].as_str().unwrap().to_owned()
+        };
+        let second_step = |body: Value| post("/user/login/2fa", body);
+
+        // The code that confirmed enrollment can't be used again.
+        let (status, body) = send(
+            &app,
+            second_step(json!({ "two_factor_token": challenge().await, "code": code() })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["code"], "invalid_two_factor_code");
+
+        clock.advance(Duration::seconds(totp::STEP_SECS));
+        let token = challenge().await;
+        let (status, body) =
+            send(&app, second_step(json!({ "two_factor_token": token, "code": code() }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["access_token"].is_string());
+
+        // A wrong guess uses up the challenge.
+        clock.advance(Duration::seconds(totp::STEP_SECS));
+        let token = challenge().await;
+        let (status, _) =
+            send(&app, second_step(json!({ "two_factor_token": token, "code": "000000" }))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) =
+            send(&app, second_step(json!({ "two_factor_token": token, "code": code() }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Recovery codes work once, however they are typed.
+        let typed = format!(" {} ", recovery_codes[3].to_uppercase());
+        for expected in [StatusCode::OK, StatusCode::UNAUTHORIZED] {
+            let (status, _) = send(
+                &app,
+                second_step(json!({ "two_factor_token": challenge().await, "recovery_code": typed })),
+            )
+            .await;
+            assert_eq!(status, expected);
+        }
+        let used = recovery_code::Entity::find()
+            .filter(recovery_code::Column::UsedAt.is_not_null())
+            .all(&db)
+            .await?;
+        assert_eq!(used.len(), 1);
+        assert_eq!(used[0].used_at, Some(clock.now().into()));
+
+        let request = with_token(
+            json_request(
+                http::Method::DELETE,
+                "/user/me/2fa",
+                json!({ "password": "correct horse battery" }),
+            ),
+            &access,
+        );
+        assert_eq!(send(&app, request).await.0, StatusCode::NO_CONTENT);
+        assert!(send(&app, login()).await.1["access_token"].is_string());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn personal_access_tokens_are_scoped_and_revocable() -> anyhow::Result<()> {
+        let db = setup_tests().await?;
+        let app = with_server(api_router(), Settings::new()?, db.clone());
+        let session = register_and_login(&app, "nina", "correct horse battery").await;
+        let create_token = |body: Value| {
+            with_token(json_request(http::Method::POST, "/user/me/tokens", body), &session)
+        };
+
+        let (status, _) = send(&app, create_token(json!({ "name": "empty", "scopes": [] }))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let (status, created) = send(
+            &app,
+            create_token(json!({
+                "name": "kitchen tablet",
+                "scopes": ["projects:read", "tasks:write"],
+                "expires_in_days": 30,
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let pat = created["token"].as_str().unwrap().to_owned();
+        assert!(pat.starts_with("hpat_"));
+        assert_eq!(created["scopes"], json!(["projects:read", "tasks:write"]));
+        assert!(created["expires_at"].is_string());
+
+        let request = json_request(
+            http::Method::POST,
+            "/projects/",
+            json!({ "title": "Garden", "text": "..." }),
+        );
+        assert_eq!(send(&app, with_token(request, &session)).await.0, StatusCode::CREATED);
+
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let (status, projects) = send(&app, with_token(get("/projects/"), &pat)).await;
+        assert_eq!(status, StatusCode::OK);
+        let project_id = projects["items"][0]["project"]["id"].as_str().unwrap().to_owned();
+        let request = json_request(
+            http::Method::POST,
+            &format!("/project/{}/tasks", project_id),
+            json!({ "title": "Water the tomatoes" }),
+        );
+        assert_eq!(send(&app, with_token(request, &pat)).await.0, StatusCode::CREATED);
+
+        // Anything outside of the scopes is refused.
+        let request = json_request(
+            http::Method::POST,
+            "/projects/",
+            json!({ "title": "Nope", "text": "..." }),
+        );
+        let (status, body) = send(&app, with_token(request, &pat)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "insufficient_scope");
+        assert_eq!(send(&app, with_token(get("/user/me"), &pat)).await.0, StatusCode::FORBIDDEN);
+        let (status, body) = send(&app, with_token(get("/workspaces"), &pat)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "insufficient_scope");
+
+        // Even an admin token can't manage tokens.
+        let (_, admin) = send(&app, create_token(json!({ "name": "admin", "scopes": ["admin"] }))).await;
+        let admin = admin["token"].as_str().unwrap().to_owned();
+        assert_eq!(send(&app, with_token(get("/user/me"), &admin)).await.0, StatusCode::OK);
+        let (status, body) = send(&app, with_token(get("/user/me/tokens"), &admin)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "session_required");
+
+        let (status, tokens) = send(&app, with_token(get("/user/me/tokens"), &session)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(tokens.as_array().unwrap().len(), 2);
+        assert_eq!(tokens[0]["name"], "kitchen tablet");
+        assert!(tokens[0]["last_used_at"].is_string());
+        assert!(tokens[0].get("token").is_none());
+        assert!(!tokens.to_string().contains(&pat));
+
+        let revoke = |id: &Value| {
+            with_token(
+                Request::builder()
+                    .method(http::Method::DELETE)
+                    .uri(format!("/user/me/tokens/{}", id.as_str().unwrap()))
+                    .body(Body::empty())
+                    .unwrap(),
+                &session,
+            )
+        };
+        assert_eq!(send(&app, revoke(&tokens[0]["id"])).await.0, StatusCode::NO_CONTENT);
+        assert_eq!(send(&app, revoke(&tokens[0]["id"])).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(
+            send(&app, with_token(get("/projects/"), &pat)).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let nina = user::Entity::find().one(&db).await?.unwrap();
+        let (_, expired) = access_token::issue(
+            &db,
+            nina.user_id,
+            "expired".to_owned(),
+            [Scope::Admin].into_iter().collect(),
+            Some(-1),
+        )
+        .await?;
+        assert_eq!(
+            send(&app, with_token(get("/user/me"), &expired)).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // Changing the password ends the remaining tokens too.
+        assert_eq!(send(&app, with_token(get("/user/me"), &admin)).await.0, StatusCode::OK);
+        let request = json_request(
+            http::Method::POST,
+            "/user/me/password",
+            json!({ "current_password": "correct horse battery", "new_password": "a new horse" }),
+        );
+        let (status, renewed) = send(&app, with_token(request, &session)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            send(&app, with_token(get("/user/me"), &admin)).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+        let renewed = renewed["access_token"].as_str().unwrap();
+        let (_, tokens) = send(&app, with_token(get("/user/me/tokens"), renewed)).await;
+        assert_eq!(tokens, json!([]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_logins_are_slowed_down_then_locked_out() -> anyhow::Result<()> {
+        let clock = Arc::new(FixedClock::new(Utc.timestamp(1_700_000_000, 0)));
+        let mut settings = Settings::new()?;
+        settings.server.trust_forwarded_for = true;
+        settings.auth.lockout.ip_free_failures = 1;
+        settings.auth.lockout.ip_max_failures = 2;
+        let mut server = Server::new(settings, setup_tests().await?)?;
+        server.clock = clock.clone();
+        let app = api_router().layer(ServiceBuilder::new().layer(AddExtensionLayer::new(server)));
+
+        register_and_login(&app, "mia", "correct horse battery").await;
+        let login = |username: &str, password: &str| {
+            json_request(
+                http::Method::POST,
+                "/user/login",
+                json!({ "username": username, "password": password }),
+            )
+        };
+
+        // Three free failures, then every failure makes the next attempt wait.
+        for _ in 0..4 {
+            let (status, _) = send(&app, login("mia", "wrong password")).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        let response = app.clone().oneshot(login("mia", "correct horse battery")).await?;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "1");
+        assert_eq!(body_json(response).await["code"], "too_many_attempts");
+
+        // After the wait the right password gets in, and the count starts over.
+        clock.advance(Duration::seconds(1));
+        let (status, _) = send(&app, login("mia", "correct horse battery")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&app, login("mia", "wrong password")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(&app, login("mia", "correct horse battery")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Guessing across accounts from one address locks the address, whichever account it
+        // tries next, but not other clients.
+        let from = |ip: &str, mut request: Request<Body>| {
+            request
+                .headers_mut()
+                .insert("x-forwarded-for", format!("10.0.0.1, {}", ip).parse().unwrap());
+            request
+        };
+        for username in ["nobody", "someone"] {
+            let (status, _) = send(&app, from("203.0.113.7", login(username, "guess"))).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        let (status, _) =
+            send(&app, from("203.0.113.7", login("mia", "correct horse battery"))).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        let mut elsewhere = login("mia", "correct horse battery");
+        elsewhere
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([198, 51, 100, 2], 40000))));
+        let (status, _) = send(&app, elsewhere).await;
+        assert_eq!(status, StatusCode::OK);
+
+        clock.advance(Duration::seconds(15 * 60));
+        let (status, _) =
+            send(&app, from("203.0.113.7", login("mia", "correct horse battery"))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn roles_limit_what_users_can_do_and_admins_manage_them() -> anyhow::Result<()> {
+        let db = setup_tests().await?;
+        let app = app(Server::new(Settings::new()?, db.clone())?)?;
+
+        let bob = register_and_login_tokens(&app, "bob", "correct horse battery").await;
+        let bob_access = bob["access_token"].as_str().unwrap().to_owned();
+        let mut ada: user::ActiveModel = insert_user(&db, "ada").await?.into();
+        ada.role = Set(user::Role::Admin);
+        let ada = ada.update(&db).await?;
+        let mut gus: user::ActiveModel = insert_user(&db, "gus").await?.into();
+        gus.role = Set(user::Role::Guest);
+        let gus = gus.update(&db).await?;
+
+        let new_project = || {
+            json_request(
+                http::Method::POST,
+                "/projects/",
+                json!({ "title": "shed", "text": "paint it" }),
+            )
+        };
+        let get = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let post = |uri: &str| json_request(http::Method::POST, uri, json!({}));
+
+        // Guests can look but not change anything, and only admins manage users.
+        assert_eq!(send(&app, authed(get("/projects/"), &gus)).await.0, StatusCode::OK);
+        let (status, body) = send(&app, authed(new_project(), &gus)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "insufficient_role");
+        let (status, _) = send(&app, with_token(get("/admin/users"), &bob_access)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(send(&app, get("/admin/users")).await.0, StatusCode::UNAUTHORIZED);
+
+        let (status, page) = send(&app, authed(get("/admin/users?role=guest"), &ada)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        assert_eq!(page["items"][0]["username"], "gus");
+        assert_eq!(page["items"][0]["email"], "gus@example.com");
+
+        // Promoting the guest lets them create projects.
+        let (status, promoted) = send(
+            &app,
+            authed(
+                json_request(
+                    http::Method::PUT,
+                    &format!("/admin/users/{}/role", gus.user_id),
+                    json!({ "role": "member" }),
+                ),
+                &ada,
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(promoted["role"], "member");
+        assert_eq!(send(&app, authed(new_project(), &gus)).await.0, StatusCode::CREATED);
+
+        // A disabled account can't log in, refresh or use its access token anywhere, and drops out
+        // of the public user list.
+        let bob_id = send(&app, with_token(get("/user/me"), &bob_access)).await.1["user_id"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        let (status, disabled) =
+            send(&app, authed(post(&format!("/admin/users/{}/disable", bob_id)), &ada)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(disabled["disabled_at"].is_string());
+
+        let (status, body) = send(&app, with_token(get("/projects/"), &bob_access)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "account_disabled");
+        let (status, body) = send(&app, with_token(get("/user/me"), &bob_access)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "account_disabled");
+        let login = json_request(
+            http::Method::POST,
+            "/user/login",
+            json!({ "username": "bob", "password": "correct horse battery" }),
+        );
+        assert_eq!(send(&app, login).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(refresh(&app, &bob["refresh_token"]).await.status(), StatusCode::UNAUTHORIZED);
+        let settings = Settings::new()?;
+        let bob_uuid = bob_id.parse()?;
+        let unused = refresh_token::issue(&db, &settings.auth, bob_uuid, None).await?;
+        let response = refresh(&app, &json!(unused)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(body_json(response).await["code"], "account_disabled");
+        let (_, users) = send(&app, get("/users")).await;
+        assert!(!users.to_string().contains("\"bob\""));
+
+        let (status, enabled) =
+            send(&app, authed(post(&format!("/admin/users/{}/enable", bob_id)), &ada)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(enabled["disabled_at"].is_null());
+        assert_eq!(send(&app, with_token(get("/projects/"), &bob_access)).await.0, StatusCode::OK);
+
+        // Admins can't lock themselves out.
+        let (status, body) = send(
+            &app,
+            authed(post(&format!("/admin/users/{}/disable", ada.user_id)), &ada),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "own_account");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspaces_share_projects_only_with_their_members() -> anyhow::Result<()> {
+        let db = setup_tests().await?;
+        let app = with_server(api_router(), Settings::new()?, db.clone());
+        let ann = insert_user(&db, "ann").await?;
+        let ben = insert_user(&db, "ben").await?;
+        let cat = insert_user(&db, "cat").await?;
+
+        let get = |uri: &str, user: &user::Model| {
+            authed(Request::builder().uri(uri).body(Body::empty()).unwrap(), user)
+        };
+        let call = |method: http::Method, uri: &str, body: Value, user: &user::Model| {
+            authed(json_request(method, uri, body), user)
+        };
+        let project = json!({ "title": "Attic", "text": "Insulate it" });
+
+        let (status, home) =
+            send(&app, call(http::Method::POST, "/workspaces", json!({ "name": "Home" }), &ann))
+                .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(home["role"], "owner");
+        let home = home["id"].as_str().unwrap().to_owned();
+        let in_home = format!("/projects/?workspace={}", home);
+        for uri in [in_home.as_str(), "/projects/"] {
+            let (status, _) = send(&app, call(http::Method::POST, uri, project.clone(), &ann)).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+        let (_, page) = send(&app, get(&in_home, &ann)).await;
+        let shared = page["items"][0]["project"]["id"].as_str().unwrap().to_owned();
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+
+        // Nothing of the workspace is reachable before joining it.
+        assert_eq!(send(&app, get("/projects/", &ben)).await.1["items"], json!([]));
+        let shared_uri = format!("/project/{}", shared);
+        assert_eq!(send(&app, get(&shared_uri, &ben)).await.0, StatusCode::NOT_FOUND);
+        let (status, _) = send(&app, call(http::Method::POST, &in_home, project, &ben)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Personal workspaces can't be shared.
+        let personal = format!("/workspace/{}/invitations", ann.user_id);
+        let (status, body) =
+            send(&app, call(http::Method::POST, &personal, json!({ "role": "viewer" }), &ann))
+                .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "personal_workspace");
+
+        // A link invitation, which can't make owners.
+        let invitations = format!("/workspace/{}/invitations", home);
+        let (status, _) =
+            send(&app, call(http::Method::POST, &invitations, json!({ "role": "owner" }), &ann))
+                .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (status, link) =
+            send(&app, call(http::Method::POST, &invitations, json!({ "role": "editor" }), &ann))
+                .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(link["link"].as_str().unwrap().contains("/join-workspace?token="));
+        let join = |token: &Value, user: &user::Model| {
+            call(http::Method::POST, "/workspaces/join", json!({ "token": token }), user)
+        };
+        let (status, joined) = send(&app, join(&link["token"], &ben)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(joined["role"], "editor");
+        assert_eq!(send(&app, join(&link["token"], &ben)).await.1["code"], "already_member");
+
+        // Members see the workspace's projects, but not the other projects of its owner.
+        let (_, page) = send(&app, get("/projects/", &ben)).await;
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        assert_eq!(page["items"][0]["project"]["id"], shared.as_str());
+        let (status, _) = send(
+            &app,
+            call(http::Method::PATCH, &shared_uri, json!({ "title": "Loft" }), &ben),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // An email invitation only works for the verified owner of that address, and only once.
+        let (_, invitation) = send(
+            &app,
+            call(
+                http::Method::POST,
+                &invitations,
+                json!({ "email": "cat@example.com", "role": "viewer" }),
+                &ann,
+            ),
+        )
+        .await;
+        let (status, body) = send(&app, join(&invitation["token"], &cat)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "wrong_account");
+        let mut verified: user::ActiveModel = cat.clone().into();
+        verified.email_verified_at = Set(Some(Utc::now().into()));
+        verified.update(&db).await?;
+        assert_eq!(send(&app, join(&invitation["token"], &cat)).await.0, StatusCode::OK);
+        let (status, body) = send(&app, join(&invitation["token"], &cat)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_invitation");
+
+        // Viewers can look but not change anything.
+        assert_eq!(send(&app, get(&shared_uri, &cat)).await.0, StatusCode::OK);
+        let (status, body) = send(
+            &app,
+            call(http::Method::PATCH, &shared_uri, json!({ "title": "Mine" }), &cat),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "read_only");
+        let tasks = format!("{}/tasks", shared_uri);
+        let (status, _) =
+            send(&app, call(http::Method::POST, &tasks, json!({ "title": "Look" }), &cat)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (_, members) = send(&app, get(&format!("/workspace/{}/members", home), &cat)).await;
+        let names: Vec<&str> = members
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["username"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["ann", "ben", "cat"]);
+
+        // The last owner can't leave, but others can, and lose access.
+        let member = |user: &user::Model| format!("/workspace/{}/members/{}", home, user.user_id);
+        let (status, body) =
+            send(&app, call(http::Method::DELETE, &member(&ann), json!({}), &ann)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "last_owner");
+        let (status, _) =
+            send(&app, call(http::Method::DELETE, &member(&ben), json!({}), &ben)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(send(&app, get(&shared_uri, &ben)).await.0, StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deleting_an_account_leaves_shared_workspace_projects_alone() -> anyhow::Result<()> {
+        let db = setup_tests().await?;
+        let app = with_server(api_router(), Settings::new()?, db.clone());
+        let ann = insert_user(&db, "ann").await?;
+        let dan = register_and_login(&app, "dan", "correct horse battery").await;
+        let eve = register_and_login(&app, "eve", "correct horse battery").await;
+        register_and_login(&app, "fay", "correct horse battery").await;
+
+        let (_, home) = send(
+            &app,
+            authed(
+                json_request(http::Method::POST, "/workspaces", json!({ "name": "Home" })),
+                &ann,
+            ),
+        )
+        .await;
+        let home = home["id"].as_str().unwrap().to_owned();
+        let (_, link) = send(
+            &app,
+            authed(
+                json_request(
+                    http::Method::POST,
+                    &format!("/workspace/{}/invitations", home),
+                    json!({ "role": "editor" }),
+                ),
+                &ann,
+            ),
+        )
+        .await;
+        let create = |uri: &str, title: &str, token: &str| {
+            with_token(
+                json_request(http::Method::POST, uri, json!({ "title": title, "text": "..." })),
+                token,
+            )
+        };
+        let in_home = format!("/projects/?workspace={}", home);
+        for (token, title) in [(&dan, "Attic"), (&eve, "Cellar")] {
+            let join = json_request(
+                http::Method::POST,
+                "/workspaces/join",
+                json!({ "token": link["token"] }),
+            );
+            assert_eq!(send(&app, with_token(join, token)).await.0, StatusCode::OK);
+            assert_eq!(send(&app, create(&in_home, title, token)).await.0, StatusCode::CREATED);
+            let projects = project::Entity::find().all(&db).await?;
+            let project = projects.iter().find(|project| project.title == title).unwrap();
+            let tasks = format!("/project/{}/tasks", project.id);
+            assert_eq!(send(&app, create(&tasks, "Tidy", token)).await.0, StatusCode::CREATED);
+            assert_eq!(
+                send(&app, create("/projects/", "Bike", token)).await.0,
+                StatusCode::CREATED
+            );
+        }
+
+        // Dan's own project goes with the account, and Eve's moves to Fay, who isn't a member of
+        // the workspace. Their projects in it stay, with its owner taking them over.
+        for (token, body) in [
+            (&dan, json!({ "password": "correct horse battery" })),
+            (&eve, json!({ "password": "correct horse battery", "transfer_projects_to": "fay" })),
+        ] {
+            let request = with_token(json_request(http::Method::DELETE, "/user/me", body), token);
+            assert_eq!(send(&app, request).await.0, StatusCode::NO_CONTENT);
+        }
+        let home: sea_orm::prelude::Uuid = home.parse()?;
+        let mut projects = project::Entity::find().all(&db).await?;
+        projects.sort_by(|a, b| a.title.cmp(&b.title));
+        let placed: Vec<_> = projects
+            .iter()
+            .map(|project| {
+                (
+                    project.title.as_str(),
+                    project.workspace_id == home,
+                    project.user_id == ann.user_id,
+                )
+            })
+            .collect();
+        assert_eq!(placed, [("Attic", true, true), ("Bike", false, false), ("Cellar", true, true)]);
+        assert_eq!(task::Entity::find().all(&db).await?.len(), 2);
 
         Ok(())
     }